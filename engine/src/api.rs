@@ -1,32 +1,52 @@
 use crate as engine;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use engine::{
-    constants, experience, job_board::JobBoard, job_board::JobTier, ErrorCategory, ErrorCode,
+    compendium::Compendium, constants, experience, job_board::JobBoard, job_board::JobTier,
+    ErrorCode,
 };
-use futures::future;
 use pccg_rs_models::{
     Card, Character, CharacterEx, ExperienceGain, Job, JobCompletionReport, JobPrototype, User,
+    UserSubCollections,
 };
-use pccg_rs_storage::firestore::{FirestoreClient, Transaction, TransactionType};
-use rand::Rng;
-use std::{convert::TryInto, sync::Arc, time::Duration};
+use pccg_rs_storage::{
+    self as storage,
+    collection::Collection,
+    firestore::{Document, DocumentField},
+    query::{Direction, Filter, StructuredQuery, DOCUMENT_NAME_FIELD},
+    retry::{RetryPolicy, RetryStats},
+    transform::FieldTransform,
+    DocumentStore, Page, Precondition, StoreTransaction, TransactionType,
+};
+use std::convert::TryInto;
 use uuid::Uuid;
 
-pub struct Api {
-    cards: FirestoreClient,
+pub struct Api<S: DocumentStore> {
+    compendium: Compendium<S>,
     job_board: JobBoard,
-    users: FirestoreClient,
+    retry_policy: RetryPolicy,
+    users: Collection<S, User>,
 }
 
-impl Api {
-    pub async fn new(cards: FirestoreClient, job_board: JobBoard, users: FirestoreClient) -> Api {
+impl<S: DocumentStore> Api<S> {
+    pub async fn new(
+        compendium: Compendium<S>,
+        job_board: JobBoard,
+        users: S,
+        retry_policy: RetryPolicy,
+    ) -> Api<S> {
         Api {
-            cards,
+            compendium,
             job_board,
-            users,
+            retry_policy,
+            users: Collection::new(users),
         }
     }
 
+    /// Counts of the retries made by operations that failed with a retryable storage error
+    pub fn retry_metrics(&self) -> RetryStats {
+        self.retry_policy.metrics()
+    }
+
     // ######################
     // # Account management #
     // ######################
@@ -41,53 +61,28 @@ impl Api {
     }
 
     pub async fn delete_user(&self, user_id: &Uuid) -> engine::Result<()> {
-        let mut retries: usize = 2;
-        loop {
-            let ret = async {
+        self.retry_policy
+            .run("delete_user", engine::Error::is_retryable, || async {
                 // Check user exists
-                let t = self
-                    .users
-                    .begin_transaction(TransactionType::ReadWrite)
-                    .await?;
-                if let Some(_) = self.users.get::<User>(user_id, Some(&t)).await? {
-                    self.users.delete::<User>(user_id, Some(&t)).await?;
-                    t.commit().await?;
-                    Ok(())
-                } else {
-                    t.commit().await?;
-                    Err(engine::Error::new(ErrorCode::UserNotFound, None))
-                }
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
+                match self.users.get(user_id, None).await? {
+                    Some(_) => {
+                        // Remove the user's characters, jobs and anything else stored under it
+                        let deleted = self.users.delete_recursive(user_id).await?;
+                        debug!("Deleted user {} and {} of its documents", user_id, deleted);
+                        Ok(())
                     }
+                    None => Err(engine::Error::new(ErrorCode::UserNotFound, None)),
                 }
-                _ => break ret,
-            }
-        }
+            })
+            .await
     }
 
     pub async fn get_user(&self, user_id: &Uuid) -> engine::Result<Option<User>> {
-        Ok(self.users.get::<User>(user_id, None).await?)
+        Ok(self.users.get(user_id, None).await?)
     }
 
     pub async fn list_user_ids(&self) -> engine::Result<Vec<Uuid>> {
-        // TODO find a way to do this without a full db enumeration
-        Ok(self
-            .users
-            .list::<User>()
-            .await?
-            .into_iter()
-            .map(|u| u.id)
-            .collect())
+        Ok(self.users.list_ids().await?)
     }
 
     // ##############
@@ -98,62 +93,86 @@ impl Api {
         &self,
         card: Card,
     ) -> engine::Result<AddOrUpdateOperation> {
-        let mut retries: usize = 2;
-        loop {
-            let ret: engine::Result<AddOrUpdateOperation> = async {
-                let t = self
-                    .users
-                    .begin_transaction(TransactionType::ReadWrite)
-                    .await?;
-                // TODO figure out how to do this without 2 firestore calls
-                let ret = match self.cards.get::<Card>(&card.id, Some(&t)).await? {
-                    Some(_) => AddOrUpdateOperation::Update,
-                    None => AddOrUpdateOperation::Add,
-                };
-                self.cards.upsert(&card.id, card.clone(), Some(&t)).await?;
-                t.commit().await?;
-                Ok(ret)
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
+        let cards = self.compendium.cards();
+        let ret = self
+            .retry_policy
+            .run(
+                "add_or_update_card_in_compendium",
+                engine::Error::is_retryable,
+                || async {
+                    // Update the card if it exists, otherwise add it. Either write can lose a race
+                    // with a concurrent add or delete of the same card, which is then retried.
+                    match cards
+                        .upsert_if(&card.id, card.clone(), Precondition::Exists(true), None)
+                        .await
+                    {
+                        Ok(_) => Ok(AddOrUpdateOperation::Update),
+                        Err(storage::Error::Conflict(_)) => {
+                            match cards.insert(&card.id, card.clone()).await {
+                                Ok(_) => Ok(AddOrUpdateOperation::Add),
+                                Err(e @ storage::Error::Conflict(_)) => Err(engine::Error::new(
+                                    ErrorCode::StorageTransaction,
+                                    Some(e.into()),
+                                )),
+                                Err(e) => Err(e.into()),
+                            }
+                        }
+                        Err(e) => Err(e.into()),
                     }
-                }
-                _ => break ret,
-            }
-        }
+                },
+            )
+            .await;
+        // Even a failed write may have been applied
+        self.compendium.invalidate();
+        ret
     }
 
     pub async fn get_random_card(&self) -> engine::Result<Card> {
-        let mut cards = self.cards.list::<Card>().await?;
-        if cards.is_empty() {
-            Err(engine::Error::new(ErrorCode::CompendiumEmpty, None))
-        } else {
-            let rnd = rand::thread_rng().gen_range(0..cards.len());
-            Ok(cards.swap_remove(rnd))
-        }
+        self.compendium.get_random_card().await
     }
 
     pub async fn get_card(&self, card_id: &Uuid) -> engine::Result<Option<Card>> {
-        Ok(self.cards.get::<Card>(card_id, None).await?)
+        self.compendium.get_card(card_id).await
     }
 
     pub async fn list_card_ids(&self) -> engine::Result<Vec<Uuid>> {
-        // TODO find a way to do this without a full db enumeration
+        Ok(self.compendium.cards().list_ids().await?)
+    }
+
+    pub async fn list_cards(
+        &self,
+        page_size: usize,
+        page_token: Option<String>,
+    ) -> engine::Result<Page<Card>> {
         Ok(self
-            .cards
-            .list::<Card>()
-            .await?
-            .into_iter()
-            .map(|c| c.id)
-            .collect())
+            .compendium
+            .cards()
+            .list_page(page_size, page_token)
+            .await?)
+    }
+
+    /// The ids of every user with a character made from the card
+    pub async fn find_card_owners(&self, card_id: &Uuid) -> engine::Result<Vec<Uuid>> {
+        let query = StructuredQuery::new()
+            .select(&[DOCUMENT_NAME_FIELD])
+            .filter(Filter::equal(
+                "prototype_id",
+                DocumentField::StringValue(card_id.to_string()),
+            ));
+        let docs = self
+            .users
+            .run_collection_group_query::<Character, Document>(query, None)
+            .await?;
+
+        let mut owners = vec![];
+        for doc in docs.iter() {
+            match doc.extract_parent_id() {
+                Ok(user_id) if !owners.contains(&user_id) => owners.push(user_id),
+                Ok(_) => (),
+                Err(e) => error!("Skipping character with unexpected name: {}", e),
+            }
+        }
+        Ok(owners)
     }
 
     // #############
@@ -173,24 +192,13 @@ impl Api {
         user_id: &Uuid,
         character_id: &Uuid,
     ) -> engine::Result<Option<CharacterEx>> {
-        let fs = FirestoreClient::new_for_subcollection(
-            &self.users,
-            user_id.to_string(),
-            "characters".to_owned(),
-        );
-
-        let mut retries: usize = 2;
-        loop {
-            let ret = async {
-                let t = fs.begin_transaction(TransactionType::ReadOnly).await?;
-
-                let character = fs.get::<Character>(character_id, Some(&t)).await?;
+        let fs = self.users.characters(user_id);
+
+        self.retry_policy
+            .run("get_character", engine::Error::is_retryable, || async {
+                let character = fs.get(character_id, None).await?;
                 if let Some(character) = character {
-                    match self
-                        .cards
-                        .get::<Card>(&character.prototype_id, Some(&t))
-                        .await?
-                    {
+                    match self.compendium.get_card(&character.prototype_id).await? {
                         Some(prototype) => Ok(Some(CharacterEx::new(character, prototype).await)),
                         None => {
                             error!("prototype with id {} not found", character.prototype_id);
@@ -200,22 +208,8 @@ impl Api {
                 } else {
                     Ok(None)
                 }
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
-                    }
-                }
-                _ => break ret,
-            }
-        }
+            })
+            .await
     }
 
     pub async fn get_current_job_for_character(
@@ -223,41 +217,32 @@ impl Api {
         user_id: &Uuid,
         character_id: &Uuid,
     ) -> engine::Result<Option<Job>> {
-        let fs = Arc::new(FirestoreClient::new_for_subcollection(
-            &self.users,
-            user_id.to_string(),
-            "jobs".to_owned(),
-        ));
-
-        // TODO replace with query
-        let jobs = fs.list::<Job>().await?;
-        Ok(jobs
-            .into_iter()
-            .find(|j| j.character_ids.contains(character_id)))
+        let fs = self.users.jobs(user_id);
+
+        let query = StructuredQuery::new()
+            .filter(Filter::array_contains(
+                "character_ids",
+                DocumentField::StringValue(character_id.to_string()),
+            ))
+            .limit(1);
+        Ok(fs.run_query(query, None).await?.pop())
     }
 
     pub async fn list_characters(&self, user_id: &Uuid) -> engine::Result<Vec<CharacterEx>> {
-        let mut retries: usize = 2;
-        loop {
-            let ret = async {
-                let t = self
-                    .users
-                    .begin_transaction(TransactionType::ReadOnly)
-                    .await?;
-                match self.users.get::<User>(user_id, Some(&t)).await? {
+        self.retry_policy
+            .run("list_characters", engine::Error::is_retryable, || async {
+                match self.users.get(user_id, None).await? {
                     Some(_) => {
-                        let fs = FirestoreClient::new_for_subcollection(
-                            &self.users,
-                            user_id.to_string(),
-                            "characters".to_owned(),
-                        );
+                        let fs = self.users.characters(user_id);
 
-                        let characters = fs.list::<Character>().await?;
+                        let characters = fs.list().await?;
                         let prototypes = self
-                            .cards
-                            .batch_get::<Card>(
-                                &characters.iter().map(|ch| ch.prototype_id).collect(),
-                                Some(&t),
+                            .compendium
+                            .get_cards(
+                                &characters
+                                    .iter()
+                                    .map(|ch| ch.prototype_id)
+                                    .collect::<Vec<_>>(),
                             )
                             .await?;
 
@@ -273,22 +258,8 @@ impl Api {
                     }
                     None => Err(engine::Error::new(ErrorCode::UserNotFound, None)),
                 }
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
-                    }
-                }
-                _ => break ret,
-            }
-        }
+            })
+            .await
     }
 
     // ################
@@ -296,9 +267,8 @@ impl Api {
     // ################
 
     pub async fn draw_card(&self, user_id: &Uuid) -> engine::Result<u32> {
-        let mut retries: usize = 2;
-        loop {
-            let ret = async {
+        self.retry_policy
+            .run("draw_card", engine::Error::is_retryable, || async {
                 let t = self
                     .users
                     .begin_transaction(TransactionType::ReadWrite)
@@ -307,7 +277,7 @@ impl Api {
                 // Get user
                 let mut user = self
                     .users
-                    .get::<User>(user_id, Some(&t))
+                    .get(user_id, Some(&t))
                     .await?
                     .ok_or(engine::Error::new(ErrorCode::UserNotFound, None))?;
 
@@ -333,37 +303,16 @@ impl Api {
 
                     Ok(new_currency_amount)
                 }
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
-                    }
-                }
-                _ => break ret,
-            }
-        }
+            })
+            .await
     }
 
     pub async fn get_staged_card(&self, user_id: &Uuid) -> engine::Result<Option<Card>> {
-        let mut retries: usize = 2;
-        loop {
-            let ret = async {
-                let t = self
-                    .users
-                    .begin_transaction(TransactionType::ReadOnly)
-                    .await?;
-                if let Some(user) = self.users.get::<User>(user_id, Some(&t)).await? {
+        self.retry_policy
+            .run("get_staged_card", engine::Error::is_retryable, || async {
+                if let Some(user) = self.users.get(user_id, None).await? {
                     if let Some(staged_card_id) = user.staged_card {
-                        if let Some(card) =
-                            self.cards.get::<Card>(&staged_card_id, Some(&t)).await?
-                        {
+                        if let Some(card) = self.compendium.get_card(&staged_card_id).await? {
                             Ok(Some(card))
                         } else {
                             // ID of staged card does not match a card in compendium
@@ -380,22 +329,8 @@ impl Api {
                 } else {
                     Err(engine::Error::new(ErrorCode::UserNotFound, None))
                 }
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
-                    }
-                }
-                _ => break ret,
-            }
-        }
+            })
+            .await
     }
 
     pub async fn promote_staged_card(
@@ -403,67 +338,55 @@ impl Api {
         user_id: &Uuid,
         requested_card_id: &Uuid,
     ) -> engine::Result<Card> {
-        let mut retries: usize = 2;
-        loop {
-            let ret = async {
-                let t = self
-                    .users
-                    .begin_transaction(TransactionType::ReadWrite)
-                    .await?;
-                if let Some(mut user) = self.users.get::<User>(user_id, Some(&t)).await? {
-                    if let Some(staged_card_id) = user.staged_card {
-                        if staged_card_id == *requested_card_id {
-                            if let Some(card) =
-                                self.cards.get::<Card>(&staged_card_id, Some(&t)).await?
-                            {
-                                let character_id = Uuid::new_v4();
-                                let character = Character::new(character_id, staged_card_id);
-                                let fs = FirestoreClient::new_for_subcollection(
-                                    &self.users,
-                                    user_id.to_string(),
-                                    "characters".to_owned(),
-                                );
-                                fs.upsert(&character_id, character, Some(&t)).await?;
-                                user.staged_card = None;
-                                self.users.upsert(user_id, user, Some(&t)).await?;
-                                t.commit().await?;
-                                Ok(card)
-                            } else {
-                                // ID of staged card does not match a card in compendium
-                                // Maybe it was removed?
-                                error!(
+        self.retry_policy
+            .run(
+                "promote_staged_card",
+                engine::Error::is_retryable,
+                || async {
+                    let t = self
+                        .users
+                        .begin_transaction(TransactionType::ReadWrite)
+                        .await?;
+                    if let Some(mut user) = self.users.get(user_id, Some(&t)).await? {
+                        if let Some(staged_card_id) = user.staged_card {
+                            if staged_card_id == *requested_card_id {
+                                if let Some(card) = self
+                                    .compendium
+                                    .cards()
+                                    .get(&staged_card_id, Some(&t))
+                                    .await?
+                                {
+                                    let character_id = Uuid::new_v4();
+                                    let character = Character::new(character_id, staged_card_id);
+                                    let fs = self.users.characters(user_id);
+                                    fs.upsert(&character_id, character, Some(&t)).await?;
+                                    user.staged_card = None;
+                                    self.users.upsert(user_id, user, Some(&t)).await?;
+                                    t.commit().await?;
+                                    Ok(card)
+                                } else {
+                                    // ID of staged card does not match a card in compendium
+                                    // Maybe it was removed?
+                                    error!(
                                     "Staged card with id {} for user {} not found in compendium!",
                                     staged_card_id, user_id
                                 );
-                                Err(engine::Error::new(ErrorCode::CardNotFound, None))
+                                    Err(engine::Error::new(ErrorCode::CardNotFound, None))
+                                }
+                            } else {
+                                // Requested card ID does not match the currently staged card ID
+                                // Enforcing ID match mitigates the race condition caused by concurrent draws
+                                Err(engine::Error::new(ErrorCode::IdMismatch, None))
                             }
                         } else {
-                            // Requested card ID does not match the currently staged card ID
-                            // Enforcing ID match mitigates the race condition caused by concurrent draws
-                            Err(engine::Error::new(ErrorCode::IdMismatch, None))
+                            Err(engine::Error::new(ErrorCode::DrawStageEmpty, None))
                         }
                     } else {
-                        Err(engine::Error::new(ErrorCode::DrawStageEmpty, None))
+                        Err(engine::Error::new(ErrorCode::UserNotFound, None))
                     }
-                } else {
-                    Err(engine::Error::new(ErrorCode::UserNotFound, None))
-                }
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
-                    }
-                }
-                _ => break ret,
-            }
-        }
+                },
+            )
+            .await
     }
 
     pub async fn scrap_staged_card(
@@ -471,14 +394,13 @@ impl Api {
         user_id: &Uuid,
         requested_card_id: &Uuid,
     ) -> engine::Result<u32> {
-        let mut retries: usize = 2;
-        loop {
-            let ret = async {
+        self.retry_policy
+            .run("scrap_staged_card", engine::Error::is_retryable, || async {
                 let t = self
                     .users
                     .begin_transaction(TransactionType::ReadWrite)
                     .await?;
-                if let Some(mut user) = self.users.get::<User>(user_id, Some(&t)).await? {
+                if let Some(mut user) = self.users.get(user_id, Some(&t)).await? {
                     if let Some(staged_card_id) = user.staged_card {
                         if staged_card_id == *requested_card_id {
                             // Partial refund
@@ -499,22 +421,8 @@ impl Api {
                 } else {
                     Err(engine::Error::new(ErrorCode::UserNotFound, None))
                 }
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
-                    }
-                }
-                _ => break ret,
-            }
-        }
+            })
+            .await
     }
 
     // #############
@@ -522,13 +430,9 @@ impl Api {
     // #############
 
     pub async fn cancel_job(&self, user_id: &Uuid, job_id: &Uuid) -> engine::Result<()> {
-        let fs = FirestoreClient::new_for_subcollection(
-            &self.users,
-            user_id.to_string(),
-            "jobs".to_owned(),
-        );
+        let fs = self.users.jobs(user_id);
 
-        Ok(fs.delete::<Job>(job_id, None).await?)
+        Ok(fs.delete(job_id, None).await?)
     }
 
     pub async fn complete_job(
@@ -536,37 +440,30 @@ impl Api {
         user_id: &Uuid,
         job_id: &Uuid,
     ) -> engine::Result<JobCompletionReport> {
-        let char_fs = FirestoreClient::new_for_subcollection(
-            &self.users,
-            user_id.to_string(),
-            "characters".to_owned(),
-        );
-        let job_fs = FirestoreClient::new_for_subcollection(
-            &self.users,
-            user_id.to_string(),
-            "jobs".to_owned(),
-        );
-
-        let mut retries: usize = 2;
-        loop {
-            let ret = async {
+        let char_fs = self.users.characters(user_id);
+        let job_fs = self.users.jobs(user_id);
+
+        self.retry_policy
+            .run("complete_job", engine::Error::is_retryable, || async {
                 let t = self
                     .users
                     .begin_transaction(TransactionType::ReadWrite)
                     .await?;
-                if let Some(job) = job_fs.get::<Job>(job_id, Some(&t)).await? {
+                if let Some(job) = job_fs.get(job_id, Some(&t)).await? {
                     if job.can_complete() {
                         // Generate completion report
                         let report = self.generate_job_completion_report(job, &t).await?;
 
-                        // Apply currency rewards
-                        let mut user = self
-                            .users
-                            .get::<User>(user_id, Some(&t))
-                            .await?
-                            .expect("User assumed to exist");
-                        user.currency += report.currency_gain;
-                        self.users.upsert(user_id, user, Some(&t)).await?;
+                        // Apply currency rewards, without adding the user to the transaction's
+                        // reads, and without creating a user that has since been deleted
+                        self.users
+                            .transform_if(
+                                user_id,
+                                vec![currency_delta(report.currency_gain as i64)],
+                                Precondition::Exists(true),
+                                Some(&t),
+                            )
+                            .await?;
 
                         // Apply experience changes
                         let char_ids: Vec<Uuid> = report
@@ -574,7 +471,7 @@ impl Api {
                             .iter()
                             .map(|eg| eg.character_id)
                             .collect();
-                        let mut chars = char_fs.batch_get::<Character>(&char_ids, Some(&t)).await?;
+                        let mut chars = char_fs.batch_get(&char_ids, Some(&t)).await?;
                         for eg in report.experience_gain.iter() {
                             let mut ch = chars
                                 .remove(&eg.character_id)
@@ -586,116 +483,101 @@ impl Api {
                         }
 
                         // Delete job
-                        job_fs.delete::<Job>(job_id, Some(&t)).await?;
+                        job_fs.delete(job_id, Some(&t)).await?;
 
-                        // Commit transaction
-                        t.commit().await?;
-
-                        Ok(report)
+                        // Commit transaction, which fails the precondition if the user is gone
+                        match t.commit().await {
+                            Ok(_) => Ok(report),
+                            Err(e @ storage::Error::Conflict(_)) => {
+                                Err(engine::Error::new(ErrorCode::UserNotFound, Some(e.into())))
+                            }
+                            Err(e) => Err(e.into()),
+                        }
                     } else {
                         Err(engine::Error::new(ErrorCode::JobNotComplete, None))
                     }
                 } else {
                     Err(engine::Error::new(ErrorCode::JobNotFound, None))
                 }
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
-                    }
-                }
-                _ => break ret,
-            }
-        }
+            })
+            .await
     }
 
     pub async fn get_job(&self, user_id: &Uuid, job_id: &Uuid) -> engine::Result<Option<Job>> {
-        let fs = FirestoreClient::new_for_subcollection(
-            &self.users,
-            user_id.to_string(),
-            "jobs".to_owned(),
-        );
+        let fs = self.users.jobs(user_id);
 
-        Ok(fs.get::<Job>(job_id, None).await?)
+        Ok(fs.get(job_id, None).await?)
     }
 
     pub async fn list_jobs(&self, user_id: &Uuid) -> engine::Result<Vec<Job>> {
         match self.get_user(user_id).await? {
             Some(_) => {
-                let fs = FirestoreClient::new_for_subcollection(
-                    &self.users,
-                    user_id.to_string(),
-                    "jobs".to_owned(),
-                );
+                let fs = self.users.jobs(user_id);
 
-                Ok(fs.list::<Job>().await?)
+                Ok(fs.list().await?)
             }
             None => Err(engine::Error::new(ErrorCode::UserNotFound, None)),
         }
     }
 
+    /// Every user's jobs that complete at or before `before`, soonest first
+    pub async fn list_jobs_due(&self, before: DateTime<Utc>) -> engine::Result<Vec<Job>> {
+        let query = StructuredQuery::new()
+            .filter(Filter::less_than_or_equal(
+                "completion_time",
+                DocumentField::TimestampValue(before),
+            ))
+            .order_by("completion_time", Direction::Ascending);
+        Ok(self
+            .users
+            .run_collection_group_query::<Job, Job>(query, None)
+            .await?)
+    }
+
     pub async fn take_job(
         &self,
         user_id: Uuid,
         job_prototype_id: &Uuid,
         character_ids: Vec<Uuid>,
     ) -> engine::Result<Job> {
-        let mut retries: usize = 2;
-        loop {
-            let ret = async {
+        if character_ids.is_empty() || character_ids.len() > constants::MAX_JOB_CHARACTERS {
+            return Err(engine::Error::new(ErrorCode::InvalidCharacterCount, None));
+        }
+
+        self.retry_policy
+            .run("take_job", engine::Error::is_retryable, || async {
                 let t = self
                     .users
                     .begin_transaction(TransactionType::ReadWrite)
                     .await?;
 
                 // Check valid user id
-                if let None = self.users.get::<User>(&user_id, Some(&t)).await? {
+                if let None = self.users.get(&user_id, Some(&t)).await? {
                     return Err(engine::Error::new(ErrorCode::UserNotFound, None));
                 }
 
                 let sw = std::time::Instant::now();
 
                 // Check valid character ids
-                let char_fs = Arc::new(FirestoreClient::new_for_subcollection(
-                    &self.users,
-                    user_id.to_string(),
-                    "characters".to_owned(),
-                ));
-                let char_map = char_fs
-                    .batch_get::<Character>(&character_ids, Some(&t))
-                    .await?;
+                let char_fs = self.users.characters(&user_id);
+                let char_map = char_fs.batch_get(&character_ids, Some(&t)).await?;
                 if !char_map.values().all(|o| o.is_some()) {
                     return Err(engine::Error::new(ErrorCode::CharacterNotFound, None));
                 }
 
                 // Check characters are not preoccupied with other jobs
-                // TODO this sucks. query would be nice (or at least a batch version)
-                let ids_clone = character_ids.clone();
-                let check_characters_not_preoccupied_tasks: Vec<_> = ids_clone
-                    .iter()
-                    .map(|c| self.get_current_job_for_character(&user_id, c))
-                    .collect();
-
-                let all_characters_not_preoccupied =
-                    future::join_all(check_characters_not_preoccupied_tasks)
-                        .await
-                        .into_iter()
-                        .all(|r| match r {
-                            Ok(r) => r.is_none(),
-                            Err(e) => {
-                                error!("Error joining future: {:?}", e);
-                                false
-                            }
-                        });
-
-                if !all_characters_not_preoccupied {
+                let job_fs = self.users.jobs(&user_id);
+                let query = StructuredQuery::new()
+                    .filter(Filter::array_contains_any(
+                        "character_ids",
+                        character_ids
+                            .iter()
+                            .map(|c| DocumentField::StringValue(c.to_string()))
+                            .collect(),
+                    ))
+                    .limit(1);
+                let preoccupied = job_fs.run_query(query, Some(&t)).await?;
+                if !preoccupied.is_empty() {
                     return Err(engine::Error::new(ErrorCode::CharacterPreoccupied, None));
                 }
 
@@ -709,47 +591,23 @@ impl Api {
                     .create_job(job_prototype_id, user_id, character_ids.clone())
                     .await?;
 
-                let job_fs = Arc::new(FirestoreClient::new_for_subcollection(
-                    &self.users,
-                    user_id.to_string(),
-                    "jobs".to_owned(),
-                ));
-
                 job_fs.upsert(&job.id, job.clone(), Some(&t)).await?;
                 t.commit().await?;
 
                 Ok(job)
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
-                    }
-                }
-                _ => break ret,
-            }
-        }
+            })
+            .await
     }
 
     async fn generate_job_completion_report(
         &self,
         job: Job,
-        transaction: &Transaction,
+        transaction: &S::Transaction,
     ) -> engine::Result<JobCompletionReport> {
-        let char_fs = FirestoreClient::new_for_subcollection(
-            &self.users,
-            job.user_id.to_string(),
-            "characters".to_owned(),
-        );
+        let char_fs = self.users.characters(&job.user_id);
 
         let char_map = char_fs
-            .batch_get::<Character>(&job.character_ids, Some(transaction))
+            .batch_get(&job.character_ids, Some(transaction))
             .await?;
         if !char_map.values().all(|c| c.is_some()) {
             // TODO handle this properly
@@ -785,49 +643,50 @@ impl Api {
     // #############
 
     pub async fn claim_user_daily_reward(&self, user_id: &Uuid) -> engine::Result<u32> {
-        let mut retries: usize = 2;
-        loop {
-            let ret = async {
-                let t = self
-                    .users
-                    .begin_transaction(TransactionType::ReadWrite)
-                    .await?;
-                match self.users.get::<User>(user_id, Some(&t)).await? {
-                    Some(mut user) => {
-                        if user.daily_last_claimed.date() < Utc::now().date() {
-                            let new_currency_amount =
-                                user.currency + constants::DAILY_CURRENCY_REWARD;
-                            user.currency = new_currency_amount;
-                            user.daily_last_claimed = Utc::now();
-
-                            self.users.upsert(user_id, user, Some(&t)).await?;
-                            t.commit().await?;
-                            Ok(new_currency_amount)
-                        } else {
-                            Err(engine::Error::new(ErrorCode::DailyAlreadyClaimed, None))
+        self.retry_policy
+            .run(
+                "claim_user_daily_reward",
+                engine::Error::is_retryable,
+                || async {
+                    let t = self
+                        .users
+                        .begin_transaction(TransactionType::ReadWrite)
+                        .await?;
+                    match self.users.get(user_id, Some(&t)).await? {
+                        Some(user) => {
+                            if user.daily_last_claimed.date() < Utc::now().date() {
+                                let new_currency_amount =
+                                    user.currency + constants::DAILY_CURRENCY_REWARD;
+
+                                self.users
+                                    .transform(
+                                        user_id,
+                                        vec![
+                                            currency_delta(constants::DAILY_CURRENCY_REWARD as i64),
+                                            FieldTransform::server_timestamp("daily_last_claimed"),
+                                        ],
+                                        Some(&t),
+                                    )
+                                    .await?;
+                                t.commit().await?;
+                                Ok(new_currency_amount)
+                            } else {
+                                Err(engine::Error::new(ErrorCode::DailyAlreadyClaimed, None))
+                            }
                         }
+                        None => Err(engine::Error::new(ErrorCode::UserNotFound, None)),
                     }
-                    None => Err(engine::Error::new(ErrorCode::UserNotFound, None)),
-                }
-            }
-            .await;
-
-            match ret {
-                Err(ref e) if retries > 0 => {
-                    if let ErrorCategory::InternalRetryable = e.classify() {
-                        info!("Caught retryable error, {} retries remaining", retries);
-                        retries -= 1;
-                        tokio::time::sleep(Duration::from_millis(300)).await;
-                    } else {
-                        break ret;
-                    }
-                }
-                _ => break ret,
-            }
-        }
+                },
+            )
+            .await
     }
 }
 
+/// Atomically add `delta` to the currency of a user
+fn currency_delta(delta: i64) -> FieldTransform {
+    FieldTransform::increment("currency", DocumentField::IntegerValue(delta.to_string()))
+}
+
 pub enum AddOrUpdateOperation {
     Add,
     Update,
@@ -0,0 +1,209 @@
+use crate as engine;
+use engine::ErrorCode;
+use pccg_rs_models::Card;
+use pccg_rs_storage::{collection::Collection, DocumentStore};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// The card compendium, with a read-through copy of every card kept in memory.
+///
+/// The copy is loaded on first use and reloaded once it is older than the refresh interval, so
+/// cards added through another server instance show up here within that interval. Writes made
+/// through this instance should be followed by `invalidate`. Cards missing from the copy are
+/// read from storage.
+pub struct Compendium<S: DocumentStore> {
+    cards: Collection<S, Card>,
+    refresh_interval: Duration,
+    snapshot: RwLock<Option<Arc<Snapshot>>>,
+    /// Bumped by every `invalidate`, so that a load that raced with one is not kept
+    generation: AtomicU64,
+    /// Held while loading, so that concurrent misses share a single load
+    load_lock: Mutex<()>,
+}
+
+struct Snapshot {
+    cards: HashMap<Uuid, Card>,
+    ids: Vec<Uuid>,
+    loaded_at: Instant,
+}
+
+impl<S: DocumentStore> Compendium<S> {
+    pub fn new(cards: S, refresh_interval: Duration) -> Compendium<S> {
+        Compendium {
+            cards: Collection::new(cards),
+            refresh_interval,
+            snapshot: RwLock::new(None),
+            generation: AtomicU64::new(0),
+            load_lock: Mutex::new(()),
+        }
+    }
+
+    /// The collection behind the cache. Reads through it bypass the cache, and writes through it
+    /// should be followed by `invalidate`.
+    pub fn cards(&self) -> &Collection<S, Card> {
+        &self.cards
+    }
+
+    /// Drop the cached copy, so that the next read loads the compendium again
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        *self.snapshot.write().unwrap() = None;
+    }
+
+    pub async fn get_card(&self, card_id: &Uuid) -> engine::Result<Option<Card>> {
+        let snapshot = self.snapshot().await?;
+        match snapshot.cards.get(card_id) {
+            Some(card) => Ok(Some(card.clone())),
+            None => Ok(self.cards.get(card_id, None).await?),
+        }
+    }
+
+    /// Look up each of `card_ids`, reading those missing from the cached copy in one batch
+    pub async fn get_cards(
+        &self,
+        card_ids: &[Uuid],
+    ) -> engine::Result<HashMap<Uuid, Option<Card>>> {
+        let snapshot = self.snapshot().await?;
+        let mut ret = HashMap::new();
+        let mut missing = vec![];
+        for id in card_ids.iter() {
+            match snapshot.cards.get(id) {
+                Some(card) => {
+                    ret.insert(*id, Some(card.clone()));
+                }
+                None => missing.push(*id),
+            }
+        }
+        if !missing.is_empty() {
+            ret.extend(self.cards.batch_get(&missing, None).await?);
+        }
+        Ok(ret)
+    }
+
+    pub async fn get_random_card(&self) -> engine::Result<Card> {
+        let snapshot = self.snapshot().await?;
+        if snapshot.ids.is_empty() {
+            Err(engine::Error::new(ErrorCode::CompendiumEmpty, None))
+        } else {
+            let rnd = rand::thread_rng().gen_range(0..snapshot.ids.len());
+            Ok(snapshot.cards[&snapshot.ids[rnd]].clone())
+        }
+    }
+
+    /// The cached copy, loading it first if there is none or it is due for a refresh
+    async fn snapshot(&self) -> engine::Result<Arc<Snapshot>> {
+        if let Some(snapshot) = self.fresh_snapshot() {
+            return Ok(snapshot);
+        }
+
+        let _load_guard = self.load_lock.lock().await;
+        // Another caller may have loaded it while this one waited
+        if let Some(snapshot) = self.fresh_snapshot() {
+            return Ok(snapshot);
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        let cards = self.cards.list().await?;
+        debug!("Loaded {} cards into the compendium cache", cards.len());
+        let snapshot = Arc::new(Snapshot {
+            ids: cards.iter().map(|card| card.id).collect(),
+            cards: cards.into_iter().map(|card| (card.id, card)).collect(),
+            loaded_at: Instant::now(),
+        });
+
+        let mut cached = self.snapshot.write().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            *cached = Some(Arc::clone(&snapshot));
+        }
+        Ok(snapshot)
+    }
+
+    fn fresh_snapshot(&self) -> Option<Arc<Snapshot>> {
+        match *self.snapshot.read().unwrap() {
+            Some(ref snapshot) if snapshot.loaded_at.elapsed() < self.refresh_interval => {
+                Some(Arc::clone(snapshot))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pccg_rs_storage::memory::{MemoryBackend, MemoryStore};
+
+    fn card(name: &str) -> Card {
+        Card {
+            id: Uuid::new_v4(),
+            name: name.to_owned(),
+            ..Card::default()
+        }
+    }
+
+    fn cards_store(backend: &Arc<MemoryBackend>) -> MemoryStore {
+        MemoryStore::new(Arc::clone(backend), None, "cards".to_owned())
+    }
+
+    #[tokio::test]
+    async fn reads_come_from_the_cache_until_invalidated() {
+        let backend = Arc::new(MemoryBackend::new());
+        let compendium = Compendium::new(cards_store(&backend), Duration::from_secs(3600));
+        match compendium.get_random_card().await {
+            Err(e) => assert!(matches!(e.code, ErrorCode::CompendiumEmpty)),
+            other => panic!("Expected an empty compendium, got {:?}", other),
+        }
+
+        // Written behind the cache's back, as by another server instance
+        let first = card("first");
+        cards_store(&backend)
+            .insert(&first.id, first.clone())
+            .await
+            .unwrap();
+        assert!(compendium.get_random_card().await.is_err());
+        // Cache misses are read from storage
+        assert_eq!(
+            compendium.get_card(&first.id).await.unwrap(),
+            Some(first.clone())
+        );
+
+        compendium.invalidate();
+        assert_eq!(compendium.get_random_card().await.unwrap(), first);
+
+        let second = card("second");
+        compendium
+            .cards()
+            .insert(&second.id, second.clone())
+            .await
+            .unwrap();
+        let ret = compendium.get_cards(&[first.id, second.id]).await.unwrap();
+        assert_eq!(ret[&first.id], Some(first.clone()));
+        assert_eq!(ret[&second.id], Some(second.clone()));
+        for _ in 0..10 {
+            assert_eq!(compendium.get_random_card().await.unwrap(), first);
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_is_refreshed_after_the_refresh_interval() {
+        let backend = Arc::new(MemoryBackend::new());
+        let compendium = Compendium::new(cards_store(&backend), Duration::from_millis(50));
+        assert!(compendium.get_random_card().await.is_err());
+
+        let first = card("first");
+        cards_store(&backend)
+            .insert(&first.id, first.clone())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(compendium.get_random_card().await.unwrap(), first);
+    }
+}
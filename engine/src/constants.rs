@@ -1,5 +1,7 @@
 pub const DRAW_COST: u32 = 100;
 pub const DAILY_CURRENCY_REWARD: u32 = 50;
+/// Firestore accepts at most 10 values in the query for characters already on a job
+pub const MAX_JOB_CHARACTERS: usize = 10;
 // pub const NUM_AVAILABLE_JOBS: u32 = 3;
 pub const SCRAP_REFUND: u32 = 20;
 pub const USER_STARTING_CURRENCY: u32 = 200;
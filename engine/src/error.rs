@@ -23,11 +23,14 @@ pub enum ErrorCode {
     DrawStagePopulated,
     IdMismatch,
     InsufficientFunds,
+    InvalidCharacterCount,
     JobNotComplete,
     JobNotFound,
     Other,
+    StorageCircuitOpen,
     StorageGeneric,
     StorageTransaction,
+    StorageUnavailable,
     UserNotFound,
 }
 
@@ -36,23 +39,32 @@ impl Error {
         Error { code, source }
     }
 
+    /// Whether the operation that failed with this error could succeed if run again
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.classify(), ErrorCategory::InternalRetryable)
+    }
+
     pub fn classify(&self) -> ErrorCategory {
         match self.code {
             ErrorCode::CardNotFound
             | ErrorCode::CharacterNotFound
             | ErrorCode::IdMismatch
+            | ErrorCode::InvalidCharacterCount
             | ErrorCode::JobNotFound
             | ErrorCode::UserNotFound => ErrorCategory::BadRequest,
-            ErrorCode::CompendiumEmpty | ErrorCode::Other | ErrorCode::StorageGeneric => {
-                ErrorCategory::Internal
-            }
+            ErrorCode::CompendiumEmpty
+            | ErrorCode::Other
+            | ErrorCode::StorageCircuitOpen
+            | ErrorCode::StorageGeneric => ErrorCategory::Internal,
             ErrorCode::CharacterPreoccupied
             | ErrorCode::DailyAlreadyClaimed
             | ErrorCode::DrawStageEmpty
             | ErrorCode::DrawStagePopulated
             | ErrorCode::InsufficientFunds
             | ErrorCode::JobNotComplete => ErrorCategory::FailedPrecondition,
-            ErrorCode::StorageTransaction => ErrorCategory::InternalRetryable,
+            ErrorCode::StorageTransaction | ErrorCode::StorageUnavailable => {
+                ErrorCategory::InternalRetryable
+            }
         }
     }
 }
@@ -76,6 +88,12 @@ impl From<storage::Error> for Error {
     fn from(e: storage::Error) -> Self {
         let code = match e {
             storage::Error::Transaction(_) => ErrorCode::StorageTransaction,
+            storage::Error::Service(ref s) if s.code == storage::ServiceErrorCode::Aborted => {
+                ErrorCode::StorageTransaction
+            }
+            // Retrying would only fail fast again until the circuit breaker lets requests through
+            storage::Error::Unavailable(_) => ErrorCode::StorageCircuitOpen,
+            ref e if e.is_retryable() => ErrorCode::StorageUnavailable,
             _ => ErrorCode::StorageGeneric,
         };
         Error::new(code, Some(e.into()))
@@ -3,7 +3,7 @@ use chrono::Utc;
 use dashmap::DashMap;
 use engine::ErrorCode;
 use pccg_rs_models::{Job, JobPrototype};
-use pccg_rs_storage::firestore::FirestoreClient;
+use pccg_rs_storage::{retry::RetryPolicy, DocumentStore};
 use rand::{rngs::StdRng, SeedableRng};
 use std::sync::Arc;
 use tokio::{
@@ -24,7 +24,12 @@ pub struct JobBoard {
 }
 
 impl JobBoard {
-    pub async fn new(prototypes_client: FirestoreClient) -> JobBoard {
+    /// Start refreshing the available jobs from `prototypes_client` once a day, retrying reads
+    /// that fail with a retryable error according to `retry_policy`
+    pub async fn new<S: DocumentStore + 'static>(
+        prototypes_client: S,
+        retry_policy: RetryPolicy,
+    ) -> JobBoard {
         let available_jobs_cache = Arc::new(DashMap::new());
 
         let _refresh_jobs_last_checked = Arc::new(Mutex::new(chrono::MIN_DATE));
@@ -39,13 +44,16 @@ impl JobBoard {
                 // Refresh on day roll over
                 if *_refresh_jobs_last_checked_clone < current_date {
                     info!("Generating jobs for {}", current_date);
-                    if let Err(e) = JobBoard::generate_jobs(
-                        Arc::clone(&available_jobs_cache_clone),
-                        &current_date,
-                        &prototypes_client,
-                    )
-                    .await
-                    {
+                    let generated = retry_policy
+                        .run("generate_jobs", engine::Error::is_retryable, || {
+                            JobBoard::generate_jobs(
+                                Arc::clone(&available_jobs_cache_clone),
+                                &current_date,
+                                &prototypes_client,
+                            )
+                        })
+                        .await;
+                    if let Err(e) = generated {
                         error!("Error generating jobs for {}: {:?}", current_date, e);
                     } else {
                         *_refresh_jobs_last_checked_clone = current_date;
@@ -102,10 +110,10 @@ impl JobBoard {
         }
     }
 
-    async fn generate_jobs(
+    async fn generate_jobs<S: DocumentStore>(
         cache: Arc<DashMap<JobTier, Vec<JobPrototype>>>,
         date: &chrono::Date<Utc>,
-        prototypes_client: &FirestoreClient,
+        prototypes_client: &S,
     ) -> engine::Result<()> {
         let days_since_epoch = (*date - chrono::MIN_DATE).num_days();
         let _rng: StdRng = SeedableRng::seed_from_u64(days_since_epoch as u64);
@@ -124,8 +132,8 @@ impl JobBoard {
         Ok(())
     }
 
-    async fn get_job_prototypes(
-        client: &FirestoreClient,
+    async fn get_job_prototypes<S: DocumentStore>(
+        client: &S,
         tier: JobTier,
     ) -> engine::Result<Vec<JobPrototype>> {
         let subcollection_relative_path = match tier {
@@ -135,11 +143,7 @@ impl JobBoard {
         }
         .to_owned();
 
-        let client = FirestoreClient::new_for_subcollection(
-            client,
-            subcollection_relative_path,
-            "prototypes".to_owned(),
-        );
+        let client = client.subcollection(subcollection_relative_path, "prototypes".to_owned());
 
         Ok(client.list::<JobPrototype>().await?)
     }
@@ -4,6 +4,8 @@ extern crate log;
 pub mod api;
 pub use self::api::Api;
 
+pub mod compendium;
+
 mod error;
 pub use self::error::*;
 
@@ -2,13 +2,22 @@ extern crate env_logger;
 #[macro_use]
 extern crate log;
 
-use pccg_rs_engine::{constants, job_board::JobBoard, Api};
-use pccg_rs_storage::firestore::{Firestore, FirestoreClient};
+use pccg_rs_engine::{
+    compendium::Compendium, constants, job_board::JobBoard, job_board::JobTier, Api, ErrorCode,
+};
+use pccg_rs_models::{
+    stats::{StatsF, StatsI},
+    Card, JobPrototype,
+};
+#[cfg(feature = "test_uses_emulator")]
+use pccg_rs_storage::firestore::{Firestore, FirestoreClient, HttpOptions};
+#[cfg(not(feature = "test_uses_emulator"))]
+use pccg_rs_storage::memory::{MemoryBackend, MemoryStore};
+use pccg_rs_storage::{retry::RetryPolicy, DocumentStore};
 use std::sync::Arc;
 use std::time::Duration;
 use uuid::Uuid;
 
-static JSON_KEY_PATH: &str = "../secrets/service_account.json";
 static UUID_NAMESPACE: &str = "6e81479f-5718-4d5c-aab7-6eb6de4465c2";
 
 fn logging_init() {
@@ -23,50 +32,119 @@ fn generate_uuid(string: &str) -> Uuid {
     Uuid::new_v5(&namespace, string.as_bytes())
 }
 
-async fn recreate_user(api: Arc<Api>, user_id: &Uuid) {
-    if let Err(e) = api.delete_user(user_id).await {
-        if let pccg_rs_engine::ErrorCode::UserNotFound = e.code {
-            // Deleting user failed because it did not exist to begin with. This is fine
-        } else {
-            assert!(
-                false,
-                "Unexpected error when deleting user to recreate: {:?}",
-                e
-            );
-        }
-    }
+#[cfg(not(feature = "test_uses_emulator"))]
+type TestStore = MemoryStore;
+
+#[cfg(feature = "test_uses_emulator")]
+type TestStore = FirestoreClient;
+
+/// Create empty cards, jobs and users collections in a fresh in-memory store
+#[cfg(not(feature = "test_uses_emulator"))]
+async fn build_stores() -> (TestStore, TestStore, TestStore) {
+    let backend = Arc::new(MemoryBackend::new());
+    (
+        MemoryStore::new(Arc::clone(&backend), None, "cards".to_owned()),
+        MemoryStore::new(Arc::clone(&backend), None, "jobs".to_owned()),
+        MemoryStore::new(Arc::clone(&backend), None, "users".to_owned()),
+    )
+}
+
+/// Create empty cards, jobs and users collections in the Firestore emulator at
+/// `FIRESTORE_EMULATOR_HOST`. Each call uses a new project, so tests running in parallel do not
+/// see each other's documents.
+#[cfg(feature = "test_uses_emulator")]
+async fn build_stores() -> (TestStore, TestStore, TestStore) {
+    let host = std::env::var("FIRESTORE_EMULATOR_HOST")
+        .expect("FIRESTORE_EMULATOR_HOST must be set to run tests against the emulator");
+    let project_id = format!("pccg-rs-test-{}", Uuid::new_v4());
+    let firestore = Firestore::new_for_emulator(&host, &project_id, HttpOptions::default())
+        .await
+        .unwrap();
+    let firestore = Arc::new(firestore);
+    (
+        FirestoreClient::new(Arc::clone(&firestore), None, "cards".to_owned()),
+        FirestoreClient::new(Arc::clone(&firestore), None, "jobs".to_owned()),
+        FirestoreClient::new(Arc::clone(&firestore), None, "users".to_owned()),
+    )
+}
+
+/// Build an engine over fresh stores, seeded with a single card and a single beginner job
+/// prototype of the given duration
+async fn build_api(job_duration_mins: u32) -> Arc<Api<TestStore>> {
+    let (cards, jobs, users) = build_stores().await;
+
+    let card = Card {
+        id: generate_uuid("test card"),
+        name: "test card".to_owned(),
+        description: "test description".to_owned(),
+        image_uri: "https://localhost/test_uri.png".to_owned(),
+        stat_base: StatsI {
+            physical: 10,
+            mental: 10,
+            tactical: 10,
+        },
+        stat_multiplier: StatsF {
+            physical: 1.0,
+            mental: 1.0,
+            tactical: 1.0,
+        },
+    };
+    cards.upsert(&card.id, card.clone(), None).await.unwrap();
 
-    tokio::time::sleep(Duration::from_secs(2)).await;
+    let prototype = JobPrototype {
+        id: generate_uuid("test job"),
+        name: "test job".to_owned(),
+        description: "test description".to_owned(),
+        recommended_stats: StatsF::default(),
+        duration_mins: job_duration_mins,
+    };
+    jobs.subcollection("beginner".to_owned(), "prototypes".to_owned())
+        .upsert(&prototype.id, prototype.clone(), None)
+        .await
+        .unwrap();
 
-    if let Err(e) = api.add_user(&user_id).await {
-        assert!(false, "Unexpected error when recreating user: {:?}", e);
+    let job_board = JobBoard::new(jobs, RetryPolicy::default()).await;
+    let compendium = Compendium::new(cards, Duration::from_secs(60));
+    let api = Arc::new(Api::new(compendium, job_board, users, RetryPolicy::default()).await);
+
+    // Jobs are generated in the background, wait for them to show up
+    while api
+        .list_available_jobs(&JobTier::Beginner)
+        .await
+        .unwrap()
+        .is_empty()
+    {
+        tokio::time::sleep(Duration::from_millis(1)).await;
     }
+
+    api
+}
+
+/// Add a new user, draw the only card in the compendium and promote it to a character.
+/// Returns the id of the character.
+async fn add_user_with_character(api: &Api<TestStore>, user_id: &Uuid) -> Uuid {
+    api.add_user(user_id).await.unwrap();
+    api.draw_card(user_id).await.unwrap();
+    let card = api.get_staged_card(user_id).await.unwrap().unwrap();
+    api.promote_staged_card(user_id, &card.id).await.unwrap();
+    let characters = api.list_characters(user_id).await.unwrap();
+    assert_eq!(characters.len(), 1);
+    characters[0].id
 }
 
 #[tokio::test(flavor = "multi_thread")]
 async fn claim_daily_increases_currency_once() {
     logging_init();
 
-    let fs = Arc::new(Firestore::new(JSON_KEY_PATH).await.unwrap());
-    let cards = FirestoreClient::new(Arc::clone(&fs), None, "_test_cards".to_owned());
-    let users = FirestoreClient::new(Arc::clone(&fs), None, "_test_users".to_owned());
-    let job_board = JobBoard::new(FirestoreClient::new(
-        Arc::clone(&fs),
-        None,
-        "_test_jobs".to_owned(),
-    ))
-    .await;
-    let api = Arc::new(Api::new(cards, job_board, users).await);
-
-    tokio::time::sleep(Duration::from_secs(2)).await;
+    let api = build_api(0).await;
 
     // Add a new user
     info!(
-        "[{}] Deleting and adding new user",
+        "[{}] Adding new user",
         stringify!(claim_daily_increases_currency_once)
     );
     let user_id = generate_uuid(stringify!(claim_daily_increases_currency_once));
-    recreate_user(Arc::clone(&api), &user_id).await;
+    api.add_user(&user_id).await.unwrap();
 
     // Save the starting currency amount
     info!(
@@ -76,8 +154,6 @@ async fn claim_daily_increases_currency_once() {
     let user = api.get_user(&user_id).await.unwrap().unwrap();
     let starting_currency = user.currency;
 
-    tokio::time::sleep(Duration::from_secs(2)).await;
-
     // Claim daily first time
     info!(
         "[{}] Claming daily once",
@@ -85,8 +161,6 @@ async fn claim_daily_increases_currency_once() {
     );
     let ret = api.claim_user_daily_reward(&user_id).await;
 
-    tokio::time::sleep(Duration::from_secs(2)).await;
-
     // Claim daily second time
     info!(
         "[{}] Claming daily twice",
@@ -94,8 +168,6 @@ async fn claim_daily_increases_currency_once() {
     );
     let ret2 = api.claim_user_daily_reward(&user_id).await;
 
-    tokio::time::sleep(Duration::from_secs(2)).await;
-
     // Fetch the updated currency amount
     info!(
         "[{}] Fetching updated curency amount",
@@ -121,54 +193,201 @@ async fn claim_daily_increases_currency_once() {
 async fn can_complete_finished_job() {
     logging_init();
 
-    let fs = Arc::new(Firestore::new(JSON_KEY_PATH).await.unwrap());
-    let cards = FirestoreClient::new(Arc::clone(&fs), None, "_test_cards".to_owned());
-    let users = FirestoreClient::new(Arc::clone(&fs), None, "_test_users".to_owned());
-    let job_board = JobBoard::new(FirestoreClient::new(
-        Arc::clone(&fs),
-        None,
-        "_test_jobs".to_owned(),
-    ))
-    .await;
-    let api = Arc::new(Api::new(cards, job_board, users).await);
-
-    tokio::time::sleep(Duration::from_secs(2)).await;
+    let api = build_api(0).await;
 
-    // Add a new user
+    // Add a new user with a character
     info!(
-        "[{}] Deleting and adding new user",
+        "[{}] Adding new user with a character",
         stringify!(can_complete_finished_job)
     );
     let user_id = generate_uuid(stringify!(can_complete_finished_job));
-    recreate_user(Arc::clone(&api), &user_id).await;
+    let character_id = add_user_with_character(&api, &user_id).await;
+    let starting_currency = api.get_user(&user_id).await.unwrap().unwrap().currency;
+
+    // Take a job that finishes immediately
+    info!("[{}] Taking job", stringify!(can_complete_finished_job));
+    let prototype_id = generate_uuid("test job");
+    let job = api
+        .take_job(user_id, &prototype_id, vec![character_id])
+        .await
+        .unwrap();
+    assert_eq!(
+        api.get_current_job_for_character(&user_id, &character_id)
+            .await
+            .unwrap(),
+        Some(job.clone())
+    );
+
+    // Complete the job
+    info!("[{}] Completing job", stringify!(can_complete_finished_job));
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    let report = api.complete_job(&user_id, &job.id).await.unwrap();
 
-    // TODO
+    // Assert that rewards were applied and the job was removed
+    info!(
+        "[{}] Running assertions",
+        stringify!(can_complete_finished_job)
+    );
+    let user = api.get_user(&user_id).await.unwrap().unwrap();
+    assert_eq!(user.currency, starting_currency + report.currency_gain);
+    assert_eq!(report.experience_gain.len(), 1);
+    let character = api
+        .get_character(&user_id, &character_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(character.level, report.experience_gain[0].level_after);
+    assert_eq!(character.experience, report.experience_gain[0].exp_after);
+    assert_eq!(api.get_job(&user_id, &job.id).await.unwrap(), None);
+    assert!(api.list_jobs(&user_id).await.unwrap().is_empty());
 }
 
 #[tokio::test(flavor = "multi_thread")]
 async fn cannot_complete_unfinished_job() {
     logging_init();
 
-    let fs = Arc::new(Firestore::new(JSON_KEY_PATH).await.unwrap());
-    let cards = FirestoreClient::new(Arc::clone(&fs), None, "_test_cards".to_owned());
-    let users = FirestoreClient::new(Arc::clone(&fs), None, "_test_users".to_owned());
-    let job_board = JobBoard::new(FirestoreClient::new(
-        Arc::clone(&fs),
-        None,
-        "_test_jobs".to_owned(),
-    ))
-    .await;
-    let api = Arc::new(Api::new(cards, job_board, users).await);
-
-    tokio::time::sleep(Duration::from_secs(2)).await;
+    let api = build_api(60).await;
 
-    // Add a new user
+    // Add a new user with a character
     info!(
-        "[{}] Deleting and adding new user",
+        "[{}] Adding new user with a character",
         stringify!(cannot_complete_unfinished_job)
     );
     let user_id = generate_uuid(stringify!(cannot_complete_unfinished_job));
-    recreate_user(Arc::clone(&api), &user_id).await;
+    let character_id = add_user_with_character(&api, &user_id).await;
+
+    // Take a job that finishes in an hour
+    info!(
+        "[{}] Taking job",
+        stringify!(cannot_complete_unfinished_job)
+    );
+    let prototype_id = generate_uuid("test job");
+    let job = api
+        .take_job(user_id, &prototype_id, vec![character_id])
+        .await
+        .unwrap();
+
+    // Attempt to complete the job, and to take another job with the same character
+    info!(
+        "[{}] Completing job",
+        stringify!(cannot_complete_unfinished_job)
+    );
+    let ret = api.complete_job(&user_id, &job.id).await;
+    let ret2 = api
+        .take_job(user_id, &prototype_id, vec![character_id])
+        .await;
+
+    info!(
+        "[{}] Running assertions",
+        stringify!(cannot_complete_unfinished_job)
+    );
+    match ret {
+        Err(e) => assert!(matches!(e.code, ErrorCode::JobNotComplete)),
+        Ok(_) => panic!("Completed an unfinished job"),
+    }
+    match ret2 {
+        Err(e) => assert!(matches!(e.code, ErrorCode::CharacterPreoccupied)),
+        Ok(_) => panic!("Took a job with a preoccupied character"),
+    }
+    assert_eq!(api.get_job(&user_id, &job.id).await.unwrap(), Some(job));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cannot_take_job_with_too_few_or_many_characters() {
+    logging_init();
+
+    let api = build_api(60).await;
+
+    let user_id = generate_uuid(stringify!(cannot_take_job_with_too_few_or_many_characters));
+    let character_id = add_user_with_character(&api, &user_id).await;
+    let prototype_id = generate_uuid("test job");
+    for character_ids in [vec![], vec![character_id; 11]] {
+        match api.take_job(user_id, &prototype_id, character_ids).await {
+            Err(e) => assert!(matches!(e.code, ErrorCode::InvalidCharacterCount)),
+            Ok(_) => panic!("Took a job with an invalid number of characters"),
+        }
+    }
+    assert!(api.list_jobs(&user_id).await.unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn can_delete_user() {
+    logging_init();
+
+    let api = build_api(0).await;
+
+    let user_id = generate_uuid(stringify!(can_delete_user));
+    let character_id = add_user_with_character(&api, &user_id).await;
+    api.take_job(user_id, &generate_uuid("test job"), vec![character_id])
+        .await
+        .unwrap();
+    assert_eq!(api.list_user_ids().await.unwrap(), vec![user_id]);
+
+    // The user's characters and jobs go with it
+    api.delete_user(&user_id).await.unwrap();
+    assert_eq!(api.get_user(&user_id).await.unwrap(), None);
+    assert!(api
+        .find_card_owners(&generate_uuid("test card"))
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(api
+        .list_jobs_due(chrono::Utc::now() + chrono::Duration::days(1))
+        .await
+        .unwrap()
+        .is_empty());
+    match api.delete_user(&user_id).await {
+        Err(e) => assert!(matches!(e.code, ErrorCode::UserNotFound)),
+        Ok(_) => panic!("Deleted a user that does not exist"),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn can_query_across_users() {
+    logging_init();
+
+    let api = build_api(60).await;
+
+    let user_ids = vec![
+        generate_uuid("can_query_across_users 1"),
+        generate_uuid("can_query_across_users 2"),
+    ];
+    let mut jobs = vec![];
+    for user_id in user_ids.iter() {
+        let character_id = add_user_with_character(&api, user_id).await;
+        let job = api
+            .take_job(*user_id, &generate_uuid("test job"), vec![character_id])
+            .await
+            .unwrap();
+        jobs.push(job);
+    }
+
+    let mut owners = api
+        .find_card_owners(&generate_uuid("test card"))
+        .await
+        .unwrap();
+    owners.sort();
+    let mut expected_owners = user_ids.clone();
+    expected_owners.sort();
+    assert_eq!(owners, expected_owners);
+    assert!(api
+        .find_card_owners(&Uuid::new_v4())
+        .await
+        .unwrap()
+        .is_empty());
 
-    // TODO
+    assert!(api
+        .list_jobs_due(chrono::Utc::now())
+        .await
+        .unwrap()
+        .is_empty());
+    let mut due = api
+        .list_jobs_due(chrono::Utc::now() + chrono::Duration::hours(2))
+        .await
+        .unwrap();
+    assert_eq!(due.len(), 2);
+    assert!(due[0].completion_time <= due[1].completion_time);
+    due.sort_by_key(|job| job.id);
+    jobs.sort_by_key(|job| job.id);
+    assert_eq!(due, jobs);
 }
@@ -0,0 +1,390 @@
+//! Derives conversions between model structs and Firestore documents.
+//!
+//! `#[derive(FirestoreDocument)]` on a struct with named fields generates
+//! `TryFrom<Document>` and `From<Self> for Document`. Each field is stored under its own name
+//! using the `FromDocumentField`/`IntoDocumentField` impls in `pccg_rs_storage::convert`.
+//!
+//! Container attributes:
+//! - `#[firestore(map)]` converts to and from a `DocumentField::MapValue` instead of a whole
+//!   document, so the struct can be nested as a field of another model
+//! - `#[firestore(version = 2)]` stamps written documents with a schema version, and upgrades
+//!   documents with an older version on read. Also implements `migration::Versioned`.
+//! - `#[firestore(migrations = "path")]` takes the migrations for `version` from the given
+//!   function returning `&'static [Migration]`
+//!
+//! Field attributes:
+//! - `#[firestore(id)]` reads the field from the last segment of the document name rather than
+//!   the fields, and leaves it out when writing
+//! - `#[firestore(rename = "name")]` stores the field under a different name
+//! - `#[firestore(default)]` or `#[firestore(default = "path")]` uses `Default::default()` or
+//!   the given function when the field is missing
+//! - `#[firestore(skip)]` neither reads nor writes the field, filling it from its default
+//!
+//! `Option` fields are optional without any attributes: `None` is left out of the document, and
+//! a missing or null field reads back as `None`.
+
+extern crate proc_macro;
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, LitInt, LitStr, Path, Type};
+
+#[proc_macro_derive(FirestoreDocument, attributes(firestore))]
+pub fn derive_firestore_document(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct ContainerAttrs {
+    map: bool,
+    version: Option<u32>,
+    migrations: Option<Path>,
+}
+
+enum MissingValue {
+    Required,
+    Default,
+    DefaultWith(Path),
+}
+
+struct FieldAttrs {
+    id: bool,
+    rename: Option<String>,
+    missing: MissingValue,
+    skip: bool,
+}
+
+struct FieldInfo {
+    ident: Ident,
+    ty: Type,
+    attrs: FieldAttrs,
+}
+
+impl FieldInfo {
+    fn stored_name(&self) -> String {
+        self.attrs
+            .rename
+            .clone()
+            .unwrap_or_else(|| self.ident.to_string())
+    }
+
+    fn default_value(&self) -> TokenStream {
+        match &self.attrs.missing {
+            MissingValue::DefaultWith(path) => quote!(#path()),
+            _ => quote!(::std::default::Default::default()),
+        }
+    }
+}
+
+fn parse_container_attrs(input: &DeriveInput) -> syn::Result<ContainerAttrs> {
+    let mut attrs = ContainerAttrs {
+        map: false,
+        version: None,
+        migrations: None,
+    };
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("firestore"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("map") {
+                attrs.map = true;
+            } else if meta.path.is_ident("version") {
+                let version: LitInt = meta.value()?.parse()?;
+                attrs.version = Some(version.base10_parse()?);
+            } else if meta.path.is_ident("migrations") {
+                let path: LitStr = meta.value()?.parse()?;
+                attrs.migrations = Some(path.parse()?);
+            } else {
+                return Err(meta.error("unknown firestore container attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    if attrs.map && attrs.version.is_some() {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "maps are versioned with the document they are nested in",
+        ));
+    }
+    if attrs.migrations.is_some() && attrs.version.is_none() {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "migrations need a schema version to migrate to",
+        ));
+    }
+    Ok(attrs)
+}
+
+fn parse_field_attrs(field: &syn::Field) -> syn::Result<FieldAttrs> {
+    let mut attrs = FieldAttrs {
+        id: false,
+        rename: None,
+        missing: MissingValue::Required,
+        skip: false,
+    };
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("firestore"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                attrs.id = true;
+            } else if meta.path.is_ident("rename") {
+                let name: LitStr = meta.value()?.parse()?;
+                attrs.rename = Some(name.value());
+            } else if meta.path.is_ident("default") {
+                attrs.missing = if meta.input.peek(syn::Token![=]) {
+                    let path: LitStr = meta.value()?.parse()?;
+                    MissingValue::DefaultWith(path.parse()?)
+                } else {
+                    MissingValue::Default
+                };
+            } else if meta.path.is_ident("skip") {
+                attrs.skip = true;
+            } else {
+                return Err(meta.error("unknown firestore field attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attrs)
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let container = parse_container_attrs(&input)?;
+    let named = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(named) => named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "FirestoreDocument can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "FirestoreDocument can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut fields = Vec::new();
+    for field in &named.named {
+        let attrs = parse_field_attrs(field)?;
+        if attrs.id && container.map {
+            return Err(syn::Error::new_spanned(
+                field,
+                "maps have no document name to take an id from",
+            ));
+        }
+        fields.push(FieldInfo {
+            ident: field.ident.clone().unwrap(),
+            ty: field.ty.clone(),
+            attrs,
+        });
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let reads = fields.iter().map(|f| read_field(name, f));
+    let writes = fields.iter().map(write_field);
+
+    let storage = quote!(::pccg_rs_storage);
+    let tokens = if container.map {
+        let type_name = name.to_string();
+        quote! {
+            impl #impl_generics ::std::convert::TryFrom<&#storage::firestore::DocumentField>
+                for #name #ty_generics #where_clause
+            {
+                type Error = ::std::string::String;
+
+                fn try_from(
+                    value: &#storage::firestore::DocumentField,
+                ) -> ::std::result::Result<Self, Self::Error> {
+                    let empty = ::std::collections::HashMap::new();
+                    let fields = match value {
+                        #storage::firestore::DocumentField::MapValue(dmv) => {
+                            // Firestore leaves out the fields of an empty map
+                            dmv.fields.as_ref().unwrap_or(&empty)
+                        }
+                        _ => {
+                            return ::std::result::Result::Err(::std::format!(
+                                "Expected DocumentMapValue to convert to {}, found {:?}",
+                                #type_name,
+                                value
+                            ))
+                        }
+                    };
+                    ::std::result::Result::Ok(#name { #(#reads,)* })
+                }
+            }
+
+            impl #impl_generics ::std::convert::From<#name #ty_generics>
+                for #storage::firestore::DocumentField #where_clause
+            {
+                fn from(value: #name #ty_generics) -> Self {
+                    let mut fields = ::std::collections::HashMap::new();
+                    #(#writes)*
+                    #storage::firestore::DocumentField::MapValue(
+                        #storage::firestore::DocumentMapValue {
+                            fields: ::std::option::Option::Some(fields),
+                        },
+                    )
+                }
+            }
+
+            impl #impl_generics #storage::convert::FromDocumentField
+                for #name #ty_generics #where_clause
+            {
+                fn from_document_field(
+                    field: &#storage::firestore::DocumentField,
+                ) -> ::std::result::Result<Self, ::std::string::String> {
+                    ::std::convert::TryFrom::try_from(field)
+                }
+            }
+
+            impl #impl_generics #storage::convert::IntoDocumentField
+                for #name #ty_generics #where_clause
+            {
+                fn into_document_field(self) -> #storage::firestore::DocumentField {
+                    self.into()
+                }
+            }
+        }
+    } else {
+        let (upgrade, stamp, versioned) = match container.version {
+            Some(version) => {
+                let migrations = match &container.migrations {
+                    Some(path) => quote!(#path()),
+                    None => quote!(&[]),
+                };
+                let type_name = name.to_string();
+                (
+                    quote! {
+                        let mut value = value;
+                        #storage::migration::upgrade_document(
+                            &mut value,
+                            #version,
+                            <Self as #storage::migration::Versioned>::migrations(),
+                        )
+                        .map_err(|e| {
+                            ::std::format!("Could not convert Document to {}: {}", #type_name, e)
+                        })?;
+                    },
+                    quote! {
+                        fields.insert(
+                            ::std::borrow::ToOwned::to_owned(
+                                #storage::migration::SCHEMA_VERSION_FIELD,
+                            ),
+                            #storage::firestore::DocumentField::IntegerValue(
+                                ::std::string::ToString::to_string(&#version),
+                            ),
+                        );
+                    },
+                    quote! {
+                        impl #impl_generics #storage::migration::Versioned
+                            for #name #ty_generics #where_clause
+                        {
+                            const SCHEMA_VERSION: u32 = #version;
+
+                            fn migrations() -> &'static [#storage::migration::Migration] {
+                                #migrations
+                            }
+                        }
+                    },
+                )
+            }
+            None => (TokenStream::new(), TokenStream::new(), TokenStream::new()),
+        };
+        quote! {
+            impl #impl_generics ::std::convert::TryFrom<#storage::firestore::Document>
+                for #name #ty_generics #where_clause
+            {
+                type Error = ::std::string::String;
+
+                fn try_from(
+                    value: #storage::firestore::Document,
+                ) -> ::std::result::Result<Self, Self::Error> {
+                    #upgrade
+                    let fields = &value.fields;
+                    ::std::result::Result::Ok(#name { #(#reads,)* })
+                }
+            }
+
+            impl #impl_generics ::std::convert::From<#name #ty_generics>
+                for #storage::firestore::Document #where_clause
+            {
+                fn from(value: #name #ty_generics) -> Self {
+                    let mut fields = ::std::collections::HashMap::new();
+                    #(#writes)*
+                    #stamp
+                    #storage::firestore::Document::new(fields)
+                }
+            }
+
+            #versioned
+        }
+    };
+    Ok(tokens)
+}
+
+fn read_field(struct_name: &Ident, field: &FieldInfo) -> TokenStream {
+    let ident = &field.ident;
+    if field.attrs.skip {
+        let default = field.default_value();
+        return quote!(#ident: #default);
+    }
+    if field.attrs.id {
+        return quote! {
+            #ident: value.extract_id().map_err(|e| {
+                ::std::format!("Could not convert Document to {}: {}", stringify!(#struct_name), e)
+            })?
+        };
+    }
+
+    let ty = &field.ty;
+    let stored_name = field.stored_name();
+    let trait_path = quote!(::pccg_rs_storage::convert::FromDocumentField);
+    let missing = match &field.attrs.missing {
+        MissingValue::Required => quote! {
+            <#ty as #trait_path>::from_missing_field().ok_or_else(|| {
+                ::std::format!("Missing field '{}'", #stored_name)
+            })?
+        },
+        _ => field.default_value(),
+    };
+    quote! {
+        #ident: match fields.get(#stored_name) {
+            ::std::option::Option::Some(field) => {
+                <#ty as #trait_path>::from_document_field(field).map_err(|e| {
+                    ::std::format!("Error converting field '{}': {}", #stored_name, e)
+                })?
+            }
+            ::std::option::Option::None => #missing,
+        }
+    }
+}
+
+fn write_field(field: &FieldInfo) -> TokenStream {
+    if field.attrs.skip || field.attrs.id {
+        return TokenStream::new();
+    }
+    let ident = &field.ident;
+    let stored_name = field.stored_name();
+    let trait_path = quote!(::pccg_rs_storage::convert::IntoDocumentField);
+    quote! {
+        if !#trait_path::is_absent(&value.#ident) {
+            fields.insert(
+                ::std::borrow::ToOwned::to_owned(#stored_name),
+                #trait_path::into_document_field(value.#ident),
+            );
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use pccg_rs_models_derive::FirestoreDocument;
+use pccg_rs_storage::{
+    firestore::{Document, DocumentField},
+    migration::{self, Migration, Versioned},
+};
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+};
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Default, FirestoreDocument, PartialEq)]
+#[firestore(map)]
+struct Inner {
+    count: u32,
+}
+
+#[derive(Clone, Debug, FirestoreDocument, PartialEq)]
+struct Outer {
+    #[firestore(id)]
+    id: Uuid,
+    #[firestore(rename = "displayName")]
+    name: String,
+    nickname: Option<String>,
+    #[firestore(default)]
+    inner: Inner,
+    #[firestore(default = "default_tags")]
+    tags: Vec<String>,
+    #[firestore(default)]
+    favourite: bool,
+    #[firestore(skip)]
+    cached: Option<u32>,
+}
+
+fn default_tags() -> Vec<String> {
+    vec!["untagged".to_owned()]
+}
+
+fn outer() -> Outer {
+    Outer {
+        id: Uuid::new_v4(),
+        name: "outer".to_owned(),
+        nickname: None,
+        inner: Inner { count: 3 },
+        tags: vec!["a".to_owned(), "b".to_owned()],
+        favourite: true,
+        cached: None,
+    }
+}
+
+#[test]
+fn can_round_trip_through_document() {
+    let value = outer();
+    let mut doc: Document = value.clone().into();
+    doc.name = format!("parent_path/{}", value.id);
+
+    assert!(doc.fields.contains_key("displayName"));
+    assert!(!doc.fields.contains_key("nickname"));
+    assert!(!doc.fields.contains_key("id"));
+    assert!(!doc.fields.contains_key("cached"));
+
+    let from_doc: Outer = doc.try_into().unwrap();
+    assert_eq!(value, from_doc);
+}
+
+#[test]
+fn missing_fields_use_defaults() {
+    let id = Uuid::new_v4();
+    let mut fields = HashMap::new();
+    fields.insert(
+        "displayName".to_owned(),
+        DocumentField::StringValue("outer".to_owned()),
+    );
+    let mut doc = Document::new(fields);
+    doc.name = format!("parent_path/{}", id);
+
+    let from_doc = Outer::try_from(doc).unwrap();
+    assert_eq!(from_doc.id, id);
+    assert_eq!(from_doc.nickname, None);
+    assert_eq!(from_doc.inner, Inner::default());
+    assert_eq!(from_doc.tags, default_tags());
+    assert!(!from_doc.favourite);
+    assert_eq!(from_doc.cached, None);
+}
+
+#[test]
+fn reports_missing_and_mistyped_fields() {
+    let mut doc = Document::new(HashMap::new());
+    doc.name = format!("parent_path/{}", Uuid::new_v4());
+    let err = Outer::try_from(doc.clone()).unwrap_err();
+    assert_eq!(err, "Missing field 'displayName'");
+
+    doc.fields
+        .insert("displayName".to_owned(), DocumentField::DoubleValue(1.0));
+    let err = Outer::try_from(doc).unwrap_err();
+    assert!(err.starts_with("Error converting field 'displayName'"));
+}
+
+#[derive(Clone, Debug, FirestoreDocument, PartialEq)]
+#[firestore(version = 1, migrations = "versioned_migrations")]
+struct Versioned1 {
+    name: String,
+    level: u32,
+}
+
+fn versioned_migrations() -> &'static [Migration] {
+    &[Migration {
+        from_version: 0,
+        description: "Add level",
+        upgrade: |doc| {
+            doc.fields.insert(
+                "level".to_owned(),
+                DocumentField::IntegerValue("1".to_owned()),
+            );
+            Ok(())
+        },
+    }]
+}
+
+#[test]
+fn versioned_documents_are_stamped_and_upgraded_on_read() {
+    assert_eq!(Versioned1::SCHEMA_VERSION, 1);
+    let value = Versioned1 {
+        name: "versioned".to_owned(),
+        level: 4,
+    };
+    let doc: Document = value.clone().into();
+    assert_eq!(migration::schema_version(&doc), Ok(1));
+    assert_eq!(Versioned1::try_from(doc).unwrap(), value);
+
+    let mut fields = HashMap::new();
+    fields.insert(
+        "name".to_owned(),
+        DocumentField::StringValue("old".to_owned()),
+    );
+    let from_doc = Versioned1::try_from(Document::new(fields)).unwrap();
+    assert_eq!(from_doc.level, 1);
+}
@@ -1,22 +1,24 @@
 use crate::stats::StatsF;
 use crate::Card;
-use pccg_rs_storage::firestore::{Document, DocumentField};
+use pccg_rs_models_derive::FirestoreDocument;
 use std::{
-    collections::HashMap,
     convert::{TryFrom, TryInto},
     sync::Arc,
 };
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, FirestoreDocument, serde::Deserialize, serde::Serialize)]
+#[firestore(version = 1, migrations = "crate::migrations::character")]
 pub struct Character {
+    #[firestore(id)]
     pub id: Uuid,
     pub prototype_id: Uuid,
     pub level: u32,
     pub experience: u32,
     #[serde(skip)]
     #[serde(default = "default_prototype_field")]
+    #[firestore(skip, default = "default_prototype_field")]
     prototype: Arc<Mutex<Option<Card>>>,
 }
 
@@ -57,59 +59,6 @@ impl PartialEq for Character {
     }
 }
 
-impl TryFrom<Document> for Character {
-    type Error = String;
-
-    fn try_from(value: Document) -> Result<Self, Self::Error> {
-        let id = value.extract_id();
-        if let Err(e) = id {
-            return Err(format!("Could not convert Document to Character: {}", e));
-        }
-        let id = id.unwrap();
-
-        let prototype_id;
-        match Uuid::parse_str(&value.extract_string("prototype_id")?) {
-            Ok(id) => prototype_id = id,
-            Err(e) => {
-                return Err(format!(
-                "Could not convert Document to Character: error parsing field 'prototype_id': {}",
-                e
-            ))
-            }
-        }
-
-        let level = value.extract_integer("level")?;
-        let experience = value.extract_integer("experience")?;
-
-        Ok(Character {
-            id,
-            prototype_id,
-            level,
-            experience,
-            prototype: default_prototype_field(),
-        })
-    }
-}
-
-impl Into<Document> for Character {
-    fn into(self) -> Document {
-        let mut fields = HashMap::new();
-        fields.insert(
-            "prototype_id".to_owned(),
-            DocumentField::StringValue(self.prototype_id.to_string()),
-        );
-        fields.insert(
-            "level".to_owned(),
-            DocumentField::IntegerValue(self.level.to_string()),
-        );
-        fields.insert(
-            "experience".to_owned(),
-            DocumentField::IntegerValue(self.experience.to_string()),
-        );
-        Document::new(fields)
-    }
-}
-
 #[derive(serde::Serialize)]
 pub struct CharacterEx {
     pub id: Uuid,
@@ -159,6 +108,7 @@ impl TryFrom<Character> for CharacterEx {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use pccg_rs_storage::firestore::Document;
 
     #[test]
     fn can_convert_between_document_and_character() {
@@ -1,9 +1,16 @@
+use pccg_rs_storage::{http_client::HttpOptions, retry::RetryPolicy};
 use serde::Deserialize;
+use std::time::Duration;
 
 #[derive(Clone, Deserialize)]
 pub struct Config {
     pub compendium: CompendiumConfig,
-    pub firestore: FirestoreConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    pub firestore: Option<FirestoreConfig>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    pub sqlite: Option<SqliteConfig>,
     pub user_registry: UserRegistryConfig,
     pub server: ServerConfig,
 }
@@ -11,6 +18,19 @@ pub struct Config {
 #[derive(Clone, Deserialize)]
 pub struct CompendiumConfig {
     pub directory: String,
+    /// How long each server instance keeps its cached copy of the compendium before reloading it
+    #[serde(default = "default_cache_refresh_secs")]
+    pub cache_refresh_secs: u64,
+}
+
+impl CompendiumConfig {
+    pub fn get_cache_refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.cache_refresh_secs)
+    }
+}
+
+fn default_cache_refresh_secs() -> u64 {
+    300
 }
 
 #[derive(Clone, Deserialize)]
@@ -33,7 +53,109 @@ pub struct UserRegistryConfig {
     pub directory: String,
 }
 
+/// How to authenticate with Firestore, checked in this order:
+/// - `emulator_host` and `project_id` to connect to a local Firestore emulator instead
+/// - `secret`, the path to a service account key
+/// - `secret_env`, an environment variable holding a service account key, as JSON or base64
+/// - `token_env`, an environment variable holding an access token, which is never renewed
+/// - `metadata_server`, the token endpoint of a metadata server, usually
+///   `pccg_rs_storage::credentials::METADATA_TOKEN_URI`
+///
+/// `project_id` is required unless authenticating with a service account key.
+///
+/// The remaining settings tune the HTTP client, and default to those of `HttpOptions`.
 #[derive(Clone, Deserialize)]
 pub struct FirestoreConfig {
-    pub secret: String,
+    pub secret: Option<String>,
+    pub secret_env: Option<String>,
+    pub token_env: Option<String>,
+    pub metadata_server: Option<String>,
+    pub emulator_host: Option<String>,
+    pub project_id: Option<String>,
+    pub request_timeout_ms: Option<u64>,
+    pub connect_timeout_ms: Option<u64>,
+    pub pool_idle_timeout_secs: Option<u64>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub circuit_breaker_threshold: Option<u32>,
+    pub circuit_breaker_reset_secs: Option<u64>,
+}
+
+impl FirestoreConfig {
+    pub fn get_http_options(&self) -> HttpOptions {
+        let defaults = HttpOptions::default();
+        HttpOptions {
+            request_timeout: self
+                .request_timeout_ms
+                .map_or(defaults.request_timeout, Duration::from_millis),
+            connect_timeout: self
+                .connect_timeout_ms
+                .map_or(defaults.connect_timeout, Duration::from_millis),
+            pool_idle_timeout: self
+                .pool_idle_timeout_secs
+                .map_or(defaults.pool_idle_timeout, Duration::from_secs),
+            pool_max_idle_per_host: self
+                .pool_max_idle_per_host
+                .unwrap_or(defaults.pool_max_idle_per_host),
+            circuit_breaker_threshold: self
+                .circuit_breaker_threshold
+                .unwrap_or(defaults.circuit_breaker_threshold),
+            circuit_breaker_reset: self
+                .circuit_breaker_reset_secs
+                .map_or(defaults.circuit_breaker_reset, Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct StorageConfig {
+    #[serde(default)]
+    pub backend: StorageBackend,
+}
+
+/// Which document store the server persists to. The matching section, e.g. `[sqlite]`, must also
+/// be present in the config. The filesystem store keeps users under `user_registry.directory` and
+/// everything else under `compendium.directory`.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    Filesystem,
+    #[default]
+    Firestore,
+    Sqlite,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SqliteConfig {
+    pub path: String,
+}
+
+/// How storage operations that fail with a retryable error, e.g. a contended transaction, are
+/// retried. Each retry waits a random delay of up to `base_delay_ms` doubled for every previous
+/// retry, and at most `max_delay_ms`.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub max_attempts: usize,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl RetryConfig {
+    pub fn get_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::new(
+            self.max_attempts,
+            Duration::from_millis(self.base_delay_ms),
+            Duration::from_millis(self.max_delay_ms),
+        )
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay_ms: 300,
+            max_delay_ms: 5000,
+        }
+    }
 }
@@ -1,15 +1,13 @@
 use crate::stats::StatsF;
-use chrono::{DateTime, Utc};
-use pccg_rs_storage::firestore::{Document, DocumentArrayValue, DocumentField};
-use std::{
-    collections::HashMap,
-    convert::{TryFrom, TryInto},
-    hash::Hash,
-};
+use chrono::{DateTime, SubsecRound, Utc};
+use pccg_rs_models_derive::FirestoreDocument;
+use std::hash::Hash;
 use uuid::Uuid;
 
-#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, FirestoreDocument, PartialEq, serde::Deserialize, serde::Serialize)]
+#[firestore(version = 1, migrations = "crate::migrations::job")]
 pub struct Job {
+    #[firestore(id)]
     pub id: Uuid,
     pub name: String,
     pub description: String,
@@ -22,12 +20,16 @@ pub struct Job {
 
 impl Job {
     pub fn new(prototype: &JobPrototype, user_id: Uuid, character_ids: Vec<Uuid>) -> Job {
+        // Firestore only keeps timestamps to the microsecond, so truncate up front to get back
+        // exactly what was stored
+        let completion_time =
+            Utc::now().trunc_subsecs(6) + chrono::Duration::minutes(prototype.duration_mins as i64);
         Job {
             id: Uuid::new_v4(),
             name: prototype.name.clone(),
             description: prototype.description.clone(),
             recommended_stats: prototype.recommended_stats.clone(),
-            completion_time: Utc::now() + chrono::Duration::minutes(prototype.duration_mins as i64),
+            completion_time,
             user_id,
             character_ids,
         }
@@ -38,86 +40,9 @@ impl Job {
     }
 }
 
-impl TryFrom<Document> for Job {
-    type Error = String;
-
-    fn try_from(value: Document) -> Result<Self, Self::Error> {
-        let id = value.extract_id()?;
-        let name = value.extract_string("name")?;
-        let description = value.extract_string("description")?;
-        let recommended_stats = value
-            .fields
-            .get("recommended_stats")
-            .ok_or_else(|| format!("Missing field 'recommended_stats'"))?
-            .try_into()?;
-        let completion_time = value.extract_timestamp("completion_time")?;
-
-        let user_id_str = value.extract_string("user_id")?;
-        let user_id = Uuid::parse_str(&user_id_str).unwrap();
-        let character_ids = match value
-            .fields
-            .get("character_ids")
-            .ok_or_else(|| format!("Missing field 'character_ids"))?
-        {
-            DocumentField::ArrayValue(dav) => dav
-                .values
-                .as_ref()
-                .unwrap()
-                .into_iter()
-                .map(|df| Uuid::parse_str(&df.extract_string().unwrap()).unwrap())
-                .collect(),
-            df => return Err(format!("Error parsing ArrayValue from {:?}", df)),
-        };
-
-        Ok(Job {
-            id,
-            name,
-            description,
-            recommended_stats,
-            completion_time,
-            user_id,
-            character_ids,
-        })
-    }
-}
-
-impl Into<Document> for Job {
-    fn into(self) -> Document {
-        let mut fields = HashMap::new();
-        fields.insert("name".to_owned(), DocumentField::StringValue(self.name));
-        fields.insert(
-            "description".to_owned(),
-            DocumentField::StringValue(self.description),
-        );
-        fields.insert(
-            "recommended_stats".to_owned(),
-            self.recommended_stats.into(),
-        );
-        fields.insert(
-            "completion_time".to_owned(),
-            DocumentField::TimestampValue(self.completion_time),
-        );
-        fields.insert(
-            "user_id".to_owned(),
-            DocumentField::StringValue(self.user_id.to_string()),
-        );
-        fields.insert(
-            "character_ids".to_owned(),
-            DocumentField::ArrayValue(DocumentArrayValue {
-                values: Some(
-                    self.character_ids
-                        .into_iter()
-                        .map(|id| DocumentField::StringValue(id.to_string()))
-                        .collect(),
-                ),
-            }),
-        );
-        Document::new(fields)
-    }
-}
-
-#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, FirestoreDocument, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct JobPrototype {
+    #[firestore(id)]
     pub id: Uuid,
     pub name: String,
     pub description: String,
@@ -133,50 +58,6 @@ impl Hash for JobPrototype {
 
 impl Eq for JobPrototype {}
 
-impl TryFrom<Document> for JobPrototype {
-    type Error = String;
-
-    fn try_from(value: Document) -> Result<Self, Self::Error> {
-        let id = value.extract_id()?;
-        let name = value.extract_string("name")?;
-        let description = value.extract_string("description")?;
-        let recommended_stats = value
-            .fields
-            .get("recommended_stats")
-            .ok_or_else(|| format!("Missing field 'recommended_stats'"))?
-            .try_into()?;
-        let duration_mins = value.extract_integer("duration_mins")?;
-
-        Ok(JobPrototype {
-            id,
-            name,
-            description,
-            recommended_stats,
-            duration_mins,
-        })
-    }
-}
-
-impl Into<Document> for JobPrototype {
-    fn into(self) -> Document {
-        let mut fields = HashMap::new();
-        fields.insert("name".to_owned(), DocumentField::StringValue(self.name));
-        fields.insert(
-            "description".to_owned(),
-            DocumentField::StringValue(self.description),
-        );
-        fields.insert(
-            "recommended_stats".to_owned(),
-            self.recommended_stats.into(),
-        );
-        fields.insert(
-            "duration_mins".to_owned(),
-            DocumentField::IntegerValue(self.duration_mins.to_string()),
-        );
-        Document::new(fields)
-    }
-}
-
 #[derive(serde::Serialize)]
 pub struct JobCompletionReport {
     pub job: Job,
@@ -197,6 +78,8 @@ pub struct ExperienceGain {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use pccg_rs_storage::firestore::Document;
+    use std::convert::TryInto;
 
     #[test]
     fn can_convert_between_document_and_job() {
@@ -6,10 +6,12 @@ pub mod config;
 pub mod stats;
 
 mod user;
-pub use self::user::User;
+pub use self::user::{User, UserSubCollections};
 
 mod character;
 pub use self::character::{Character, CharacterEx};
 
 mod job;
 pub use self::job::{ExperienceGain, Job, JobCompletionReport, JobPrototype};
+
+mod migrations;
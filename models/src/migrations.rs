@@ -0,0 +1,52 @@
+//! The schema migrations of each versioned model. Documents written with an older schema version
+//! are upgraded by these as they are read, or all at once by the server's `migrate` command.
+
+use chrono::{TimeZone, Utc};
+use pccg_rs_storage::{
+    firestore::{Document, DocumentField},
+    migration::Migration,
+};
+
+pub(crate) fn user() -> &'static [Migration] {
+    &[Migration {
+        from_version: 0,
+        description: "Fill in currency and daily_last_claimed",
+        upgrade: |doc| {
+            set_if_missing(doc, "currency", DocumentField::IntegerValue("0".to_owned()));
+            set_if_missing(
+                doc,
+                "daily_last_claimed",
+                DocumentField::TimestampValue(Utc.timestamp_opt(0, 0).unwrap()),
+            );
+            Ok(())
+        },
+    }]
+}
+
+pub(crate) fn character() -> &'static [Migration] {
+    &[Migration {
+        from_version: 0,
+        description: "Fill in level and experience",
+        upgrade: |doc| {
+            set_if_missing(doc, "level", DocumentField::IntegerValue("1".to_owned()));
+            set_if_missing(
+                doc,
+                "experience",
+                DocumentField::IntegerValue("0".to_owned()),
+            );
+            Ok(())
+        },
+    }]
+}
+
+pub(crate) fn job() -> &'static [Migration] {
+    &[Migration {
+        from_version: 0,
+        description: "Stamp the schema version, no fields changed",
+        upgrade: |_| Ok(()),
+    }]
+}
+
+fn set_if_missing(doc: &mut Document, field_name: &str, value: DocumentField) {
+    doc.fields.entry(field_name.to_owned()).or_insert(value);
+}
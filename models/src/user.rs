@@ -1,10 +1,16 @@
+use crate::{Character, Job};
 use chrono::{DateTime, TimeZone, Utc};
-use pccg_rs_storage::firestore::{Document, DocumentField};
-use std::{collections::HashMap, convert::TryFrom};
+use pccg_rs_models_derive::FirestoreDocument;
+use pccg_rs_storage::{
+    collection::{Collection, SubCollection, SubCollectionOf},
+    DocumentStore,
+};
 use uuid::Uuid;
 
-#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, FirestoreDocument, PartialEq, serde::Deserialize, serde::Serialize)]
+#[firestore(version = 1, migrations = "crate::migrations::user")]
 pub struct User {
+    #[firestore(id)]
     pub id: Uuid,
     pub currency: u32,
     pub daily_last_claimed: DateTime<Utc>,
@@ -22,61 +28,35 @@ impl User {
     }
 }
 
-impl TryFrom<Document> for User {
-    type Error = &'static str;
-
-    fn try_from(value: Document) -> Result<Self, Self::Error> {
-        let id = value.name.split('/').next_back().unwrap();
-        if let Some(DocumentField::IntegerValue(currency)) = value.fields.get("currency") {
-            if let Ok(currency) = currency.parse() {
-                if let Some(DocumentField::TimestampValue(daily_last_claimed)) =
-                    value.fields.get("daily_last_claimed")
-                {
-                    let staged_card = match value.fields.get("staged_card") {
-                        Some(DocumentField::StringValue(staged_id_str)) => {
-                            Some(Uuid::parse_str(staged_id_str).unwrap())
-                        }
-                        _ => None,
-                    };
-
-                    return Ok(User {
-                        id: Uuid::parse_str(id).unwrap(),
-                        currency,
-                        daily_last_claimed: *daily_last_claimed,
-                        staged_card,
-                    });
-                }
-            }
-        }
+impl SubCollectionOf<User> for Character {
+    const COLLECTION_ID: &'static str = "characters";
+}
 
-        Err("Could not convert Document to User")
-    }
+impl SubCollectionOf<User> for Job {
+    const COLLECTION_ID: &'static str = "jobs";
 }
 
-impl Into<Document> for User {
-    fn into(self) -> Document {
-        let mut fields = HashMap::new();
-        fields.insert(
-            "currency".to_owned(),
-            DocumentField::IntegerValue(self.currency.to_string()),
-        );
-        fields.insert(
-            "daily_last_claimed".to_owned(),
-            DocumentField::TimestampValue(self.daily_last_claimed),
-        );
-        if let Some(staged_card_id) = self.staged_card {
-            fields.insert(
-                "staged_card".to_owned(),
-                DocumentField::StringValue(staged_card_id.to_string()),
-            );
-        }
-        Document::new(fields)
+/// Typed access to the subcollections of each user
+pub trait UserSubCollections<S: DocumentStore> {
+    fn characters(&self, user_id: &Uuid) -> SubCollection<S, User, Character>;
+
+    fn jobs(&self, user_id: &Uuid) -> SubCollection<S, User, Job>;
+}
+
+impl<S: DocumentStore> UserSubCollections<S> for Collection<S, User> {
+    fn characters(&self, user_id: &Uuid) -> SubCollection<S, User, Character> {
+        self.subcollection(user_id)
+    }
+
+    fn jobs(&self, user_id: &Uuid) -> SubCollection<S, User, Job> {
+        self.subcollection(user_id)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use pccg_rs_storage::firestore::Document;
     use std::convert::TryInto;
 
     #[test]
@@ -91,4 +71,15 @@ mod tests {
 
         assert_eq!(user, user_from_doc);
     }
+
+    #[test]
+    fn documents_from_before_schema_versions_are_upgraded() {
+        let id = Uuid::new_v4();
+        let mut doc = Document::new(Default::default());
+        doc.name = format!("parent_path/{}", id);
+
+        let user_from_doc: User = doc.try_into().unwrap();
+
+        assert_eq!(user_from_doc, User::new(id));
+    }
 }
@@ -1,5 +1,6 @@
 use super::schemas;
 use crate::engine;
+use crate::storage::DocumentStore;
 
 use engine::api::AddOrUpdateOperation;
 use engine::{job_board::JobTier, ErrorCategory, ErrorCode};
@@ -8,7 +9,9 @@ use uuid::Uuid;
 use warp::http::StatusCode;
 use warp::{reject, reply, Rejection, Reply};
 
-pub async fn add_user_to_registry(api: Arc<engine::Api>) -> Result<impl Reply, Rejection> {
+pub async fn add_user_to_registry<S: DocumentStore>(
+    api: Arc<engine::Api<S>>,
+) -> Result<impl Reply, Rejection> {
     info!("Handling: add_user_to_registry");
 
     let user_id = uuid::Uuid::new_v4();
@@ -24,9 +27,9 @@ pub async fn add_user_to_registry(api: Arc<engine::Api>) -> Result<impl Reply, R
     }
 }
 
-pub async fn claim_daily_for_user(
+pub async fn claim_daily_for_user<S: DocumentStore>(
     user_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: claim_daily_for_user");
 
@@ -51,9 +54,9 @@ pub async fn claim_daily_for_user(
     }
 }
 
-pub async fn confirm_staged_card(
+pub async fn confirm_staged_card<S: DocumentStore>(
     user_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
     body: schemas::ConfirmStagedCardRequest,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: confirm_staged_card");
@@ -99,9 +102,9 @@ pub async fn confirm_staged_card(
     }
 }
 
-pub async fn delete_user_from_registry(
+pub async fn delete_user_from_registry<S: DocumentStore>(
     user_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: delete_user_from_registry");
 
@@ -120,9 +123,9 @@ pub async fn delete_user_from_registry(
     }
 }
 
-pub async fn draw_card_to_stage_for_user(
+pub async fn draw_card_to_stage_for_user<S: DocumentStore>(
     user_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: draw_card_to_stage_for_user");
 
@@ -135,9 +138,9 @@ pub async fn draw_card_to_stage_for_user(
     }
 }
 
-pub async fn get_card_from_compendium(
+pub async fn get_card_from_compendium<S: DocumentStore>(
     card_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: get_card_from_compendium");
 
@@ -151,10 +154,10 @@ pub async fn get_card_from_compendium(
     }
 }
 
-pub async fn get_character_for_user(
+pub async fn get_character_for_user<S: DocumentStore>(
     user_id: Uuid,
     character_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: get_character_for_user");
 
@@ -168,10 +171,10 @@ pub async fn get_character_for_user(
     }
 }
 
-pub async fn get_job_for_user(
+pub async fn get_job_for_user<S: DocumentStore>(
     user_id: Uuid,
     job_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: get_job_for_user");
 
@@ -185,9 +188,9 @@ pub async fn get_job_for_user(
     }
 }
 
-pub async fn get_staged_card(
+pub async fn get_staged_card<S: DocumentStore>(
     user_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: get_staged_card");
 
@@ -208,9 +211,9 @@ pub async fn get_staged_card(
     }
 }
 
-pub async fn get_user_from_registry(
+pub async fn get_user_from_registry<S: DocumentStore>(
     user_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: get_user_from_registry");
 
@@ -224,9 +227,9 @@ pub async fn get_user_from_registry(
     }
 }
 
-pub async fn list_available_jobs(
+pub async fn list_available_jobs<S: DocumentStore>(
     tier: String,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: list_available_jobs");
 
@@ -250,9 +253,9 @@ pub async fn list_available_jobs(
     }
 }
 
-pub async fn list_characters_for_user(
+pub async fn list_characters_for_user<S: DocumentStore>(
     user_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: list_characters_for_user");
 
@@ -265,7 +268,9 @@ pub async fn list_characters_for_user(
     }
 }
 
-pub async fn list_cards_from_compendium(api: Arc<engine::Api>) -> Result<impl Reply, Rejection> {
+pub async fn list_cards_from_compendium<S: DocumentStore>(
+    api: Arc<engine::Api<S>>,
+) -> Result<impl Reply, Rejection> {
     info!("Handling: list_cards_from_compendium");
 
     match api.list_card_ids().await {
@@ -280,9 +285,30 @@ pub async fn list_cards_from_compendium(api: Arc<engine::Api>) -> Result<impl Re
     }
 }
 
-pub async fn list_jobs_for_user(
+pub async fn list_card_page_from_compendium<S: DocumentStore>(
+    api: Arc<engine::Api<S>>,
+    query: schemas::PageQuery,
+) -> Result<impl Reply, Rejection> {
+    info!("Handling: list_card_page_from_compendium");
+
+    const DEFAULT_PAGE_SIZE: usize = 50;
+    const MAX_PAGE_SIZE: usize = 100;
+    let page_size = query
+        .page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    match api.list_cards(page_size, query.page_token).await {
+        Ok(page) => Ok(reply::with_status(
+            reply::json(&schemas::ListCardPageFromCompendiumResponse::from(page)),
+            StatusCode::OK,
+        )),
+        Err(e) => Err(reject::custom(EngineError::new(e))),
+    }
+}
+
+pub async fn list_jobs_for_user<S: DocumentStore>(
     user_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: list_jobs_for_user");
 
@@ -292,7 +318,9 @@ pub async fn list_jobs_for_user(
     }
 }
 
-pub async fn list_users_from_registry(api: Arc<engine::Api>) -> Result<impl Reply, Rejection> {
+pub async fn list_users_from_registry<S: DocumentStore>(
+    api: Arc<engine::Api<S>>,
+) -> Result<impl Reply, Rejection> {
     info!("Handling: list_users_from_registry");
 
     match api.list_user_ids().await {
@@ -307,9 +335,9 @@ pub async fn list_users_from_registry(api: Arc<engine::Api>) -> Result<impl Repl
     }
 }
 
-pub async fn put_card_to_compendium(
+pub async fn put_card_to_compendium<S: DocumentStore>(
     card_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
     body: schemas::PutCardToCompendiumRequest,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: put_card_to_compendium");
@@ -334,10 +362,10 @@ pub async fn put_card_to_compendium(
     }
 }
 
-pub async fn recall_job_for_user(
+pub async fn recall_job_for_user<S: DocumentStore>(
     user_id: Uuid,
     job_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
     body: schemas::RecallJobRequest,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: recall_job_for_user");
@@ -366,9 +394,9 @@ pub async fn recall_job_for_user(
     }
 }
 
-pub async fn take_job_for_user(
+pub async fn take_job_for_user<S: DocumentStore>(
     user_id: Uuid,
-    api: Arc<engine::Api>,
+    api: Arc<engine::Api<S>>,
     body: schemas::TakeJobRequest,
 ) -> Result<impl Reply, Rejection> {
     info!("Handling: take_job_for_user");
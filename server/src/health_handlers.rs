@@ -1,5 +1,8 @@
+use crate::engine;
+use crate::storage::{retry::RetryStats, DocumentStore};
 use std::convert::Infallible;
 use std::env;
+use std::sync::Arc;
 use warp::Reply;
 
 pub async fn ping() -> Result<impl Reply, Infallible> {
@@ -15,6 +18,17 @@ pub async fn version() -> Result<impl Reply, Infallible> {
     }
 }
 
+pub async fn metrics<S: DocumentStore>(api: Arc<engine::Api<S>>) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&Metrics {
+        storage_retries: api.retry_metrics(),
+    }))
+}
+
+#[derive(serde::Serialize)]
+struct Metrics {
+    storage_retries: RetryStats,
+}
+
 #[derive(serde::Serialize)]
 struct Version {
     commit_hash: String,
@@ -4,6 +4,7 @@ extern crate log;
 mod engine_handlers;
 mod health_handlers;
 mod logging;
+mod migrate;
 mod routes;
 mod schemas;
 
@@ -11,10 +12,15 @@ use pccg_rs_engine as engine;
 use pccg_rs_models as models;
 use pccg_rs_storage as storage;
 
+use models::config::{Config, FirestoreConfig, StorageBackend};
 use std::fs;
 use std::path::PathBuf;
 use std::sync::Arc;
+use storage::credentials::{CredentialProvider, MetadataServer, ServiceAccountKey, StaticToken};
+use storage::file::{FileBackend, FileStore};
 use storage::firestore::{Firestore, FirestoreClient};
+use storage::sqlite::{SqliteBackend, SqliteStore};
+use storage::DocumentStore;
 use tokio::signal;
 
 #[tokio::main]
@@ -22,7 +28,7 @@ async fn main() {
     logging_init();
 
     info!("Parsing config path from argv");
-    let config_path = get_config_path_from_argv().unwrap_or_else(|err_msg| {
+    let (config_path, command) = get_args_from_argv().unwrap_or_else(|err_msg| {
         error!("Problem parsing arguments: {:?}", err_msg);
         std::process::exit(1);
     });
@@ -32,22 +38,114 @@ async fn main() {
         config_path.to_str().unwrap()
     );
     let config_str = fs::read_to_string(config_path).unwrap();
-    let config: models::config::Config = toml::from_str(&config_str).unwrap();
+    let config: Config = toml::from_str(&config_str).unwrap();
     let config = Arc::new(config);
 
-    let firestore = Arc::new(Firestore::new(&config.firestore.secret).await.unwrap());
+    match config.storage.backend {
+        StorageBackend::Filesystem => {
+            let backend = Arc::new(
+                FileBackend::new(&config.compendium.directory)
+                    .with_collection_root("users", &config.user_registry.directory),
+            );
+            run(
+                &config,
+                &command,
+                FileStore::new(Arc::clone(&backend), None, "cards".to_owned()),
+                FileStore::new(Arc::clone(&backend), None, "jobs".to_owned()),
+                FileStore::new(Arc::clone(&backend), None, "users".to_owned()),
+            )
+            .await;
+        }
+        StorageBackend::Firestore => {
+            let firestore_config = config
+                .firestore
+                .as_ref()
+                .expect("Missing [firestore] config section");
+            let firestore = connect_firestore(firestore_config).await;
+            let firestore = Arc::new(firestore.unwrap());
+            run(
+                &config,
+                &command,
+                FirestoreClient::new(Arc::clone(&firestore), None, "cards".to_owned()),
+                FirestoreClient::new(Arc::clone(&firestore), None, "jobs".to_owned()),
+                FirestoreClient::new(Arc::clone(&firestore), None, "users".to_owned()),
+            )
+            .await;
+        }
+        StorageBackend::Sqlite => {
+            let sqlite_config = config
+                .sqlite
+                .as_ref()
+                .expect("Missing [sqlite] config section");
+            let sqlite = Arc::new(SqliteBackend::open(&sqlite_config.path).await.unwrap());
+            run(
+                &config,
+                &command,
+                SqliteStore::new(Arc::clone(&sqlite), None, "cards".to_owned()),
+                SqliteStore::new(Arc::clone(&sqlite), None, "jobs".to_owned()),
+                SqliteStore::new(Arc::clone(&sqlite), None, "users".to_owned()),
+            )
+            .await;
+        }
+    }
+
+    info!("Shutting down");
+}
 
-    let users_firestore = FirestoreClient::new(Arc::clone(&firestore), None, "users".to_owned());
-    let job_board = engine::job_board::JobBoard::new(FirestoreClient::new(
-        Arc::clone(&firestore),
-        None,
-        "jobs".to_owned(),
-    ))
-    .await;
-    let cards_firestore = FirestoreClient::new(Arc::clone(&firestore), None, "cards".to_owned());
+/// Connect to the emulator or to Firestore with the first credentials given in the config
+async fn connect_firestore(config: &FirestoreConfig) -> storage::Result<Firestore> {
+    let options = config.get_http_options();
+    if let Some(ref emulator_host) = config.emulator_host {
+        let project_id = config
+            .project_id
+            .as_ref()
+            .expect("Missing firestore.project_id for emulator");
+        return Firestore::new_for_emulator(emulator_host, project_id, options).await;
+    }
+
+    let credentials: Box<dyn CredentialProvider> = if let Some(ref secret) = config.secret {
+        Box::new(ServiceAccountKey::from_file(secret).await?)
+    } else if let Some(ref secret_env) = config.secret_env {
+        Box::new(ServiceAccountKey::from_env(secret_env)?)
+    } else if let Some(ref token_env) = config.token_env {
+        Box::new(StaticToken::from_env(token_env)?)
+    } else if let Some(ref metadata_server) = config.metadata_server {
+        Box::new(MetadataServer::with_token_uri(metadata_server))
+    } else {
+        panic!("Missing firestore credentials, one of secret, secret_env, token_env or metadata_server");
+    };
+    Firestore::with_credentials(credentials, config.project_id.as_deref(), options).await
+}
+
+/// Run `command` against the given document stores
+async fn run<S: DocumentStore + 'static>(
+    config: &Config,
+    command: &Command,
+    cards: S,
+    jobs: S,
+    users: S,
+) {
+    match command {
+        Command::Serve => serve(config, cards, jobs, users).await,
+        Command::Migrate { dry_run } => {
+            if let Err(e) = migrate::migrate(users, *dry_run).await {
+                error!("Migration failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Run the web server until SIGINT, with the engine backed by the given document stores
+async fn serve<S: DocumentStore + 'static>(config: &Config, cards: S, jobs: S, users: S) {
+    // The job board and the engine share a policy, so their retries are counted together
+    let retry_policy = config.retry.get_retry_policy();
+    let job_board = engine::job_board::JobBoard::new(jobs, retry_policy.clone()).await;
 
     info!("Initialising engine api");
-    let api = engine::Api::new(cards_firestore, job_board, users_firestore).await;
+    let compendium =
+        engine::compendium::Compendium::new(cards, config.compendium.get_cache_refresh_interval());
+    let api = engine::Api::new(compendium, job_board, users, retry_policy).await;
     let api = Arc::new(api);
 
     info!("Starting web server");
@@ -55,8 +153,6 @@ async fn main() {
     let (_, server) = warp::serve(routes)
         .bind_with_graceful_shutdown(config.server.get_socket_addr(), ctrlc_handler());
     server.await;
-
-    info!("Shutting down");
 }
 
 fn logging_init() {
@@ -74,9 +170,32 @@ async fn ctrlc_handler() {
     info!("SIGINT detected");
 }
 
-fn get_config_path_from_argv() -> Result<PathBuf, String> {
+enum Command {
+    Serve,
+    /// Upgrade stored documents to the current schema versions, see `migrate::migrate`
+    Migrate {
+        dry_run: bool,
+    },
+}
+
+/// `pccg-rs <config> [migrate [--dry-run]]`
+fn get_args_from_argv() -> Result<(PathBuf, Command), String> {
     let args: Vec<String> = std::env::args().collect();
-    args.get(1)
+    let config_path = args
+        .get(1)
         .ok_or(String::from("Missing arg"))
-        .map(|p| PathBuf::from(p))
+        .map(|p| PathBuf::from(p))?;
+    let command = match args
+        .iter()
+        .skip(2)
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => Command::Serve,
+        ["migrate"] => Command::Migrate { dry_run: false },
+        ["migrate", "--dry-run"] => Command::Migrate { dry_run: true },
+        other => return Err(format!("Unknown command {:?}", other)),
+    };
+    Ok((config_path, command))
 }
@@ -0,0 +1,47 @@
+use crate::models::{Character, Job, User, UserSubCollections};
+use crate::storage::{
+    self,
+    collection::Collection,
+    migration::{migrate_collection, MigrationReport},
+    DocumentStore,
+};
+
+/// Upgrade every user, character and job document that is behind its model's schema version.
+/// With `dry_run`, only log what would be upgraded.
+pub async fn migrate<S: DocumentStore>(users: S, dry_run: bool) -> storage::Result<()> {
+    let users: Collection<S, User> = Collection::new(users);
+    let report = migrate_collection::<User, _>(users.store(), dry_run).await?;
+    info!("Users: {:?}", report);
+
+    let mut characters_report = MigrationReport::default();
+    let mut jobs_report = MigrationReport::default();
+    for user_id in users.list_ids().await? {
+        characters_report +=
+            migrate_collection::<Character, _>(users.characters(&user_id).store(), dry_run).await?;
+        jobs_report += migrate_collection::<Job, _>(users.jobs(&user_id).store(), dry_run).await?;
+    }
+    info!("Characters: {:?}", characters_report);
+    info!("Jobs: {:?}", jobs_report);
+
+    let mut total = report;
+    total += characters_report;
+    total += jobs_report;
+    if dry_run {
+        info!(
+            "Dry run finished, {} of {} documents would be upgraded and {} cannot be",
+            total.upgraded, total.scanned, total.failed
+        );
+    } else {
+        info!(
+            "Migration finished, upgraded {} of {} documents and failed to upgrade {}",
+            total.upgraded, total.scanned, total.failed
+        );
+    }
+    if total.failed > 0 {
+        return Err(storage::Error::Other(format!(
+            "{} documents could not be upgraded",
+            total.failed
+        )));
+    }
+    Ok(())
+}
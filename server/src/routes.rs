@@ -1,7 +1,9 @@
 use super::engine_handlers;
 use super::health_handlers;
 use super::logging;
+use super::schemas;
 use crate::engine;
+use crate::storage::DocumentStore;
 
 use http::StatusCode;
 use std::convert::Infallible;
@@ -9,8 +11,8 @@ use std::sync::Arc;
 use uuid::Uuid;
 use warp::{Filter, Rejection, Reply};
 
-pub fn build_routes(
-    api: Arc<engine::Api>,
+pub fn build_routes<S: DocumentStore + 'static>(
+    api: Arc<engine::Api<S>>,
 ) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
     let ping = warp::path!("api" / "v0.1" / "ping")
         .and(warp::get())
@@ -20,6 +22,11 @@ pub fn build_routes(
         .and(warp::get())
         .and_then(health_handlers::version);
 
+    let metrics = warp::path!("api" / "v0.1" / "metrics")
+        .and(warp::get())
+        .and(with_engine_api(Arc::clone(&api)))
+        .and_then(health_handlers::metrics);
+
     let list_users_from_registry = warp::path!("api" / "v0.1" / "users")
         .and(warp::get())
         .and(with_engine_api(Arc::clone(&api)))
@@ -60,6 +67,12 @@ pub fn build_routes(
         .and(with_engine_api(Arc::clone(&api)))
         .and_then(engine_handlers::list_cards_from_compendium);
 
+    let list_card_page_from_compendium = warp::path!("api" / "v0.1" / "compendium" / "cards")
+        .and(warp::get())
+        .and(with_engine_api(Arc::clone(&api)))
+        .and(warp::query::<schemas::PageQuery>())
+        .and_then(engine_handlers::list_card_page_from_compendium);
+
     let draw_card_to_stage_for_user = warp::path!("api" / "v0.1" / "users" / Uuid / "draw")
         .and(warp::post())
         .and(with_engine_api(Arc::clone(&api)))
@@ -115,6 +128,8 @@ pub fn build_routes(
         .and_then(engine_handlers::recall_job_for_user);
 
     ping.or(version)
+        .boxed()
+        .or(metrics)
         .boxed()
         .or(list_users_from_registry)
         .boxed()
@@ -134,6 +149,8 @@ pub fn build_routes(
         .boxed()
         .or(list_cards_from_compendium)
         .boxed()
+        .or(list_card_page_from_compendium)
+        .boxed()
         .or(get_card_from_compendium)
         .boxed()
         .or(put_card_to_compendium)
@@ -159,9 +176,9 @@ pub fn build_routes(
         .with(logging::log_incoming_request())
 }
 
-fn with_engine_api(
-    api: Arc<engine::Api>,
-) -> impl Filter<Extract = (Arc<engine::Api>,), Error = Infallible> + Clone {
+fn with_engine_api<S: DocumentStore>(
+    api: Arc<engine::Api<S>>,
+) -> impl Filter<Extract = (Arc<engine::Api<S>>,), Error = Infallible> + Clone {
     warp::any().map(move || Arc::clone(&api))
 }
 
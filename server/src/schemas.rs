@@ -1,4 +1,5 @@
 use crate::models;
+use crate::storage::Page;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -24,6 +25,12 @@ pub enum StagedCardAction {
     Scrap,
 }
 
+#[derive(Deserialize)]
+pub struct PageQuery {
+    pub page_size: Option<usize>,
+    pub page_token: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct PutCardToCompendiumRequest {
     pub card: models::Card,
@@ -74,6 +81,21 @@ pub struct ListCharactersForUserResponse {
 
 pub type ListCardsFromCompendiumResponse = Vec<Uuid>;
 
+#[derive(Serialize)]
+pub struct ListCardPageFromCompendiumResponse {
+    pub cards: Vec<models::Card>,
+    pub next_page_token: Option<String>,
+}
+
+impl From<Page<models::Card>> for ListCardPageFromCompendiumResponse {
+    fn from(page: Page<models::Card>) -> Self {
+        ListCardPageFromCompendiumResponse {
+            cards: page.items,
+            next_page_token: page.next_page_token,
+        }
+    }
+}
+
 pub type ListUsersFromRegistryResponse = Vec<Uuid>;
 
 #[derive(Serialize)]
@@ -0,0 +1,155 @@
+#[macro_use]
+extern crate log;
+
+use pccg_rs_storage as storage;
+
+use std::sync::Arc;
+use storage::credentials::MetadataServer;
+use storage::dump::{self, CollectionTree};
+use storage::file::{FileBackend, FileStore};
+use storage::firestore::{Firestore, FirestoreClient, HttpOptions};
+use storage::sqlite::{SqliteBackend, SqliteStore};
+use storage::DocumentStore;
+use tokio::fs::File;
+use tokio::io::{self, AsyncWrite, BufReader};
+
+const USAGE: &str = "\
+Usage: pccg-rs-dump export <backend> [<file>]
+       pccg-rs-dump import <backend> [<file>]
+
+Exports the cards, job prototypes and users (with their characters and jobs) to newline-delimited
+JSON documents, or upserts the documents in such a dump. The dump is written to stdout or read
+from stdin if no file is given.
+
+Backends:
+    sqlite:<path>
+    file:<directory>[,<collection_id>=<directory>...]
+    firestore:<service account key file>
+    firestore-metadata:<project_id>
+    firestore-emulator:<project_id>@<host>";
+
+#[derive(Clone, Copy)]
+enum Command {
+    Export,
+    Import,
+}
+
+#[tokio::main]
+async fn main() {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (command, backend, path) = match args.as_slice() {
+        [command, backend, rest @ ..] if rest.len() <= 1 => {
+            let command = match command.as_str() {
+                "export" => Command::Export,
+                "import" => Command::Import,
+                _ => exit_with_usage(),
+            };
+            (command, backend.as_str(), rest.first().map(String::as_str))
+        }
+        _ => exit_with_usage(),
+    };
+
+    if let Err(e) = run_with_backend(command, backend, path).await {
+        error!("{:?}", e);
+        std::process::exit(1);
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+/// Connect to the backend described by `spec` and run `command` against it
+async fn run_with_backend(command: Command, spec: &str, path: Option<&str>) -> storage::Result<()> {
+    let (kind, arg) = spec.split_once(':').unwrap_or_else(|| exit_with_usage());
+    match kind {
+        "sqlite" => {
+            let sqlite = Arc::new(SqliteBackend::open(arg).await?);
+            let root = |id| SqliteStore::new(Arc::clone(&sqlite), None, id);
+            run(command, root, path).await
+        }
+        "file" => {
+            let mut dirs = arg.split(',');
+            let mut backend = FileBackend::new(dirs.next().unwrap_or_default());
+            for dir in dirs {
+                let (collection_id, dir) = dir.split_once('=').unwrap_or_else(|| exit_with_usage());
+                backend = backend.with_collection_root(collection_id, dir);
+            }
+            let backend = Arc::new(backend);
+            let root = |id| FileStore::new(Arc::clone(&backend), None, id);
+            run(command, root, path).await
+        }
+        "firestore" | "firestore-metadata" | "firestore-emulator" => {
+            let options = HttpOptions::default();
+            let firestore = match kind {
+                "firestore" => Firestore::new(arg, options).await?,
+                "firestore-metadata" => {
+                    Firestore::with_credentials(Box::new(MetadataServer::new()), Some(arg), options)
+                        .await?
+                }
+                _ => {
+                    let (project_id, host) =
+                        arg.split_once('@').unwrap_or_else(|| exit_with_usage());
+                    Firestore::new_for_emulator(host, project_id, options).await?
+                }
+            };
+            let firestore = Arc::new(firestore);
+            let root = |id| FirestoreClient::new(Arc::clone(&firestore), None, id);
+            run(command, root, path).await
+        }
+        _ => exit_with_usage(),
+    }
+}
+
+/// The collections that hold game data, as laid out by the engine
+fn game_data() -> Vec<CollectionTree> {
+    vec![
+        CollectionTree::new("cards"),
+        CollectionTree::new("jobs")
+            .with_parent_ids(&["beginner", "intermediate", "expert"])
+            .with_subcollection(CollectionTree::new("prototypes")),
+        CollectionTree::new("users")
+            .with_subcollection(CollectionTree::new("characters"))
+            .with_subcollection(CollectionTree::new("jobs")),
+    ]
+}
+
+async fn run<S, F>(command: Command, root: F, path: Option<&str>) -> storage::Result<()>
+where
+    S: DocumentStore,
+    F: Fn(String) -> S,
+{
+    match command {
+        Command::Export => {
+            let mut writer: Box<dyn AsyncWrite + Unpin> = match path {
+                Some(path) => Box::new(File::create(path).await?),
+                None => Box::new(io::stdout()),
+            };
+            for tree in game_data().iter() {
+                let count =
+                    dump::export(root(tree.collection_id().to_owned()), tree, &mut writer).await?;
+                info!("Exported {} documents from {}", count, tree.collection_id());
+            }
+        }
+        Command::Import => {
+            let stats = match path {
+                Some(path) => dump::import(root, BufReader::new(File::open(path).await?)).await?,
+                None => dump::import(root, BufReader::new(io::stdin())).await?,
+            };
+            info!(
+                "Imported {} documents, {} failed",
+                stats.written, stats.failed
+            );
+            if stats.failed > 0 {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,311 @@
+use crate as storage;
+use crate::firestore::Document;
+use crate::query::{FieldMask, StructuredQuery};
+use crate::transform::FieldTransform;
+use crate::{BatchWrite, DocumentStore, Page, Precondition, TransactionType};
+use std::{collections::HashMap, convert::TryFrom, marker::PhantomData, ops::Deref};
+use uuid::Uuid;
+
+/// Declares that documents of type `Self` are kept in a subcollection with id `COLLECTION_ID`
+/// under each document of type `Parent`, e.g. a user's characters.
+pub trait SubCollectionOf<Parent> {
+    const COLLECTION_ID: &'static str;
+}
+
+/// A `DocumentStore` collection whose documents are all of the model type `T`, so that reads
+/// and writes need no type annotations and subcollections are reached through `subcollection`
+/// rather than by name.
+///
+/// Masked reads and queries with a projection usually give documents that do not convert to a
+/// whole `T`, so those methods still take the type to convert to.
+pub struct Collection<S: DocumentStore, T> {
+    store: S,
+    _model: PhantomData<fn() -> T>,
+}
+
+/// A `Collection` of `T` nested under a single document of type `Parent`
+pub struct SubCollection<S: DocumentStore, Parent, T> {
+    collection: Collection<S, T>,
+    _parent: PhantomData<fn() -> Parent>,
+}
+
+impl<S, T> Collection<S, T>
+where
+    S: DocumentStore,
+    T: TryFrom<Document> + Into<Document> + Send,
+{
+    pub fn new(store: S) -> Collection<S, T> {
+        Collection {
+            store,
+            _model: PhantomData,
+        }
+    }
+
+    /// The untyped store behind this collection
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The collection of `C` under the document of this collection with id `id`
+    pub fn subcollection<C>(&self, id: &Uuid) -> SubCollection<S, T, C>
+    where
+        C: SubCollectionOf<T> + TryFrom<Document> + Into<Document> + Send,
+    {
+        SubCollection {
+            collection: Collection::new(
+                self.store
+                    .subcollection(id.to_string(), C::COLLECTION_ID.to_owned()),
+            ),
+            _parent: PhantomData,
+        }
+    }
+
+    pub async fn begin_transaction(
+        &self,
+        transaction_type: TransactionType,
+    ) -> storage::Result<S::Transaction> {
+        self.store.begin_transaction(transaction_type).await
+    }
+
+    pub async fn batch_get(
+        &self,
+        ids: &[Uuid],
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<HashMap<Uuid, Option<T>>> {
+        self.store.batch_get(ids, transaction).await
+    }
+
+    /// See `DocumentStore::batch_write`
+    pub async fn batch_write(
+        &self,
+        writes: Vec<BatchWrite>,
+    ) -> storage::Result<Vec<storage::Result<()>>> {
+        self.store.batch_write(writes).await
+    }
+
+    pub async fn delete(
+        &self,
+        id: &Uuid,
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<()> {
+        self.store.delete::<T>(id, transaction).await
+    }
+
+    pub async fn delete_if(
+        &self,
+        id: &Uuid,
+        precondition: Precondition,
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<()> {
+        self.store.delete_if(id, precondition, transaction).await
+    }
+
+    pub async fn delete_recursive(&self, id: &Uuid) -> storage::Result<usize> {
+        self.store.delete_recursive(id).await
+    }
+
+    pub async fn get(
+        &self,
+        id: &Uuid,
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<Option<T>> {
+        self.store.get(id, transaction).await
+    }
+
+    pub async fn get_masked<R: TryFrom<Document> + Send>(
+        &self,
+        id: &Uuid,
+        mask: &FieldMask,
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<Option<R>> {
+        self.store.get_masked(id, mask, transaction).await
+    }
+
+    pub async fn insert(&self, id: &Uuid, value: T) -> storage::Result<()> {
+        self.store.insert(id, value).await
+    }
+
+    pub async fn list(&self) -> storage::Result<Vec<T>> {
+        self.store.list().await
+    }
+
+    pub async fn list_ids(&self) -> storage::Result<Vec<Uuid>> {
+        self.store.list_ids().await
+    }
+
+    pub async fn list_page(
+        &self,
+        page_size: usize,
+        page_token: Option<String>,
+    ) -> storage::Result<Page<T>> {
+        self.store.list_page(page_size, page_token).await
+    }
+
+    pub async fn run_query(
+        &self,
+        query: StructuredQuery,
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<Vec<T>> {
+        self.store.run_query(query, transaction).await
+    }
+
+    /// Run `query` against the `C` subcollections of every document in this collection. See
+    /// `DocumentStore::run_collection_group_query`.
+    pub async fn run_collection_group_query<C, R>(
+        &self,
+        query: StructuredQuery,
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<Vec<R>>
+    where
+        C: SubCollectionOf<T>,
+        R: TryFrom<Document> + Send,
+    {
+        self.store
+            .run_collection_group_query(C::COLLECTION_ID, query, transaction)
+            .await
+    }
+
+    pub async fn transform(
+        &self,
+        id: &Uuid,
+        transforms: Vec<FieldTransform>,
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<()> {
+        self.store.transform(id, transforms, transaction).await
+    }
+
+    pub async fn transform_if(
+        &self,
+        id: &Uuid,
+        transforms: Vec<FieldTransform>,
+        precondition: Precondition,
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<()> {
+        self.store
+            .transform_if(id, transforms, precondition, transaction)
+            .await
+    }
+
+    pub async fn upsert(
+        &self,
+        id: &Uuid,
+        value: T,
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<()> {
+        self.store.upsert(id, value, transaction).await
+    }
+
+    pub async fn upsert_if(
+        &self,
+        id: &Uuid,
+        value: T,
+        precondition: Precondition,
+        transaction: Option<&S::Transaction>,
+    ) -> storage::Result<()> {
+        self.store
+            .upsert_if(id, value, precondition, transaction)
+            .await
+    }
+}
+
+impl<S: DocumentStore, Parent, T> Deref for SubCollection<S, Parent, T> {
+    type Target = Collection<S, T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.collection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firestore::DocumentField;
+    use crate::memory::{MemoryBackend, MemoryStore};
+    use std::sync::Arc;
+
+    #[derive(Debug, PartialEq)]
+    struct Owner {
+        id: Uuid,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Pet {
+        id: Uuid,
+        name: String,
+    }
+
+    impl SubCollectionOf<Owner> for Pet {
+        const COLLECTION_ID: &'static str = "pets";
+    }
+
+    impl TryFrom<Document> for Owner {
+        type Error = String;
+
+        fn try_from(value: Document) -> Result<Self, Self::Error> {
+            Ok(Owner {
+                id: value.extract_id()?,
+            })
+        }
+    }
+
+    impl From<Owner> for Document {
+        fn from(_: Owner) -> Self {
+            Document::new(HashMap::new())
+        }
+    }
+
+    impl TryFrom<Document> for Pet {
+        type Error = String;
+
+        fn try_from(value: Document) -> Result<Self, Self::Error> {
+            Ok(Pet {
+                id: value.extract_id()?,
+                name: value.extract_string("name")?,
+            })
+        }
+    }
+
+    impl From<Pet> for Document {
+        fn from(value: Pet) -> Self {
+            let mut fields = HashMap::new();
+            fields.insert("name".to_owned(), DocumentField::StringValue(value.name));
+            Document::new(fields)
+        }
+    }
+
+    #[tokio::test]
+    async fn subcollections_are_typed_by_their_parent() {
+        let backend = Arc::new(MemoryBackend::new());
+        let owners: Collection<_, Owner> =
+            Collection::new(MemoryStore::new(backend, None, "owners".to_owned()));
+        let owner_id = Uuid::new_v4();
+        owners
+            .insert(&owner_id, Owner { id: owner_id })
+            .await
+            .unwrap();
+
+        let pets = owners.subcollection::<Pet>(&owner_id);
+        let pet_id = Uuid::new_v4();
+        let name = "rex".to_owned();
+        pets.insert(&pet_id, Pet { id: pet_id, name })
+            .await
+            .unwrap();
+
+        let pet = pets.get(&pet_id, None).await.unwrap().unwrap();
+        assert_eq!(pet.name, "rex");
+        let found: Vec<Pet> = owners
+            .run_collection_group_query::<Pet, Pet>(StructuredQuery::new(), None)
+            .await
+            .unwrap();
+        assert_eq!(found, vec![pet]);
+        assert_eq!(
+            owners
+                .store()
+                .subcollection(owner_id.to_string(), "pets".to_owned())
+                .list_ids()
+                .await
+                .unwrap(),
+            vec![pet_id]
+        );
+    }
+}
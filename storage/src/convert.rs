@@ -0,0 +1,169 @@
+use crate::firestore::{DocumentArrayValue, DocumentField, LatLng};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Conversion from a single Firestore field value, used by derived `TryFrom<Document>` impls
+pub trait FromDocumentField: Sized {
+    fn from_document_field(field: &DocumentField) -> Result<Self, String>;
+
+    /// The value to use when the field is absent from the document, if absence is allowed
+    fn from_missing_field() -> Option<Self> {
+        None
+    }
+}
+
+/// Conversion into a single Firestore field value, used by derived `Into<Document>` impls
+pub trait IntoDocumentField {
+    fn into_document_field(self) -> DocumentField;
+
+    /// Whether the value should be left out of the document entirely rather than stored
+    fn is_absent(&self) -> bool {
+        false
+    }
+}
+
+macro_rules! impl_integer_field {
+    ($($t:ty),*) => {
+        $(
+            impl FromDocumentField for $t {
+                fn from_document_field(field: &DocumentField) -> Result<Self, String> {
+                    field.extract_integer()
+                }
+            }
+
+            impl IntoDocumentField for $t {
+                fn into_document_field(self) -> DocumentField {
+                    DocumentField::IntegerValue(self.to_string())
+                }
+            }
+        )*
+    };
+}
+
+impl_integer_field!(i32, i64, u32, u64);
+
+impl FromDocumentField for bool {
+    fn from_document_field(field: &DocumentField) -> Result<Self, String> {
+        field.extract_boolean()
+    }
+}
+
+impl IntoDocumentField for bool {
+    fn into_document_field(self) -> DocumentField {
+        DocumentField::BooleanValue(self)
+    }
+}
+
+impl FromDocumentField for f64 {
+    fn from_document_field(field: &DocumentField) -> Result<Self, String> {
+        field.extract_double()
+    }
+}
+
+impl IntoDocumentField for f64 {
+    fn into_document_field(self) -> DocumentField {
+        DocumentField::DoubleValue(self)
+    }
+}
+
+impl FromDocumentField for String {
+    fn from_document_field(field: &DocumentField) -> Result<Self, String> {
+        field.extract_string()
+    }
+}
+
+impl IntoDocumentField for String {
+    fn into_document_field(self) -> DocumentField {
+        DocumentField::StringValue(self)
+    }
+}
+
+impl FromDocumentField for DateTime<Utc> {
+    fn from_document_field(field: &DocumentField) -> Result<Self, String> {
+        field.extract_timestamp()
+    }
+}
+
+impl IntoDocumentField for DateTime<Utc> {
+    fn into_document_field(self) -> DocumentField {
+        DocumentField::TimestampValue(self)
+    }
+}
+
+impl FromDocumentField for LatLng {
+    fn from_document_field(field: &DocumentField) -> Result<Self, String> {
+        field.extract_geo_point()
+    }
+}
+
+impl IntoDocumentField for LatLng {
+    fn into_document_field(self) -> DocumentField {
+        DocumentField::GeoPointValue(self)
+    }
+}
+
+impl FromDocumentField for Uuid {
+    fn from_document_field(field: &DocumentField) -> Result<Self, String> {
+        let s = field.extract_string()?;
+        Uuid::parse_str(&s).map_err(|e| format!("Error parsing uuid from '{}': {}", s, e))
+    }
+}
+
+impl IntoDocumentField for Uuid {
+    fn into_document_field(self) -> DocumentField {
+        DocumentField::StringValue(self.to_string())
+    }
+}
+
+impl<T: FromDocumentField> FromDocumentField for Option<T> {
+    fn from_document_field(field: &DocumentField) -> Result<Self, String> {
+        match field {
+            DocumentField::NullValue => Ok(None),
+            field => T::from_document_field(field).map(Some),
+        }
+    }
+
+    fn from_missing_field() -> Option<Self> {
+        Some(None)
+    }
+}
+
+impl<T: IntoDocumentField> IntoDocumentField for Option<T> {
+    fn into_document_field(self) -> DocumentField {
+        match self {
+            Some(value) => value.into_document_field(),
+            None => DocumentField::NullValue,
+        }
+    }
+
+    fn is_absent(&self) -> bool {
+        self.is_none()
+    }
+}
+
+impl<T: FromDocumentField> FromDocumentField for Vec<T> {
+    fn from_document_field(field: &DocumentField) -> Result<Self, String> {
+        if let DocumentField::ArrayValue(dav) = field {
+            // Firestore leaves out the values of an empty array
+            dav.values
+                .iter()
+                .flatten()
+                .map(T::from_document_field)
+                .collect()
+        } else {
+            Err(format!("Error parsing ArrayValue from {:?}", field))
+        }
+    }
+}
+
+impl<T: IntoDocumentField> IntoDocumentField for Vec<T> {
+    fn into_document_field(self) -> DocumentField {
+        DocumentField::ArrayValue(DocumentArrayValue {
+            values: Some(
+                self.into_iter()
+                    .map(IntoDocumentField::into_document_field)
+                    .collect(),
+            ),
+        })
+    }
+}
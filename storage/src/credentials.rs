@@ -0,0 +1,410 @@
+//! Sources of the access tokens sent with every request to Firestore.
+//!
+//! `Firestore` asks its `CredentialProvider` for a token when it connects, and again shortly
+//! before that token expires.
+
+use crate as storage;
+use crate::http_client::{HttpClient, HttpOptions};
+use async_trait::async_trait;
+use chrono::Utc;
+use hyper::{body::Body, header::HeaderName, Method, Request, StatusCode};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, time};
+use tokio::fs;
+
+const OAUTH_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+const OAUTH_SCOPE: &str = "https://www.googleapis.com/auth/datastore";
+
+/// The token endpoint of the metadata server on Google Compute Engine, Cloud Run and GKE
+pub const METADATA_TOKEN_URI: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// The token the Firestore emulator treats as an admin that bypasses security rules
+const EMULATOR_OAUTH_TOKEN: &str = "owner";
+
+pub struct AccessToken {
+    pub token: String,
+    /// How long the token is valid for, or `None` if it never needs renewing
+    pub expires_in: Option<time::Duration>,
+}
+
+#[async_trait]
+pub trait CredentialProvider: Send + Sync {
+    /// Get a new access token. `http_client` is the client used for Firestore requests.
+    async fn get_token(&self, http_client: &HttpClient) -> storage::Result<AccessToken>;
+
+    /// The project the credentials belong to, if they name one
+    fn project_id(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Exchanges a JWT signed with a service account's private key for an OAuth token
+pub struct ServiceAccountKey {
+    key: JsonKey,
+}
+
+impl ServiceAccountKey {
+    /// Read the key from the JSON file downloaded from the Google Cloud console
+    pub async fn from_file<P: Into<PathBuf>>(json_key_path: P) -> storage::Result<Self> {
+        let contents = fs::read_to_string(json_key_path.into()).await?;
+        ServiceAccountKey::from_json(&contents)
+    }
+
+    pub fn from_json(json: &str) -> storage::Result<Self> {
+        Ok(ServiceAccountKey {
+            key: serde_json::from_str(json)?,
+        })
+    }
+
+    /// Read the key from the environment variable `var`, holding either the contents of the JSON
+    /// file or the same base64-encoded
+    pub fn from_env(var: &str) -> storage::Result<Self> {
+        let value = read_env(var)?;
+        let value = value.trim();
+        if value.starts_with('{') {
+            ServiceAccountKey::from_json(value)
+        } else {
+            let decoded = base64::decode(value).map_err(|e| {
+                storage::Error::OAuth(format!("Error decoding base64 key from {}: {}", var, e))
+            })?;
+            Ok(ServiceAccountKey {
+                key: serde_json::from_slice(&decoded)?,
+            })
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for ServiceAccountKey {
+    async fn get_token(&self, http_client: &HttpClient) -> storage::Result<AccessToken> {
+        let jwt = build_jwt(&self.key.client_email, &self.key.private_key).await?;
+        let (token, expires_in) = get_oauth_token(jwt, http_client).await?;
+        Ok(AccessToken {
+            token,
+            expires_in: Some(time::Duration::from_secs(expires_in as u64)),
+        })
+    }
+
+    fn project_id(&self) -> Option<&str> {
+        Some(&self.key.project_id)
+    }
+}
+
+/// A token obtained elsewhere, e.g. by the deployment pipeline, that is used as is and never
+/// renewed
+pub struct StaticToken {
+    token: String,
+}
+
+impl StaticToken {
+    pub fn new(token: String) -> Self {
+        StaticToken { token }
+    }
+
+    pub fn from_env(var: &str) -> storage::Result<Self> {
+        Ok(StaticToken::new(read_env(var)?.trim().to_owned()))
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for StaticToken {
+    async fn get_token(&self, _: &HttpClient) -> storage::Result<AccessToken> {
+        Ok(AccessToken {
+            token: self.token.clone(),
+            expires_in: None,
+        })
+    }
+}
+
+/// Fetches tokens for the service account attached to the machine from a metadata server.
+///
+/// The metadata server is reached over plain HTTP, so this uses a client of its own rather than
+/// the HTTPS-only client for Firestore.
+pub struct MetadataServer {
+    token_uri: String,
+    http_client: HttpClient,
+}
+
+impl MetadataServer {
+    pub fn new() -> Self {
+        MetadataServer::with_token_uri(METADATA_TOKEN_URI)
+    }
+
+    /// Use the metadata server token endpoint at `token_uri`, e.g. a stand-in for testing
+    pub fn with_token_uri(token_uri: &str) -> Self {
+        let options = HttpOptions {
+            request_timeout: time::Duration::from_secs(5),
+            circuit_breaker_threshold: 0,
+            ..HttpOptions::default()
+        };
+        MetadataServer {
+            token_uri: token_uri.to_owned(),
+            http_client: HttpClient::new(&options, false),
+        }
+    }
+}
+
+impl Default for MetadataServer {
+    fn default() -> Self {
+        MetadataServer::new()
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for MetadataServer {
+    async fn get_token(&self, _: &HttpClient) -> storage::Result<AccessToken> {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(&self.token_uri)
+            .header(HeaderName::from_static("metadata-flavor"), "Google")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body_bytes) = self.http_client.send(request).await?;
+
+        match status {
+            StatusCode::OK => {
+                let body: OAuth2Response = serde_json::from_slice(&body_bytes)?;
+                debug!(
+                    "Obtained OAuth token from metadata server {}",
+                    self.token_uri
+                );
+                Ok(AccessToken {
+                    token: body.access_token,
+                    expires_in: Some(time::Duration::from_secs(body.expires_in as u64)),
+                })
+            }
+            _ => Err(storage::Error::OAuth(format!(
+                "Metadata server returned HTTP {} with body content: {}",
+                status,
+                String::from_utf8_lossy(&body_bytes)
+            ))),
+        }
+    }
+}
+
+/// For the Firestore emulator, which does not check credentials. Sends the `owner` token, which
+/// the emulator treats as an admin that bypasses security rules.
+pub struct NoAuth;
+
+#[async_trait]
+impl CredentialProvider for NoAuth {
+    async fn get_token(&self, _: &HttpClient) -> storage::Result<AccessToken> {
+        Ok(AccessToken {
+            token: EMULATOR_OAUTH_TOKEN.to_owned(),
+            expires_in: None,
+        })
+    }
+}
+
+fn read_env(var: &str) -> storage::Result<String> {
+    std::env::var(var).map_err(|e| storage::Error::OAuth(format!("Error reading {}: {}", var, e)))
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonKey {
+    r#type: String,
+    project_id: String,
+    private_key_id: String,
+    private_key: String,
+    client_email: String,
+    auth_uri: String,
+    token_uri: String,
+    auth_provider_x509_cert_url: String,
+    client_x509_cert_url: String,
+}
+
+/// OpenID Connect claims data structure
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    /// Email address of the service account
+    iss: String,
+    /// Space-delimited list of the permissions requested
+    scope: String,
+    /// Intended target of assertion, should just be https://oauth2.googleapis.com/token
+    aud: String,
+    /// Expiration time of the assertion, as seconds since epoch. Maximum of 1 hour after issuance
+    exp: usize,
+    /// Assertion issuance time, as seconds since epoch
+    iat: usize,
+}
+
+async fn build_jwt(email: &str, private_key: &str) -> storage::Result<String> {
+    let now = Utc::now().timestamp() as usize;
+    let claims = Claims {
+        iss: email.to_string(),
+        scope: OAUTH_SCOPE.to_owned(),
+        aud: OAUTH_TOKEN_URI.to_owned(),
+        exp: now + 3600,
+        iat: now,
+    };
+
+    let token = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(private_key.as_ref())?,
+    )?;
+    Ok(token)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OAuth2Request {
+    grant_type: String,
+    assertion: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OAuth2Response {
+    access_token: String,
+    expires_in: usize,
+    token_type: String,
+}
+
+async fn get_oauth_token(
+    jwt: String,
+    http_client: &HttpClient,
+) -> storage::Result<(String, usize)> {
+    let sw = time::Instant::now();
+
+    let request_body = OAuth2Request {
+        grant_type: "urn:ietf:params:oauth:grant-type:jwt-bearer".to_owned(),
+        assertion: jwt,
+    };
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(OAUTH_TOKEN_URI)
+        .body(Body::from(serde_json::to_string_pretty(&request_body)?))
+        .unwrap();
+    let (status, body_bytes) = http_client.send(request).await?;
+
+    match status {
+        StatusCode::OK => {
+            let body: OAuth2Response = serde_json::from_slice(&body_bytes)?;
+            debug!("Response: {} {:?}", status, body);
+            info!("Obtained OAuth token, took {:?}", sw.elapsed());
+            Ok((body.access_token, body.expires_in))
+        }
+        _ => {
+            let body_str = String::from_utf8(body_bytes.to_vec())
+                .unwrap_or_else(|_| "<mangled body>".to_owned());
+            info!("Failed to obtain OAuth token, took {:?}", sw.elapsed());
+            Err(storage::Error::OAuth(format!(
+                "OAuth flow returned HTTP {} with body content: {}",
+                status, body_str
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn logging_init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    static FAKE_JSON_KEY_PATH: &str = "fake_service_account.json";
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn can_read_key_from_json() {
+        logging_init();
+
+        let key = ServiceAccountKey::from_file(FAKE_JSON_KEY_PATH)
+            .await
+            .unwrap();
+        assert_eq!(key.project_id(), Some("pccg-rs"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn can_read_key_from_env() {
+        logging_init();
+
+        let json = std::fs::read_to_string(FAKE_JSON_KEY_PATH).unwrap();
+        std::env::set_var("PCCG_RS_TEST_KEY_JSON", &json);
+        std::env::set_var("PCCG_RS_TEST_KEY_BASE64", base64::encode(&json));
+
+        for var in &["PCCG_RS_TEST_KEY_JSON", "PCCG_RS_TEST_KEY_BASE64"] {
+            let key = ServiceAccountKey::from_env(var).unwrap();
+            assert_eq!(key.project_id(), Some("pccg-rs"));
+        }
+        assert!(ServiceAccountKey::from_env("PCCG_RS_TEST_KEY_UNSET").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn can_build_jwt() {
+        logging_init();
+
+        let key = ServiceAccountKey::from_file(FAKE_JSON_KEY_PATH)
+            .await
+            .unwrap()
+            .key;
+        build_jwt(&key.client_email, &key.private_key)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn static_and_emulator_tokens_never_expire() {
+        let client = HttpClient::new(&HttpOptions::default(), true);
+
+        let token = StaticToken::new("abc".to_owned())
+            .get_token(&client)
+            .await
+            .unwrap();
+        assert_eq!(token.token, "abc");
+        assert!(token.expires_in.is_none());
+
+        let token = NoAuth.get_token(&client).await.unwrap();
+        assert_eq!(token.token, "owner");
+        assert!(token.expires_in.is_none());
+    }
+
+    #[tokio::test]
+    async fn can_get_token_from_metadata_server() {
+        // A stand-in metadata server that only answers requests with the metadata flavor header
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = vec![0; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                let response = if request.contains("metadata-flavor: google") {
+                    let body = r#"{"access_token":"from-metadata","expires_in":3599,"token_type":"Bearer"}"#;
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                } else {
+                    "HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\n\r\n".to_owned()
+                };
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let provider = MetadataServer::with_token_uri(&format!("http://{}/token", addr));
+        let client = HttpClient::new(&HttpOptions::default(), true);
+        let token = provider.get_token(&client).await.unwrap();
+        assert_eq!(token.token, "from-metadata");
+        assert_eq!(token.expires_in, Some(time::Duration::from_secs(3599)));
+    }
+
+    #[cfg(feature = "test_requires_secrets")]
+    static JSON_KEY_PATH: &str = "../secrets/service_account.json";
+
+    #[cfg(feature = "test_requires_secrets")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn can_get_oauth_token() {
+        logging_init();
+
+        let key = ServiceAccountKey::from_file(JSON_KEY_PATH).await.unwrap();
+        let client = HttpClient::new(&HttpOptions::default(), true);
+
+        key.get_token(&client).await.unwrap();
+    }
+}
@@ -0,0 +1,783 @@
+use crate as storage;
+use crate::firestore::{Document, DocumentArrayValue, DocumentField, DocumentMapValue};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{
+    de::{
+        self,
+        value::{MapAccessDeserializer, MapDeserializer, SeqDeserializer},
+        DeserializeOwned, IntoDeserializer, Visitor,
+    },
+    ser::{self, Error as _, Impossible},
+    Deserializer, Serialize, Serializer,
+};
+use std::collections::HashMap;
+
+type Error = serde_json::Error;
+
+/// Newtype struct name that marks a value serialised by [`timestamp`], so that it is stored as a
+/// `TimestampValue` rather than a string
+const TIMESTAMP_TOKEN: &str = "$__pccg_rs_storage_timestamp";
+
+/// Serialise any serde type with map or struct shape into the fields of a `Document`.
+///
+/// Integers become `IntegerValue`s, floats `DoubleValue`s, byte buffers (as from `serde_bytes`)
+/// `BytesValue`s, and `None` and unit values become `NullValue`s. Timestamps are only stored as
+/// `TimestampValue`s when serialised through [`timestamp`], and are otherwise stored as RFC 3339
+/// strings. References and geo points are read as strings and `latitude`/`longitude` maps, and
+/// are written back in those forms. The document name is left empty.
+pub fn to_document<T: Serialize + ?Sized>(value: &T) -> storage::Result<Document> {
+    match value.serialize(FieldSerializer)? {
+        DocumentField::MapValue(dmv) => Ok(Document::new(dmv.fields.unwrap_or_default())),
+        field => Err(Error::custom(format!(
+            "Expected a map or struct to serialise to a Document, found {:?}",
+            field
+        ))
+        .into()),
+    }
+}
+
+/// Deserialise any serde type from the fields of a `Document`, ignoring its name
+pub fn from_document<T: DeserializeOwned>(document: Document) -> storage::Result<T> {
+    from_document_field(DocumentField::MapValue(DocumentMapValue {
+        fields: Some(document.fields),
+    }))
+}
+
+/// Serialise any serde type into a single `DocumentField`
+pub fn to_document_field<T: Serialize + ?Sized>(value: &T) -> storage::Result<DocumentField> {
+    Ok(value.serialize(FieldSerializer)?)
+}
+
+/// Deserialise any serde type from a single `DocumentField`
+pub fn from_document_field<T: DeserializeOwned>(field: DocumentField) -> storage::Result<T> {
+    Ok(T::deserialize(field)?)
+}
+
+/// For use with `#[serde(with = "pccg_rs_storage::firestore::timestamp")]`, to store a
+/// `DateTime<Utc>` as a `TimestampValue`. Other serde formats see an RFC 3339 string.
+pub mod timestamp {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        value: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(
+            super::TIMESTAMP_TOKEN,
+            &value.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        DateTime::<Utc>::deserialize(deserializer)
+    }
+}
+
+fn map_value(fields: HashMap<String, DocumentField>) -> DocumentField {
+    DocumentField::MapValue(DocumentMapValue {
+        fields: Some(fields),
+    })
+}
+
+fn array_value(values: Vec<DocumentField>) -> DocumentField {
+    DocumentField::ArrayValue(DocumentArrayValue {
+        values: Some(values),
+    })
+}
+
+/// Wraps `value` in a single-entry map keyed by the variant name, as serde_json does
+fn variant_value(variant: &str, value: DocumentField) -> DocumentField {
+    let mut fields = HashMap::new();
+    fields.insert(variant.to_owned(), value);
+    map_value(fields)
+}
+
+struct FieldSerializer;
+
+impl Serializer for FieldSerializer {
+    type Ok = DocumentField;
+    type Error = Error;
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeArray;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeMap;
+
+    fn serialize_bool(self, v: bool) -> Result<DocumentField, Error> {
+        Ok(DocumentField::BooleanValue(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<DocumentField, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<DocumentField, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<DocumentField, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<DocumentField, Error> {
+        Ok(DocumentField::IntegerValue(v.to_string()))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<DocumentField, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<DocumentField, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<DocumentField, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<DocumentField, Error> {
+        // Firestore integers are signed 64-bit
+        if v > i64::MAX as u64 {
+            Err(Error::custom(format!(
+                "Integer {} is too large to store in a Document",
+                v
+            )))
+        } else {
+            self.serialize_i64(v as i64)
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<DocumentField, Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<DocumentField, Error> {
+        Ok(DocumentField::DoubleValue(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<DocumentField, Error> {
+        Ok(DocumentField::StringValue(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<DocumentField, Error> {
+        Ok(DocumentField::StringValue(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<DocumentField, Error> {
+        Ok(DocumentField::BytesValue(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<DocumentField, Error> {
+        Ok(DocumentField::NullValue)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<DocumentField, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<DocumentField, Error> {
+        Ok(DocumentField::NullValue)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<DocumentField, Error> {
+        Ok(DocumentField::NullValue)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<DocumentField, Error> {
+        Ok(DocumentField::StringValue(variant.to_owned()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<DocumentField, Error> {
+        if name == TIMESTAMP_TOKEN {
+            let s = value
+                .serialize(self)?
+                .extract_string()
+                .map_err(Error::custom)?;
+            let dt = DateTime::parse_from_rfc3339(&s).map_err(Error::custom)?;
+            Ok(DocumentField::TimestampValue(dt.with_timezone(&Utc)))
+        } else {
+            value.serialize(self)
+        }
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<DocumentField, Error> {
+        Ok(variant_value(variant, value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray {
+            variant: None,
+            values: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray {
+            variant: Some(variant),
+            values: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeMap, Error> {
+        Ok(SerializeMap {
+            variant: None,
+            fields: HashMap::new(),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeMap, Error> {
+        Ok(SerializeMap {
+            variant: Some(variant),
+            fields: HashMap::new(),
+            next_key: None,
+        })
+    }
+}
+
+struct SerializeArray {
+    variant: Option<&'static str>,
+    values: Vec<DocumentField>,
+}
+
+impl SerializeArray {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.values.push(value.serialize(FieldSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<DocumentField, Error> {
+        let array = array_value(self.values);
+        Ok(match self.variant {
+            Some(variant) => variant_value(variant, array),
+            None => array,
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = DocumentField;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<DocumentField, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = DocumentField;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<DocumentField, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = DocumentField;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<DocumentField, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeArray {
+    type Ok = DocumentField;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<DocumentField, Error> {
+        self.finish()
+    }
+}
+
+struct SerializeMap {
+    variant: Option<&'static str>,
+    fields: HashMap<String, DocumentField>,
+    next_key: Option<String>,
+}
+
+impl SerializeMap {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<(), Error> {
+        self.fields.insert(key, value.serialize(FieldSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<DocumentField, Error> {
+        let map = map_value(self.fields);
+        Ok(match self.variant {
+            Some(variant) => variant_value(variant, map),
+            None => map,
+        })
+    }
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = DocumentField;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.next_key = Some(key.serialize(KeySerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| Error::custom("serialize_value called before serialize_key"))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<DocumentField, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = DocumentField;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.to_owned(), value)
+    }
+
+    fn end(self) -> Result<DocumentField, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeMap {
+    type Ok = DocumentField;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.to_owned(), value)
+    }
+
+    fn end(self) -> Result<DocumentField, Error> {
+        self.finish()
+    }
+}
+
+/// Map keys must be strings in Firestore. Like serde_json, integer and unit variant keys are
+/// converted to strings, and anything else is rejected.
+struct KeySerializer;
+
+fn key_error() -> Error {
+    Error::custom("Document map keys must be strings")
+}
+
+impl Serializer for KeySerializer {
+    type Ok = String;
+    type Error = Error;
+    type SerializeSeq = Impossible<String, Error>;
+    type SerializeTuple = Impossible<String, Error>;
+    type SerializeTupleStruct = Impossible<String, Error>;
+    type SerializeTupleVariant = Impossible<String, Error>;
+    type SerializeMap = Impossible<String, Error>;
+    type SerializeStruct = Impossible<String, Error>;
+    type SerializeStructVariant = Impossible<String, Error>;
+
+    fn serialize_bool(self, _v: bool) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_char(self, v: char) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<String, Error> {
+        Ok(v.to_owned())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_none(self) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_unit(self) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<String, Error> {
+        Ok(variant.to_owned())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<String, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Err(key_error())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(key_error())
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for DocumentField {
+    type Deserializer = DocumentField;
+
+    fn into_deserializer(self) -> DocumentField {
+        self
+    }
+}
+
+impl<'de> Deserializer<'de> for DocumentField {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            DocumentField::NullValue => visitor.visit_unit(),
+            DocumentField::ArrayValue(dav) => {
+                let mut seq = SeqDeserializer::new(dav.values.unwrap_or_default().into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            DocumentField::BooleanValue(b) => visitor.visit_bool(b),
+            DocumentField::BytesValue(bytes) => visitor.visit_byte_buf(bytes),
+            DocumentField::DoubleValue(f) => visitor.visit_f64(f),
+            DocumentField::GeoPointValue(lat_lng) => {
+                let mut map = MapDeserializer::new(
+                    vec![
+                        ("latitude", DocumentField::DoubleValue(lat_lng.latitude)),
+                        ("longitude", DocumentField::DoubleValue(lat_lng.longitude)),
+                    ]
+                    .into_iter(),
+                );
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+            DocumentField::IntegerValue(s) => match s.parse::<i64>() {
+                Ok(i) => visitor.visit_i64(i),
+                Err(e) => Err(de::Error::custom(format!(
+                    "Error parsing IntegerValue '{}': {}",
+                    s, e
+                ))),
+            },
+            DocumentField::MapValue(dmv) => {
+                let mut map = MapDeserializer::new(dmv.fields.unwrap_or_default().into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+            DocumentField::ReferenceValue(s) | DocumentField::StringValue(s) => {
+                visitor.visit_string(s)
+            }
+            DocumentField::TimestampValue(dt) => {
+                visitor.visit_string(dt.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            DocumentField::NullValue => visitor.visit_none(),
+            field => visitor.visit_some(field),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self {
+            DocumentField::StringValue(variant) => visitor.visit_enum(variant.into_deserializer()),
+            DocumentField::MapValue(dmv) => {
+                let fields = dmv.fields.unwrap_or_default();
+                if fields.len() != 1 {
+                    return Err(de::Error::custom(
+                        "Expected a map with a single key to deserialise an enum",
+                    ));
+                }
+                visitor.visit_enum(MapAccessDeserializer::new(MapDeserializer::new(
+                    fields.into_iter(),
+                )))
+            }
+            field => Err(de::Error::custom(format!(
+                "Expected a StringValue or MapValue to deserialise an enum, found {:?}",
+                field
+            ))),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect { w: u32, h: u32 },
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Everything {
+        count: u32,
+        ratio: f64,
+        name: String,
+        nickname: Option<String>,
+        tags: Vec<String>,
+        scores: HashMap<String, i64>,
+        shapes: Vec<Shape>,
+        #[serde(with = "timestamp")]
+        created: DateTime<Utc>,
+    }
+
+    #[test]
+    fn can_round_trip_serde_types() {
+        let mut scores = HashMap::new();
+        scores.insert("a".to_owned(), -3);
+        let value = Everything {
+            count: 7,
+            ratio: 2.0,
+            name: "name".to_owned(),
+            nickname: None,
+            tags: vec!["x".to_owned(), "y".to_owned()],
+            scores,
+            shapes: vec![Shape::Point, Shape::Circle(1.5), Shape::Rect { w: 2, h: 3 }],
+            created: "2021-02-03T04:05:06.123456Z".parse().unwrap(),
+        };
+
+        let doc = to_document(&value).unwrap();
+        assert!(matches!(
+            doc.fields.get("count"),
+            Some(DocumentField::IntegerValue(s)) if s == "7"
+        ));
+        assert!(matches!(
+            doc.fields.get("nickname"),
+            Some(DocumentField::NullValue)
+        ));
+        assert_eq!(doc.extract_timestamp("created"), Ok(value.created));
+
+        let from_doc: Everything = from_document(doc).unwrap();
+        assert_eq!(value, from_doc);
+    }
+
+    #[test]
+    fn integral_doubles_stored_as_integers_are_read_as_doubles() {
+        let mut fields = HashMap::new();
+        fields.insert(
+            "physical".to_owned(),
+            DocumentField::IntegerValue("2".to_owned()),
+        );
+        let doc = Document::new(fields);
+
+        #[derive(Deserialize)]
+        struct Stats {
+            physical: f64,
+        }
+        let stats: Stats = from_document(doc).unwrap();
+        assert_eq!(stats.physical, 2.0);
+    }
+
+    #[test]
+    fn rejects_values_that_are_not_maps() {
+        assert!(to_document(&5).is_err());
+        assert!(to_document(&vec![1, 2]).is_err());
+    }
+}
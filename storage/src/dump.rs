@@ -0,0 +1,329 @@
+use crate as storage;
+use crate::firestore::Document;
+use crate::{BatchWrite, DocumentStore};
+use std::collections::BTreeSet;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+/// Documents listed per request while exporting
+const EXPORT_PAGE_SIZE: usize = 300;
+
+/// Documents of a single collection written per `batch_write` while importing
+const IMPORT_BATCH_SIZE: usize = 500;
+
+/// A collection to export, and the subcollections to export under each of its documents
+#[derive(Clone, Debug)]
+pub struct CollectionTree {
+    collection_id: String,
+    subcollections: Vec<CollectionTree>,
+    parent_ids: Vec<String>,
+}
+
+/// Counts of the documents read from a dump by `import`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImportStats {
+    pub written: usize,
+    /// Documents whose writes failed on their own. Each failure is logged.
+    pub failed: usize,
+}
+
+impl CollectionTree {
+    pub fn new(collection_id: &str) -> CollectionTree {
+        CollectionTree {
+            collection_id: collection_id.to_owned(),
+            subcollections: vec![],
+            parent_ids: vec![],
+        }
+    }
+
+    /// Also export `subcollection` under each document of this collection
+    pub fn with_subcollection(mut self, subcollection: CollectionTree) -> CollectionTree {
+        self.subcollections.push(subcollection);
+        self
+    }
+
+    /// Look for subcollections under the documents with these ids even if the documents
+    /// themselves do not exist, as with `jobs/{tier}/prototypes`. Firestore does not list
+    /// documents that only hold subcollections.
+    pub fn with_parent_ids(mut self, ids: &[&str]) -> CollectionTree {
+        self.parent_ids
+            .extend(ids.iter().map(|id| (*id).to_owned()));
+        self
+    }
+
+    pub fn collection_id(&self) -> &str {
+        &self.collection_id
+    }
+}
+
+/// Write every document in `tree`, where `store` is the collection at the root of the tree, to
+/// `writer` as one JSON `Document` per line. Returns the number of documents written.
+///
+/// Document names are written relative to the database root, e.g. `users/{user_id}`, whichever
+/// backend they were read from. Create and update times are not kept.
+pub async fn export<S, W>(store: S, tree: &CollectionTree, writer: &mut W) -> storage::Result<usize>
+where
+    S: DocumentStore,
+    W: AsyncWrite + Unpin,
+{
+    let mut count = 0;
+    let mut pending = vec![(store, tree)];
+    while let Some((store, tree)) = pending.pop() {
+        let mut parent_ids: BTreeSet<String> = tree.parent_ids.iter().cloned().collect();
+        let mut page_token = None;
+        loop {
+            let page = store
+                .list_page::<Document>(EXPORT_PAGE_SIZE, page_token)
+                .await?;
+            for mut doc in page.items.into_iter() {
+                doc.name = relative_name(&doc.name).to_owned();
+                if let Some(id) = doc.name.rsplit('/').next() {
+                    parent_ids.insert(id.to_owned());
+                }
+                let mut line = serde_json::to_vec(&doc)?;
+                line.push(b'\n');
+                writer.write_all(&line).await?;
+                count += 1;
+            }
+            page_token = match page.next_page_token {
+                Some(token) => Some(token),
+                None => break,
+            };
+        }
+
+        for id in parent_ids.iter() {
+            for subtree in tree.subcollections.iter() {
+                let subcollection = store.subcollection(id.clone(), subtree.collection_id.clone());
+                pending.push((subcollection, subtree));
+            }
+        }
+    }
+    writer.flush().await?;
+    Ok(count)
+}
+
+/// Upsert every document in a dump written by `export`, overwriting documents that already
+/// exist. `root` gives the top-level collection with the given id.
+///
+/// Fails on the first line that is not a document with a uuid id in a valid collection path,
+/// leaving the documents before it written. Writes that fail on their own are counted in the
+/// returned stats instead.
+pub async fn import<S, F, R>(root: F, reader: R) -> storage::Result<ImportStats>
+where
+    S: DocumentStore,
+    F: Fn(String) -> S,
+    R: AsyncBufRead + Unpin,
+{
+    let mut stats = ImportStats::default();
+    let mut lines = reader.lines();
+    let mut line_number = 0;
+    let mut batch: Vec<BatchWrite> = vec![];
+    let mut batch_names: Vec<String> = vec![];
+    let mut batch_collection = String::new();
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let doc: Document = serde_json::from_str(&line).map_err(|e| {
+            storage::Error::Other(format!("Invalid document on line {}: {}", line_number, e))
+        })?;
+        let (collection_path, id) = split_name(&doc.name).map_err(|e| {
+            storage::Error::Other(format!("Invalid document on line {}: {}", line_number, e))
+        })?;
+
+        if collection_path != batch_collection || batch.len() >= IMPORT_BATCH_SIZE {
+            write_batch(&root, &batch_collection, batch, batch_names, &mut stats).await?;
+            batch = vec![];
+            batch_names = vec![];
+            batch_collection = collection_path.to_owned();
+        }
+        batch_names.push(doc.name.clone());
+        batch.push(BatchWrite::upsert(id, doc));
+    }
+    write_batch(&root, &batch_collection, batch, batch_names, &mut stats).await?;
+    Ok(stats)
+}
+
+async fn write_batch<S, F>(
+    root: &F,
+    collection_path: &str,
+    batch: Vec<BatchWrite>,
+    names: Vec<String>,
+    stats: &mut ImportStats,
+) -> storage::Result<()>
+where
+    S: DocumentStore,
+    F: Fn(String) -> S,
+{
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let store = collection(root, collection_path);
+    let results = store.batch_write(batch).await?;
+    for (name, result) in names.iter().zip(results) {
+        match result {
+            Ok(()) => stats.written += 1,
+            Err(e) => {
+                warn!("Failed to import {}: {:?}", name, e);
+                stats.failed += 1;
+            }
+        }
+    }
+    info!(
+        "Imported {} documents so far, {} failed",
+        stats.written, stats.failed
+    );
+    Ok(())
+}
+
+/// The store for `collection_path`, a path of alternating collection and document ids
+fn collection<S, F>(root: &F, collection_path: &str) -> S
+where
+    S: DocumentStore,
+    F: Fn(String) -> S,
+{
+    let mut segments = collection_path.split('/');
+    let mut store = root(segments.next().unwrap_or_default().to_owned());
+    while let (Some(parent_id), Some(collection_id)) = (segments.next(), segments.next()) {
+        store = store.subcollection(parent_id.to_owned(), collection_id.to_owned());
+    }
+    store
+}
+
+/// The path of a document relative to the database root. Firestore names documents by their
+/// full resource name, `projects/{project_id}/databases/(default)/documents/{path}`.
+fn relative_name(name: &str) -> &str {
+    const DOCUMENTS: &str = "/documents/";
+    match name.find(DOCUMENTS) {
+        Some(idx) => &name[idx + DOCUMENTS.len()..],
+        None => name,
+    }
+}
+
+/// Split a relative document name into its collection path and id
+fn split_name(name: &str) -> Result<(&str, Uuid), String> {
+    let segments = name.split('/').count();
+    if segments < 2 || !segments.is_multiple_of(2) || name.split('/').any(str::is_empty) {
+        return Err(format!("'{}' is not a document path", name));
+    }
+    let idx = name.rfind('/').unwrap();
+    let id = Uuid::parse_str(&name[idx + 1..])
+        .map_err(|e| format!("Document '{}' does not have a uuid id: {}", name, e))?;
+    Ok((&name[..idx], id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firestore::DocumentField;
+    use crate::memory::{MemoryBackend, MemoryStore};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn document(value: i64) -> Document {
+        let mut fields = HashMap::new();
+        fields.insert(
+            "value".to_owned(),
+            DocumentField::IntegerValue(value.to_string()),
+        );
+        Document::new(fields)
+    }
+
+    async fn dump(backend: &Arc<MemoryBackend>, trees: &[CollectionTree]) -> Vec<u8> {
+        let mut out = vec![];
+        for tree in trees.iter() {
+            let store =
+                MemoryStore::new(Arc::clone(backend), None, tree.collection_id().to_owned());
+            export(store, tree, &mut out).await.unwrap();
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn exported_trees_can_be_imported() {
+        let backend = Arc::new(MemoryBackend::new());
+        let users = MemoryStore::new(Arc::clone(&backend), None, "users".to_owned());
+        let jobs = MemoryStore::new(Arc::clone(&backend), None, "jobs".to_owned());
+        let user_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for (i, id) in user_ids.iter().enumerate() {
+            users.insert(id, document(i as i64)).await.unwrap();
+            users
+                .subcollection(id.to_string(), "characters".to_owned())
+                .insert(&Uuid::new_v4(), document(10))
+                .await
+                .unwrap();
+        }
+        let prototype_id = Uuid::new_v4();
+        jobs.subcollection("expert".to_owned(), "prototypes".to_owned())
+            .insert(&prototype_id, document(20))
+            .await
+            .unwrap();
+        // Not part of the tree
+        users
+            .subcollection(user_ids[0].to_string(), "other".to_owned())
+            .insert(&Uuid::new_v4(), document(30))
+            .await
+            .unwrap();
+
+        let trees = vec![
+            CollectionTree::new("users").with_subcollection(CollectionTree::new("characters")),
+            CollectionTree::new("jobs")
+                .with_parent_ids(&["beginner", "expert"])
+                .with_subcollection(CollectionTree::new("prototypes")),
+        ];
+        let out = dump(&backend, &trees).await;
+        assert_eq!(out.iter().filter(|b| **b == b'\n').count(), 5);
+
+        let restored = Arc::new(MemoryBackend::new());
+        let stats = import(
+            |id| MemoryStore::new(Arc::clone(&restored), None, id),
+            &out[..],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            stats,
+            ImportStats {
+                written: 5,
+                failed: 0
+            }
+        );
+        assert_eq!(dump(&restored, &trees).await, out);
+
+        let prototypes = MemoryStore::new(Arc::clone(&restored), None, "jobs".to_owned())
+            .subcollection("expert".to_owned(), "prototypes".to_owned());
+        let doc = prototypes
+            .get::<Document>(&prototype_id, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc.extract_integer::<i64>("value"), Ok(20));
+    }
+
+    #[tokio::test]
+    async fn import_rejects_invalid_lines() {
+        let backend = Arc::new(MemoryBackend::new());
+        let root = |id| MemoryStore::new(Arc::clone(&backend), None, id);
+        let id = Uuid::new_v4();
+        let dump = format!(
+            "{{\"name\": \"cards/{}\", \"fields\": {{}}}}\n\n{{\"name\": \"cards\"}}\n",
+            id
+        );
+        match import(root, dump.as_bytes()).await {
+            Err(storage::Error::Other(message)) => assert!(message.contains("line 3")),
+            other => panic!("Expected an invalid line, got {:?}", other),
+        }
+        let dump = "{\"name\": \"jobs/expert\", \"fields\": {}}\n";
+        assert!(import(root, dump.as_bytes()).await.is_err());
+    }
+
+    #[test]
+    fn names_are_made_relative_to_the_database_root() {
+        assert_eq!(
+            relative_name("projects/p/databases/(default)/documents/users/a/jobs/b"),
+            "users/a/jobs/b"
+        );
+        assert_eq!(relative_name("users/a"), "users/a");
+    }
+}
@@ -1,4 +1,4 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::error;
 use std::fmt::{self, Display};
 use std::result;
@@ -14,7 +14,131 @@ pub enum Error {
     OAuth(String),
     Other(String),
     Serialization(serde_json::error::Error),
+    Service(ServiceError),
+    Sqlite(rusqlite::Error),
     Transaction(String),
+    /// The backend has not responded in time
+    Timeout(String),
+    /// The request was not sent, as the backend has recently been failing
+    Unavailable(String),
+}
+
+impl Error {
+    /// Whether the operation that failed with this error may succeed if it is tried again
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            Error::Service(ref e) => e.is_retryable(),
+            // Transport failures, e.g. a dropped connection or a truncated response body
+            Error::Hyper(_) | Error::Timeout(_) | Error::Transaction(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// An error response from a remote storage service such as Firestore
+#[derive(Debug)]
+pub struct ServiceError {
+    pub code: ServiceErrorCode,
+    pub http_status: u16,
+    pub message: String,
+}
+
+impl ServiceError {
+    pub fn new(code: ServiceErrorCode, http_status: u16, message: String) -> ServiceError {
+        ServiceError {
+            code,
+            http_status,
+            message,
+        }
+    }
+
+    /// Transient failures, which Google recommends retrying with backoff
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.code,
+            ServiceErrorCode::Aborted
+                | ServiceErrorCode::DeadlineExceeded
+                | ServiceErrorCode::Internal
+                | ServiceErrorCode::ResourceExhausted
+                | ServiceErrorCode::Unavailable
+        )
+    }
+}
+
+impl Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} (HTTP {}): {}",
+            self.code, self.http_status, self.message
+        )
+    }
+}
+
+/// Canonical error codes from https://firebase.google.com/docs/firestore/use-rest-api#error_codes
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ServiceErrorCode {
+    Aborted,
+    AlreadyExists,
+    Cancelled,
+    DataLoss,
+    DeadlineExceeded,
+    FailedPrecondition,
+    Internal,
+    InvalidArgument,
+    NotFound,
+    OutOfRange,
+    PermissionDenied,
+    ResourceExhausted,
+    Unauthenticated,
+    Unavailable,
+    Unimplemented,
+    #[serde(other)]
+    Unknown,
+}
+
+impl ServiceErrorCode {
+    /// The code usually sent with an HTTP status, for responses that do not carry one
+    pub fn from_http_status(http_status: u16) -> ServiceErrorCode {
+        match http_status {
+            400 => ServiceErrorCode::InvalidArgument,
+            401 => ServiceErrorCode::Unauthenticated,
+            403 => ServiceErrorCode::PermissionDenied,
+            404 => ServiceErrorCode::NotFound,
+            409 => ServiceErrorCode::Aborted,
+            429 => ServiceErrorCode::ResourceExhausted,
+            499 => ServiceErrorCode::Cancelled,
+            500 => ServiceErrorCode::Internal,
+            501 => ServiceErrorCode::Unimplemented,
+            503 => ServiceErrorCode::Unavailable,
+            504 => ServiceErrorCode::DeadlineExceeded,
+            _ => ServiceErrorCode::Unknown,
+        }
+    }
+
+    /// The code for a numeric `google.rpc.Code`, as found in the per-write statuses of a batch
+    /// write. 0 is `OK`, which is not an error and so maps to `Unknown`.
+    pub fn from_rpc_code(code: i32) -> ServiceErrorCode {
+        match code {
+            1 => ServiceErrorCode::Cancelled,
+            3 => ServiceErrorCode::InvalidArgument,
+            4 => ServiceErrorCode::DeadlineExceeded,
+            5 => ServiceErrorCode::NotFound,
+            6 => ServiceErrorCode::AlreadyExists,
+            7 => ServiceErrorCode::PermissionDenied,
+            8 => ServiceErrorCode::ResourceExhausted,
+            9 => ServiceErrorCode::FailedPrecondition,
+            10 => ServiceErrorCode::Aborted,
+            11 => ServiceErrorCode::OutOfRange,
+            12 => ServiceErrorCode::Unimplemented,
+            13 => ServiceErrorCode::Internal,
+            14 => ServiceErrorCode::Unavailable,
+            15 => ServiceErrorCode::DataLoss,
+            16 => ServiceErrorCode::Unauthenticated,
+            _ => ServiceErrorCode::Unknown,
+        }
+    }
 }
 
 impl Display for Error {
@@ -27,7 +151,11 @@ impl Display for Error {
             Error::OAuth(ref e) => Display::fmt(e, f),
             Error::Other(ref e) => Display::fmt(e, f),
             Error::Serialization(ref e) => Display::fmt(e, f),
+            Error::Service(ref e) => Display::fmt(e, f),
+            Error::Sqlite(ref e) => Display::fmt(e, f),
             Error::Transaction(ref e) => Display::fmt(e, f),
+            Error::Timeout(ref e) => Display::fmt(e, f),
+            Error::Unavailable(ref e) => Display::fmt(e, f),
         }
     }
 }
@@ -42,7 +170,11 @@ impl error::Error for Error {
             Error::OAuth(_) => None,
             Error::Other(_) => None,
             Error::Serialization(ref e) => Some(e),
+            Error::Service(_) => None,
+            Error::Sqlite(ref e) => Some(e),
             Error::Transaction(_) => None,
+            Error::Timeout(_) => None,
+            Error::Unavailable(_) => None,
         }
     }
 }
@@ -97,6 +229,26 @@ impl Serialize for Error {
                 variant = "Transaction";
                 value = e.to_string();
             }
+            Error::Sqlite(ref e) => {
+                variant_index = 8;
+                variant = "Sqlite";
+                value = format!("{:?}", e);
+            }
+            Error::Service(ref e) => {
+                variant_index = 9;
+                variant = "Service";
+                value = e.to_string();
+            }
+            Error::Timeout(ref e) => {
+                variant_index = 10;
+                variant = "Timeout";
+                value = e.to_string();
+            }
+            Error::Unavailable(ref e) => {
+                variant_index = 11;
+                variant = "Unavailable";
+                value = e.to_string();
+            }
         };
         serializer.serialize_newtype_variant(name, variant_index, variant, &value)
     }
@@ -120,6 +272,12 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Error::Sqlite(value)
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
         Error::Io(value)
@@ -0,0 +1,455 @@
+use crate as storage;
+use crate::firestore::{Document, DocumentField};
+use crate::local::{self, run_blocking, LocalBackend, LocalReader, LocalStore, LocalWrite};
+use async_trait::async_trait;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// A `DocumentStore` that keeps each document as a JSON file on local disk
+pub type FileStore = LocalStore<FileBackend>;
+
+const LOCK_FILE_NAME: &str = ".lock";
+const DOCUMENT_EXTENSION: &str = "json";
+
+/// Each document is a JSON file named after its id, inside a directory for its collection.
+/// Subcollections are nested directories named after their parent document, e.g.
+/// `users/{user_id}/characters/{character_id}` is stored at
+/// `{users root}/{user_id}/characters/{character_id}.json`.
+///
+/// Top-level collections live in `root/{collection_id}` unless given their own directory with
+/// `with_collection_root`.
+///
+/// Commits hold an exclusive lock on `root/.lock` and replace each file with an atomic rename, and
+/// reads hold a shared lock, so readers never see a partially applied commit. Commits are not
+/// journaled though, so a crash part way through a commit of several documents can leave only
+/// some of them written.
+///
+/// There is no point-in-time snapshot of the directory tree, so read-only transactions are
+/// rejected. Read-write transactions still detect conflicting commits.
+#[derive(Clone)]
+pub struct FileBackend {
+    root: PathBuf,
+    collection_roots: HashMap<String, PathBuf>,
+}
+
+/// The on-disk format of a document. Unlike `Document`, this keeps the create and update times.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredDocument {
+    name: String,
+    fields: HashMap<String, DocumentField>,
+    create_time: String,
+    update_time: String,
+}
+
+impl FileBackend {
+    pub fn new<P: Into<PathBuf>>(root: P) -> FileBackend {
+        FileBackend {
+            root: root.into(),
+            collection_roots: HashMap::new(),
+        }
+    }
+
+    /// Store the documents of the top-level collection `collection_id` directly in `directory`
+    pub fn with_collection_root<P: Into<PathBuf>>(
+        mut self,
+        collection_id: &str,
+        directory: P,
+    ) -> FileBackend {
+        self.collection_roots
+            .insert(collection_id.to_owned(), directory.into());
+        self
+    }
+
+    fn collection_dir(&self, collection_path: &str) -> PathBuf {
+        let mut segments = collection_path.split('/');
+        let first = segments.next().unwrap_or_default();
+        let mut path = match self.collection_roots.get(first) {
+            Some(dir) => dir.clone(),
+            None => self.root.join(first),
+        };
+        for segment in segments {
+            path.push(segment);
+        }
+        path
+    }
+
+    fn document_path(&self, name: &str) -> PathBuf {
+        let (collection_path, id) = match name.rfind('/') {
+            Some(idx) => (&name[..idx], &name[idx + 1..]),
+            None => ("", name),
+        };
+        self.collection_dir(collection_path)
+            .join(format!("{}.{}", id, DOCUMENT_EXTENSION))
+    }
+
+    fn lock(&self, exclusive: bool) -> storage::Result<File> {
+        fs::create_dir_all(&self.root)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.root.join(LOCK_FILE_NAME))?;
+        if exclusive {
+            file.lock_exclusive()?;
+        } else {
+            file.lock_shared()?;
+        }
+        Ok(file)
+    }
+
+    fn read_blocking(&self, name: &str) -> storage::Result<Option<Document>> {
+        let _lock = self.lock(false)?;
+        read_document(&self.document_path(name))
+    }
+
+    fn read_collection_blocking(&self, collection_path: &str) -> storage::Result<Vec<Document>> {
+        let _lock = self.lock(false)?;
+        read_documents_in(&self.collection_dir(collection_path))
+    }
+
+    fn read_collection_group_blocking(
+        &self,
+        parent_path: &str,
+        collection_id: &str,
+    ) -> storage::Result<Vec<Document>> {
+        let _lock = self.lock(false)?;
+        let mut dirs = vec![];
+        if parent_path.is_empty() {
+            dirs.push(self.collection_dir(collection_id));
+            find_collection_dirs(&self.root, collection_id, &mut dirs)?;
+            for dir in self.collection_roots.values() {
+                find_collection_dirs(dir, collection_id, &mut dirs)?;
+            }
+        } else {
+            // The subcollections of a document live in a directory at the same path as it
+            find_collection_dirs(&self.collection_dir(parent_path), collection_id, &mut dirs)?;
+        }
+        dirs.sort();
+        dirs.dedup();
+
+        let mut ret = vec![];
+        for dir in dirs.iter() {
+            for doc in read_documents_in(dir)?.into_iter() {
+                if local::in_collection_group(&doc.name, parent_path, collection_id) {
+                    ret.push(doc);
+                }
+            }
+        }
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(ret)
+    }
+
+    fn read_descendants_blocking(&self, name: &str) -> storage::Result<Vec<Document>> {
+        let _lock = self.lock(false)?;
+        // The subcollections of a document live in a directory at the same path as it
+        let mut ret = vec![];
+        read_documents_below(&self.collection_dir(name), &mut ret)?;
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(ret)
+    }
+
+    fn commit_blocking(
+        &self,
+        expected_versions: HashMap<String, Option<String>>,
+        writes: Vec<LocalWrite>,
+    ) -> storage::Result<()> {
+        let _lock = self.lock(true)?;
+        local::check_versions(&expected_versions, |name| {
+            Ok(read_document(&self.document_path(name))?.map(|doc| doc.update_time))
+        })?;
+
+        // Work out every change before touching any file, so that a failed write leaves the
+        // directory untouched
+        let commit_time = local::next_commit_time();
+        let mut changes: Vec<(String, Option<Document>)> = vec![];
+        for write in writes.into_iter() {
+            let name = write.name().to_owned();
+            let existing = match changes.iter().rev().find(|(n, _)| *n == name) {
+                Some((_, change)) => change.clone(),
+                None => read_document(&self.document_path(&name))?,
+            };
+            let change = write.apply(existing.as_ref(), &commit_time)?;
+            changes.push((name, change));
+        }
+
+        for (name, change) in changes.into_iter() {
+            let path = self.document_path(&name);
+            match change {
+                Some(doc) => write_document(&path, doc)?,
+                None => match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LocalReader for FileBackend {
+    async fn read(&self, name: &str) -> storage::Result<Option<Document>> {
+        let backend = self.clone();
+        let name = name.to_owned();
+        run_blocking(move || backend.read_blocking(&name)).await
+    }
+
+    async fn read_collection(&self, collection_path: &str) -> storage::Result<Vec<Document>> {
+        let backend = self.clone();
+        let collection_path = collection_path.to_owned();
+        run_blocking(move || backend.read_collection_blocking(&collection_path)).await
+    }
+
+    async fn read_collection_group(
+        &self,
+        parent_path: &str,
+        collection_id: &str,
+    ) -> storage::Result<Vec<Document>> {
+        let backend = self.clone();
+        let parent_path = parent_path.to_owned();
+        let collection_id = collection_id.to_owned();
+        run_blocking(move || backend.read_collection_group_blocking(&parent_path, &collection_id))
+            .await
+    }
+
+    async fn read_descendants(&self, name: &str) -> storage::Result<Vec<Document>> {
+        let backend = self.clone();
+        let name = name.to_owned();
+        run_blocking(move || backend.read_descendants_blocking(&name)).await
+    }
+}
+
+#[async_trait]
+impl LocalBackend for FileBackend {
+    type Snapshot = FileBackend;
+
+    async fn snapshot(&self) -> storage::Result<Self::Snapshot> {
+        Err(storage::Error::Other(
+            "The file backend does not support read-only transactions".to_owned(),
+        ))
+    }
+
+    async fn commit(
+        &self,
+        expected_versions: HashMap<String, Option<String>>,
+        writes: Vec<LocalWrite>,
+    ) -> storage::Result<()> {
+        let backend = self.clone();
+        run_blocking(move || backend.commit_blocking(expected_versions, writes)).await
+    }
+}
+
+/// Read every document directly inside `dir`, ordered by name
+fn read_documents_in(dir: &Path) -> storage::Result<Vec<Document>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut ret = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let path = entry.path();
+        // Skip subcollection directories, the lock file and in-flight temporary files
+        let is_document = path.is_file()
+            && !file_name.starts_with('.')
+            && file_name.ends_with(&format!(".{}", DOCUMENT_EXTENSION));
+        if is_document {
+            if let Some(doc) = read_document(&path)? {
+                ret.push(doc);
+            }
+        }
+    }
+    ret.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(ret)
+}
+
+/// Read every document in `dir` and in the directories below it
+fn read_documents_below(dir: &Path, found: &mut Vec<Document>) -> storage::Result<()> {
+    found.extend(read_documents_in(dir)?);
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_documents_below(&path, found)?;
+        }
+    }
+    Ok(())
+}
+
+/// Collect every directory named `collection_id` anywhere below `dir`
+fn find_collection_dirs(
+    dir: &Path,
+    collection_id: &str,
+    found: &mut Vec<PathBuf>,
+) -> storage::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            if path.file_name() == Some(std::ffi::OsStr::new(collection_id)) {
+                found.push(path.clone());
+            }
+            find_collection_dirs(&path, collection_id, found)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_document(path: &Path) -> storage::Result<Option<Document>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let stored: StoredDocument = serde_json::from_slice(&bytes)?;
+    Ok(Some(Document {
+        name: stored.name,
+        fields: stored.fields,
+        create_time: stored.create_time,
+        update_time: stored.update_time,
+    }))
+}
+
+/// Write the document to a temporary file next to `path`, then rename it into place
+fn write_document(path: &Path, doc: Document) -> storage::Result<()> {
+    let dir = path.parent().ok_or_else(|| {
+        storage::Error::Other(format!("Invalid document path {}", path.display()))
+    })?;
+    fs::create_dir_all(dir)?;
+    let stored = StoredDocument {
+        name: doc.name,
+        fields: doc.fields,
+        create_time: doc.create_time,
+        update_time: doc.update_time,
+    };
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = dir.join(format!(".{}.tmp", file_name));
+    let mut tmp_file = File::create(&tmp_path)?;
+    tmp_file.write_all(&serde_json::to_vec_pretty(&stored)?)?;
+    tmp_file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local::tests::{local_backend_tests, Counter};
+    use crate::query::StructuredQuery;
+    use crate::{DocumentStore, TransactionType};
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn file_backend(dir: &tempfile::TempDir) -> Arc<FileBackend> {
+        Arc::new(
+            FileBackend::new(dir.path().join("compendium"))
+                .with_collection_root("users", dir.path().join("user_registry")),
+        )
+    }
+
+    async fn backend() -> (Arc<FileBackend>, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        (file_backend(&dir), dir)
+    }
+
+    // Read-only transactions are not supported
+    local_backend_tests!(
+        backend;
+        can_upsert_then_get,
+        insert_conflicts_with_existing_document,
+        list_only_includes_direct_children,
+        can_list_in_pages,
+        can_query_collection_group,
+        can_delete_recursively,
+        masked_reads_only_return_masked_fields,
+        concurrent_read_write_transactions_conflict,
+        concurrent_transforms_do_not_conflict,
+        writes_check_preconditions,
+        batch_writes_report_each_outcome,
+        transactions_read_their_own_writes,
+    );
+
+    #[tokio::test]
+    async fn read_only_transactions_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(file_backend(&dir), None, "counters".to_owned());
+        match store.begin_transaction(TransactionType::ReadOnly).await {
+            Err(storage::Error::Other(_)) => (),
+            Err(e) => panic!("Expected an unsupported transaction, got {:?}", e),
+            Ok(_) => panic!("Began a read-only transaction"),
+        }
+    }
+
+    #[tokio::test]
+    async fn documents_are_stored_as_nested_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = file_backend(&dir);
+        let counters = FileStore::new(Arc::clone(&backend), None, "counters".to_owned());
+        let users = FileStore::new(Arc::clone(&backend), None, "users".to_owned());
+        let id = Uuid::new_v4();
+        counters
+            .insert(&id, Counter { id, count: 1 })
+            .await
+            .unwrap();
+        users.insert(&id, Counter { id, count: 2 }).await.unwrap();
+        let sub_store = users.subcollection(id.to_string(), "counters".to_owned());
+        sub_store
+            .insert(&id, Counter { id, count: 3 })
+            .await
+            .unwrap();
+
+        let compendium = dir.path().join("compendium");
+        let user_registry = dir.path().join("user_registry");
+        assert!(compendium.join(format!("counters/{}.json", id)).is_file());
+        assert!(user_registry.join(format!("{}.json", id)).is_file());
+        assert!(user_registry
+            .join(format!("{}/counters/{}.json", id, id))
+            .is_file());
+
+        // A fresh backend over the same directories sees the same documents
+        let users = FileStore::new(file_backend(&dir), None, "users".to_owned());
+        assert_eq!(
+            users.list::<Counter>().await.unwrap(),
+            vec![Counter { id, count: 2 }]
+        );
+        let sub_store = users.subcollection(id.to_string(), "counters".to_owned());
+        assert_eq!(
+            sub_store.get::<Counter>(&id, None).await.unwrap(),
+            Some(Counter { id, count: 3 })
+        );
+
+        // Collection groups are found across all the collection roots
+        let group = users
+            .run_collection_group_query::<Counter>("counters", StructuredQuery::new(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            group,
+            vec![Counter { id, count: 1 }, Counter { id, count: 3 }]
+        );
+
+        sub_store.delete::<Counter>(&id, None).await.unwrap();
+        assert!(!user_registry
+            .join(format!("{}/counters/{}.json", id, id))
+            .exists());
+    }
+}
@@ -1,14 +1,25 @@
 use crate as storage;
-use chrono::{DateTime, SubsecRound, Utc};
-use hyper::{
-    body::{self, Body},
-    client::{Client, HttpConnector},
-    header::HeaderName,
-    Method, Request, StatusCode,
+use crate::credentials::{CredentialProvider, NoAuth, ServiceAccountKey};
+pub use crate::document_serde::{
+    from_document, from_document_field, timestamp, to_document, to_document_field,
+};
+use crate::http_client::HttpClient;
+pub use crate::http_client::HttpOptions;
+use crate::query::DOCUMENT_NAME_FIELD;
+pub use crate::query::{Direction, FieldMask, Filter, StructuredQuery};
+use crate::store::{check_batch, convert_document};
+pub use crate::transform::FieldTransform;
+pub use crate::TransactionType;
+use crate::{
+    BatchWrite, BatchWriteOperation, DocumentStore, Page, Precondition, ServiceError,
+    ServiceErrorCode, StoreTransaction,
 };
-use hyper_tls::HttpsConnector;
-use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use async_trait::async_trait;
+use chrono::{DateTime, SubsecRound, Utc};
+use futures::{stream, Stream, TryStreamExt};
+use hyper::{body::Body, header::HeaderName, Method, Request, StatusCode};
 use num::{Float, Integer};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
 use std::{
     any::type_name,
@@ -20,7 +31,6 @@ use std::{
     time,
 };
 use tokio::{
-    fs,
     sync::mpsc,
     sync::oneshot::{self, error::TryRecvError},
     sync::RwLock,
@@ -28,6 +38,16 @@ use tokio::{
 };
 use uuid::Uuid;
 
+const FIRESTORE_BASE_URL: &str = "https://firestore.googleapis.com/v1";
+/// How long before a token expires to renew it
+const TOKEN_REFRESH_MARGIN: time::Duration = time::Duration::from_secs(600);
+const TOKEN_RETRY_DELAY: time::Duration = time::Duration::from_secs(10);
+/// How many times, and how soon, to retry rolling back an aborted or dropped transaction
+const ABORT_RETRIES: usize = 4;
+const ABORT_RETRY_BASE_DELAY: time::Duration = time::Duration::from_millis(200);
+/// The most writes Firestore accepts in a single commit
+const MAX_WRITES_PER_COMMIT: usize = 500;
+
 pub struct FirestoreClient {
     firestore: Arc<Firestore>,
     parent_path: String,
@@ -75,32 +95,48 @@ impl FirestoreClient {
         }
     }
 
-    pub async fn begin_transaction(
+    pub async fn commit_transaction(&self, transaction: Transaction) -> storage::Result<()> {
+        self.firestore.commit(transaction).await
+    }
+
+    pub async fn rollback_transaction(&self, transaction: Transaction) -> storage::Result<()> {
+        self.firestore.rollback(transaction).await
+    }
+
+    /// Stream every document in this collection, fetching a page at a time as the stream is
+    /// polled. A document that fails to convert gives an error item without ending the stream.
+    pub fn list_stream<T: TryFrom<Document>>(
         &self,
-        transaction_type: TransactionType,
-    ) -> storage::Result<Transaction> {
-        let database = format!(
-            "projects/{}/databases/(default)/documents",
-            self.firestore.firebase_project_id,
-        );
-        let transaction_opts = match transaction_type {
-            TransactionType::ReadOnly => TransactionOptions::ReadOnly(ReadOnlyTransactionOptions {
-                read_time: Utc::now().trunc_subsecs(6),
-            }),
-            TransactionType::ReadWrite => {
-                TransactionOptions::ReadWrite(ReadWriteTransactionOptions {
-                    retry_transaction: None, // TODO figure out what this actually does
-                })
+    ) -> impl Stream<Item = storage::Result<T>> + Send + 'static {
+        const PAGE_SIZE: usize = 100;
+        let firestore = Arc::clone(&self.firestore);
+        let parent_path = self.parent_path.clone();
+        let collection_id = self.collection_id.clone();
+        // None once the last page has been fetched
+        let first_page: Option<Option<String>> = Some(None);
+        stream::try_unfold(first_page, move |page_token| {
+            let firestore = Arc::clone(&firestore);
+            let parent_path = parent_path.clone();
+            let collection_id = collection_id.clone();
+            async move {
+                let page_token = match page_token {
+                    Some(page_token) => page_token,
+                    None => return Ok::<_, storage::Error>(None),
+                };
+                let (docs, next_page_token) = firestore
+                    .list_page(&parent_path, &collection_id, PAGE_SIZE, page_token, None)
+                    .await?;
+                let items = stream::iter(docs.into_iter().map(convert_document));
+                Ok(Some((items, next_page_token.map(Some))))
             }
-        };
-        self.firestore
-            .begin_transaction(&database, transaction_opts)
-            .await
+        })
+        .try_flatten()
     }
 
-    pub async fn batch_get<T: TryFrom<Document>>(
+    async fn batch_get_internal<T: TryFrom<Document>>(
         &self,
-        ids: &Vec<Uuid>,
+        ids: &[Uuid],
+        mask: Option<&FieldMask>,
         transaction: Option<&Transaction>,
     ) -> storage::Result<HashMap<Uuid, Option<T>>> {
         let mut id_to_name_map = HashMap::new();
@@ -124,6 +160,7 @@ impl FirestoreClient {
             .batch_get(
                 &database,
                 id_to_name_map.values().cloned().collect(),
+                mask,
                 transaction,
             )
             .await
@@ -152,11 +189,105 @@ impl FirestoreClient {
         }
     }
 
-    pub async fn commit_transaction(&self, transaction: Transaction) -> storage::Result<()> {
-        self.firestore.commit(transaction).await
+    fn document_name(&self, id: &Uuid) -> String {
+        format!("{}/{}/{}", self.parent_path, self.collection_id, id)
+    }
+
+    fn to_write(&self, write: BatchWrite) -> Write {
+        let name = self.document_name(&write.id);
+        let current_document = write.precondition;
+        match write.operation {
+            BatchWriteOperation::Upsert(mut doc) => {
+                doc.name = name;
+                Write::Update {
+                    update: doc,
+                    current_document,
+                }
+            }
+            BatchWriteOperation::Transform(transforms) => {
+                let mut doc = Document::new(HashMap::new());
+                doc.name = name;
+                Write::Transform {
+                    update: doc,
+                    update_mask: FieldMask::new(&[]),
+                    update_transforms: transforms,
+                    current_document,
+                }
+            }
+            BatchWriteOperation::Delete => Write::Delete {
+                delete: name,
+                current_document,
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl DocumentStore for FirestoreClient {
+    type Transaction = Transaction;
+
+    fn subcollection(&self, subcollection_relative_path: String, subcollection_id: String) -> Self {
+        FirestoreClient::new_for_subcollection(self, subcollection_relative_path, subcollection_id)
+    }
+
+    async fn begin_transaction(
+        &self,
+        transaction_type: TransactionType,
+    ) -> storage::Result<Self::Transaction> {
+        let database = format!(
+            "projects/{}/databases/(default)/documents",
+            self.firestore.firebase_project_id,
+        );
+        let transaction_opts = match transaction_type {
+            TransactionType::ReadOnly => TransactionOptions::ReadOnly(ReadOnlyTransactionOptions {
+                read_time: Utc::now().trunc_subsecs(6),
+            }),
+            TransactionType::ReadWrite => {
+                TransactionOptions::ReadWrite(ReadWriteTransactionOptions {
+                    retry_transaction: None, // TODO figure out what this actually does
+                })
+            }
+        };
+        self.firestore
+            .begin_transaction(&database, transaction_opts)
+            .await
+    }
+
+    async fn batch_write(
+        &self,
+        writes: Vec<BatchWrite>,
+    ) -> storage::Result<Vec<storage::Result<()>>> {
+        check_batch(&writes)?;
+        let mut writes = writes.into_iter().map(|w| self.to_write(w));
+        let mut results = vec![];
+        loop {
+            let chunk: Vec<Write> = writes.by_ref().take(MAX_WRITES_PER_COMMIT).collect();
+            if chunk.is_empty() {
+                break;
+            }
+            results.extend(self.firestore.batch_write(chunk).await?);
+        }
+        Ok(results)
+    }
+
+    async fn batch_get<T: TryFrom<Document> + Send>(
+        &self,
+        ids: &[Uuid],
+        transaction: Option<&Transaction>,
+    ) -> storage::Result<HashMap<Uuid, Option<T>>> {
+        self.batch_get_internal(ids, None, transaction).await
     }
 
-    pub async fn delete<T: TryFrom<Document>>(
+    async fn batch_get_masked<T: TryFrom<Document> + Send>(
+        &self,
+        ids: &[Uuid],
+        mask: &FieldMask,
+        transaction: Option<&Transaction>,
+    ) -> storage::Result<HashMap<Uuid, Option<T>>> {
+        self.batch_get_internal(ids, Some(mask), transaction).await
+    }
+
+    async fn delete<T: TryFrom<Document>>(
         &self,
         id: &Uuid,
         transaction: Option<&Transaction>,
@@ -169,7 +300,10 @@ impl FirestoreClient {
         );
         match transaction {
             Some(t) => {
-                let write = Write::Delete { delete: name };
+                let write = Write::Delete {
+                    delete: name,
+                    current_document: None,
+                };
                 match t.append_write(write).await {
                     Ok(_) => Ok(()),
                     Err(e) => Err(e.into()),
@@ -179,21 +313,73 @@ impl FirestoreClient {
         }
     }
 
-    pub async fn get<T: TryFrom<Document>>(
+    async fn delete_recursive(&self, id: &Uuid) -> storage::Result<usize> {
+        let name = self.document_name(id);
+        let query = StructuredQuery::new()
+            .select(&[DOCUMENT_NAME_FIELD])
+            .for_all_descendants();
+        let descendants = self
+            .firestore
+            .run_query::<Document>(&name, query, None)
+            .await?;
+        let deleted = descendants.len();
+
+        let mut names: Vec<String> = descendants.into_iter().map(|doc| doc.name).collect();
+        names.push(name);
+        for chunk in names.chunks(MAX_WRITES_PER_COMMIT) {
+            let writes = chunk
+                .iter()
+                .map(|name| Write::Delete {
+                    delete: name.clone(),
+                    current_document: None,
+                })
+                .collect();
+            self.firestore.commit_writes(writes).await?;
+        }
+        Ok(deleted)
+    }
+
+    async fn delete_if(
+        &self,
+        id: &Uuid,
+        precondition: Precondition,
+        transaction: Option<&Transaction>,
+    ) -> storage::Result<()> {
+        let write = Write::Delete {
+            delete: self.document_name(id),
+            current_document: Some(precondition),
+        };
+        match transaction {
+            Some(t) => match t.append_write(write).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            },
+            None => self.firestore.commit_writes(vec![write]).await,
+        }
+    }
+
+    async fn get<T: TryFrom<Document> + Send>(
         &self,
         id: &Uuid,
         transaction: Option<&Transaction>,
     ) -> storage::Result<Option<T>> {
-        let name = format!(
-            "{}/{}/{}",
-            self.parent_path,
-            self.collection_id,
-            id.to_string()
-        );
-        self.firestore.get::<T>(&name, transaction).await
+        self.firestore
+            .get::<T>(&self.document_name(id), None, transaction)
+            .await
     }
 
-    pub async fn insert<T: Into<Document>>(&self, id: &Uuid, value: T) -> storage::Result<()> {
+    async fn get_masked<T: TryFrom<Document> + Send>(
+        &self,
+        id: &Uuid,
+        mask: &FieldMask,
+        transaction: Option<&Transaction>,
+    ) -> storage::Result<Option<T>> {
+        self.firestore
+            .get::<T>(&self.document_name(id), Some(mask), transaction)
+            .await
+    }
+
+    async fn insert<T: Into<Document> + Send>(&self, id: &Uuid, value: T) -> storage::Result<()> {
         self.firestore
             .create_document(
                 &self.parent_path,
@@ -204,13 +390,125 @@ impl FirestoreClient {
             .await
     }
 
-    pub async fn list<T: TryFrom<Document>>(&self) -> storage::Result<Vec<T>> {
+    async fn list<T: TryFrom<Document> + Send>(&self) -> storage::Result<Vec<T>> {
+        self.firestore
+            .list::<T>(&self.parent_path, &self.collection_id, None)
+            .await
+    }
+
+    async fn list_masked<T: TryFrom<Document> + Send>(
+        &self,
+        mask: &FieldMask,
+    ) -> storage::Result<Vec<T>> {
+        self.firestore
+            .list::<T>(&self.parent_path, &self.collection_id, Some(mask))
+            .await
+    }
+
+    async fn list_page<T: TryFrom<Document> + Send>(
+        &self,
+        page_size: usize,
+        page_token: Option<String>,
+    ) -> storage::Result<Page<T>> {
+        let (docs, next_page_token) = self
+            .firestore
+            .list_page(
+                &self.parent_path,
+                &self.collection_id,
+                page_size,
+                page_token,
+                None,
+            )
+            .await?;
+        let mut items = vec![];
+        for doc in docs.into_iter() {
+            items.push(convert_document(doc)?);
+        }
+        Ok(Page {
+            items,
+            next_page_token,
+        })
+    }
+
+    async fn run_query<T: TryFrom<Document> + Send>(
+        &self,
+        query: StructuredQuery,
+        transaction: Option<&Transaction>,
+    ) -> storage::Result<Vec<T>> {
+        self.firestore
+            .run_query::<T>(
+                &self.parent_path,
+                query.for_collection(&self.collection_id),
+                transaction,
+            )
+            .await
+    }
+
+    async fn run_collection_group_query<T: TryFrom<Document> + Send>(
+        &self,
+        collection_id: &str,
+        query: StructuredQuery,
+        transaction: Option<&Transaction>,
+    ) -> storage::Result<Vec<T>> {
         self.firestore
-            .list::<T>(&self.parent_path, &self.collection_id)
+            .run_query::<T>(
+                &self.parent_path,
+                query.for_collection_group(collection_id),
+                transaction,
+            )
             .await
     }
 
-    pub async fn upsert<T: Into<Document>>(
+    async fn transform(
+        &self,
+        id: &Uuid,
+        transforms: Vec<FieldTransform>,
+        transaction: Option<&Transaction>,
+    ) -> storage::Result<()> {
+        // An update with an empty mask leaves every field alone, but still creates the document
+        // if it does not exist, before the transforms are applied
+        let mut doc = Document::new(HashMap::new());
+        doc.name = self.document_name(id);
+        let write = Write::Transform {
+            update: doc,
+            update_mask: FieldMask::new(&[]),
+            update_transforms: transforms,
+            current_document: None,
+        };
+        match transaction {
+            Some(t) => match t.append_write(write).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            },
+            None => self.firestore.commit_writes(vec![write]).await,
+        }
+    }
+
+    async fn transform_if(
+        &self,
+        id: &Uuid,
+        transforms: Vec<FieldTransform>,
+        precondition: Precondition,
+        transaction: Option<&Transaction>,
+    ) -> storage::Result<()> {
+        let mut doc = Document::new(HashMap::new());
+        doc.name = self.document_name(id);
+        let write = Write::Transform {
+            update: doc,
+            update_mask: FieldMask::new(&[]),
+            update_transforms: transforms,
+            current_document: Some(precondition),
+        };
+        match transaction {
+            Some(t) => match t.append_write(write).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            },
+            None => self.firestore.commit_writes(vec![write]).await,
+        }
+    }
+
+    async fn upsert<T: Into<Document> + Send>(
         &self,
         id: &Uuid,
         value: T,
@@ -226,7 +524,10 @@ impl FirestoreClient {
             Some(t) => {
                 let mut doc = value.into();
                 doc.name = name;
-                let write = Write::Update { update: doc };
+                let write = Write::Update {
+                    update: doc,
+                    current_document: None,
+                };
                 match t.append_write(write).await {
                     Ok(_) => Ok(()),
                     Err(e) => Err(e.into()),
@@ -235,119 +536,206 @@ impl FirestoreClient {
             None => self.firestore.patch(&name, value).await,
         }
     }
+
+    async fn upsert_if<T: Into<Document> + Send>(
+        &self,
+        id: &Uuid,
+        value: T,
+        precondition: Precondition,
+        transaction: Option<&Transaction>,
+    ) -> storage::Result<()> {
+        let mut doc = value.into();
+        doc.name = self.document_name(id);
+        let write = Write::Update {
+            update: doc,
+            current_document: Some(precondition),
+        };
+        match transaction {
+            Some(t) => match t.append_write(write).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            },
+            None => self.firestore.commit_writes(vec![write]).await,
+        }
+    }
 }
 
 pub struct Firestore {
-    client: Arc<Client<HttpsConnector<HttpConnector>>>,
+    client: Arc<HttpClient>,
+    base_url: String,
     firebase_project_id: String,
     _oauth_token: Arc<RwLock<String>>,
-    _oauth_refresh_handle: task::JoinHandle<()>,
-    _oauth_refresh_cancellation: oneshot::Sender<()>,
+    _oauth_refresh_handle: Option<task::JoinHandle<()>>,
+    _oauth_refresh_cancellation: Option<oneshot::Sender<()>>,
     _drop_tx: mpsc::Sender<(String, String)>,
     _drop_handle: task::JoinHandle<()>,
 }
 
 impl Firestore {
-    pub async fn new<P: Into<PathBuf>>(json_key_path: P) -> storage::Result<Firestore> {
-        // Create shared HTTP client
-        let mut https = HttpsConnector::new();
-        https.https_only(true);
-        let client = Arc::new(Client::builder().build::<_, hyper::Body>(https));
-
-        // Get OAuth token
-        let json_key = read_json_key(json_key_path).await?;
-        let jwt = build_jwt(&json_key.client_email, &json_key.private_key).await?;
-        let (oauth_token, oauth_expires_in) = get_oauth_token(jwt, &client).await?;
-        let oauth_token = Arc::new(RwLock::new(oauth_token));
-        let mut oauth_expires_in: u64 = oauth_expires_in as u64;
-
-        // Start background task to refresh OAuth token
+    /// Connect to Firestore with the service account key at `json_key_path`
+    pub async fn new<P: Into<PathBuf>>(
+        json_key_path: P,
+        options: HttpOptions,
+    ) -> storage::Result<Firestore> {
+        let credentials = ServiceAccountKey::from_file(json_key_path).await?;
+        Firestore::with_credentials(Box::new(credentials), None, options).await
+    }
+
+    /// Connect to Firestore, authenticating with tokens from `credentials`. `project_id` is
+    /// required unless the credentials name their project, and overrides it if they do.
+    pub async fn with_credentials(
+        credentials: Box<dyn CredentialProvider>,
+        project_id: Option<&str>,
+        options: HttpOptions,
+    ) -> storage::Result<Firestore> {
+        let project_id = match project_id.or_else(|| credentials.project_id()) {
+            Some(project_id) => project_id.to_owned(),
+            None => {
+                return Err(storage::Error::OAuth(
+                    "No project id given, and the credentials do not name one".to_owned(),
+                ))
+            }
+        };
+        let client = Arc::new(HttpClient::new(&options, true));
+        Firestore::connect(
+            client,
+            credentials,
+            FIRESTORE_BASE_URL.to_owned(),
+            project_id,
+        )
+        .await
+    }
+
+    /// Connect to a Firestore emulator listening on `host`, e.g. `localhost:8080`, over plain HTTP.
+    ///
+    /// The emulator does not verify credentials, so no OAuth flow is run. See `NoAuth`.
+    pub async fn new_for_emulator(
+        host: &str,
+        project_id: &str,
+        options: HttpOptions,
+    ) -> storage::Result<Firestore> {
+        let client = Arc::new(HttpClient::new(&options, false));
+
+        info!("Using Firestore emulator at {}", host);
+        Firestore::connect(
+            client,
+            Box::new(NoAuth),
+            format!("http://{}/v1", host),
+            project_id.to_owned(),
+        )
+        .await
+    }
+
+    async fn connect(
+        client: Arc<HttpClient>,
+        credentials: Box<dyn CredentialProvider>,
+        base_url: String,
+        firebase_project_id: String,
+    ) -> storage::Result<Firestore> {
+        let token = credentials.get_token(&client).await?;
+        let oauth_token = Arc::new(RwLock::new(token.token));
+
+        let (oauth_handle, oauth_tx) = match token.expires_in {
+            Some(expires_in) => {
+                let (handle, tx) = Firestore::spawn_token_refresh(
+                    credentials,
+                    Arc::clone(&client),
+                    Arc::clone(&oauth_token),
+                    expires_in,
+                );
+                (Some(handle), Some(tx))
+            }
+            None => (None, None),
+        };
+
+        Ok(Firestore::build(
+            client,
+            base_url,
+            firebase_project_id,
+            oauth_token,
+            oauth_handle,
+            oauth_tx,
+        ))
+    }
+
+    /// Start a background task that replaces `oauth_token` with a new one from `credentials`
+    /// shortly before it expires, until the returned sender is dropped
+    fn spawn_token_refresh(
+        credentials: Box<dyn CredentialProvider>,
+        client: Arc<HttpClient>,
+        oauth_token: Arc<RwLock<String>>,
+        expires_in: time::Duration,
+    ) -> (task::JoinHandle<()>, oneshot::Sender<()>) {
         let (oauth_tx, mut oauth_rx) = oneshot::channel();
-        let client_clone = Arc::clone(&client);
-        let client_email = json_key.client_email.clone();
-        let private_key = json_key.private_key.clone();
-        let oauth_token_clone = Arc::clone(&oauth_token);
         let oauth_handle = tokio::spawn(async move {
+            let mut delay = token_refresh_delay(expires_in);
             while let Err(TryRecvError::Empty) = oauth_rx.try_recv() {
-                // Refresh token 10 minutes before expiration
-                let delay_duration = tokio::time::Duration::from_secs(oauth_expires_in - 600);
-                tokio::time::sleep(delay_duration).await;
+                tokio::time::sleep(delay).await;
                 if let Err(TryRecvError::Closed) = oauth_rx.try_recv() {
                     debug!("Stopping background task to refresh OAuth token");
                     break;
-                } else {
-                    info!("Renewing OAuth token");
-                    match build_jwt(&client_email, &private_key).await {
-                        Ok(jwt) => match get_oauth_token(jwt, &client_clone).await {
-                            Ok(ret) => {
-                                let mut oauth_token = oauth_token_clone.write().await;
-                                *oauth_token = ret.0;
-                                oauth_expires_in = ret.1 as u64;
-                                debug!("Successfully renewed OAuth token");
-                            }
-                            Err(e) => {
-                                error!("Failed to get OAuth token, will retry renewal flow in 10s. Error: {}", e);
-                                oauth_expires_in = 10;
-                            }
-                        },
-                        Err(e) => {
-                            error!(
-                                "Failed to build JWT, will retry renewal flow in 10s. Error: {}",
-                                e
-                            );
-                            oauth_expires_in = 10;
+                }
+                info!("Renewing OAuth token");
+                match credentials.get_token(&client).await {
+                    Ok(token) => {
+                        *oauth_token.write().await = token.token;
+                        debug!("Successfully renewed OAuth token");
+                        match token.expires_in {
+                            Some(expires_in) => delay = token_refresh_delay(expires_in),
+                            None => break,
                         }
                     }
+                    Err(e) => {
+                        error!(
+                            "Failed to get OAuth token, will retry renewal flow in {:?}. Error: {}",
+                            TOKEN_RETRY_DELAY, e
+                        );
+                        delay = TOKEN_RETRY_DELAY;
+                    }
                 }
             }
         });
+        (oauth_handle, oauth_tx)
+    }
 
+    fn build(
+        client: Arc<HttpClient>,
+        base_url: String,
+        firebase_project_id: String,
+        oauth_token: Arc<RwLock<String>>,
+        oauth_refresh_handle: Option<task::JoinHandle<()>>,
+        oauth_refresh_cancellation: Option<oneshot::Sender<()>>,
+    ) -> Firestore {
         // Start background task to clean up dropped transactions
         let (drop_tx, mut drop_rx) = mpsc::channel::<(String, String)>(50);
         let client_clone = Arc::clone(&client);
         let oauth_token_clone = Arc::clone(&oauth_token);
+        let base_url_clone = base_url.clone();
         let drop_handle = tokio::spawn(async move {
             while let Some((database, transaction_id)) = drop_rx.recv().await {
-                let uri = format!("https://firestore.googleapis.com/v1/{}:rollback", database,);
-                let body = RollbackRequest {
-                    transaction: transaction_id.clone(),
-                };
-                let req = build_firestore_request(
-                    Method::POST,
-                    &uri,
-                    &*oauth_token_clone.read().await,
-                    Some(&body),
-                )
-                .await
-                .unwrap();
-                debug!("POST {} {:?}", uri, req);
-                let resp = client_clone.request(req).await.unwrap();
-                let status = resp.status();
-                let body_bytes = body::to_bytes(resp.into_body()).await.unwrap_or_default();
-                debug!(
-                    "HTTP {} {}",
-                    status,
-                    String::from_utf8(body_bytes.to_vec())
-                        .unwrap_or_else(|_| "<mangled body>".to_owned()),
-                );
-                // TODO handle it properly?
-                match status {
-                    StatusCode::OK => debug!("Successfully dropped transaction {}", transaction_id),
-                    _ => error!("Error dropping transaction {}", transaction_id),
-                }
+                // Each abort runs in a task of its own, so that retrying one does not hold up the
+                // rest
+                tokio::spawn(Transaction::abort_with_retries(
+                    base_url_clone.clone(),
+                    database,
+                    Arc::clone(&client_clone),
+                    Arc::clone(&oauth_token_clone),
+                    transaction_id,
+                ));
             }
             debug!("Stopping background task to clean up dropped transactions");
         });
 
-        Ok(Firestore {
+        Firestore {
             client,
-            firebase_project_id: json_key.project_id,
+            base_url,
+            firebase_project_id,
             _oauth_token: oauth_token,
-            _oauth_refresh_handle: oauth_handle,
-            _oauth_refresh_cancellation: oauth_tx,
+            _oauth_refresh_handle: oauth_refresh_handle,
+            _oauth_refresh_cancellation: oauth_refresh_cancellation,
             _drop_tx: drop_tx,
             _drop_handle: drop_handle,
-        })
+        }
     }
 
     async fn begin_transaction(
@@ -355,10 +743,7 @@ impl Firestore {
         database: &str,
         transaction_opts: TransactionOptions,
     ) -> storage::Result<Transaction> {
-        let uri = format!(
-            "https://firestore.googleapis.com/v1/{}:beginTransaction",
-            database,
-        );
+        let uri = format!("{}/{}:beginTransaction", self.base_url, database);
         let body = BeginTransactionRequest {
             options: transaction_opts,
         };
@@ -370,9 +755,7 @@ impl Firestore {
         )
         .await?;
         debug!("POST {} {:?}", uri, req);
-        let resp = self.client.request(req).await?;
-        let status = resp.status();
-        let body_bytes = body::to_bytes(resp.into_body()).await.unwrap_or_default();
+        let (status, body_bytes) = self.client.send(req).await?;
         debug!(
             "HTTP {} {}",
             status,
@@ -382,6 +765,7 @@ impl Firestore {
             StatusCode::OK => {
                 let resp: BeginTransactionResponse = serde_json::from_slice(&body_bytes)?;
                 Ok(Transaction::new(
+                    self.base_url.clone(),
                     database.to_owned(),
                     self._drop_tx.clone(),
                     Arc::clone(&self.client),
@@ -389,13 +773,7 @@ impl Firestore {
                     resp.transaction,
                 ))
             }
-            _ => {
-                error!("Non-success status code {} in begin_transaction", status);
-                Err(storage::Error::Other(format!(
-                    "Non-success status code {} in begin_transaction",
-                    status
-                )))
-            }
+            _ => Err(firestore_error("begin_transaction", status, &body_bytes)),
         }
     }
 
@@ -403,18 +781,20 @@ impl Firestore {
         &self,
         database: &str,
         documents: Vec<String>,
+        mask: Option<&FieldMask>,
         transaction: Option<&Transaction>,
     ) -> storage::Result<HashMap<String, Option<Document>>> {
-        let uri = format!(
-            "https://firestore.googleapis.com/v1/{}/documents:batchGet",
-            database
-        );
-
-        let body: BatchGetRequest;
-        let mut ret = HashMap::new();
-
         if let Some(t) = transaction {
+            // Pending writes are applied over whole documents, so any mask is applied afterwards
+            let has_pending_writes = documents.iter().any(|name| t.has_pending_writes(name));
+            let request_mask = if has_pending_writes {
+                None
+            } else {
+                mask.cloned()
+            };
+
             // If part of a transaction, only request the docs that are not already cached
+            let mut ret: HashMap<String, Option<Document>> = HashMap::new();
             let mut filtered_doc_names = vec![];
             for doc_name in documents.into_iter() {
                 if let Some(doc) = t.read_cache.read().await.get(&doc_name).cloned() {
@@ -425,28 +805,54 @@ impl Firestore {
                 }
             }
 
-            body = BatchGetRequest {
-                documents: filtered_doc_names,
-                transaction: Some(t.transaction_id.clone()),
-            };
-        } else {
-            body = BatchGetRequest {
-                documents,
-                transaction: None,
-            };
+            if !filtered_doc_names.is_empty() {
+                let body = BatchGetRequest {
+                    documents: filtered_doc_names,
+                    mask: request_mask.clone(),
+                    transaction: Some(t.transaction_id.clone()),
+                };
+                for (name, doc) in self.batch_get_request(database, &body).await? {
+                    // Only whole documents are cached, so that later reads see every field
+                    if let (Some(doc), None) = (&doc, &request_mask) {
+                        t.cache_read(name.clone(), doc.clone()).await;
+                    }
+                    ret.insert(name, doc);
+                }
+            }
+
+            for (name, doc) in ret.iter_mut() {
+                let with_writes = t.apply_pending_writes(name, doc.take());
+                *doc = match mask {
+                    Some(mask) => with_writes.map(|doc| mask.apply(doc)),
+                    None => with_writes,
+                };
+            }
+            return Ok(ret);
         }
 
+        let body = BatchGetRequest {
+            documents,
+            mask: mask.cloned(),
+            transaction: None,
+        };
+        self.batch_get_request(database, &body).await
+    }
+
+    async fn batch_get_request(
+        &self,
+        database: &str,
+        body: &BatchGetRequest,
+    ) -> storage::Result<HashMap<String, Option<Document>>> {
+        let uri = format!("{}/{}/documents:batchGet", self.base_url, database);
         let req = build_firestore_request(
             Method::POST,
             &uri,
             &*self._oauth_token.read().await,
-            Some(&body),
+            Some(body),
         )
         .await?;
         debug!("POST {} {:?}", uri, req);
-        let resp = self.client.request(req).await?;
-        let status = resp.status();
-        let body_bytes = body::to_bytes(resp.into_body()).await.unwrap_or_default();
+        let (status, body_bytes) = self.client.send(req).await?;
         debug!(
             "HTTP {} {}",
             status,
@@ -454,6 +860,7 @@ impl Firestore {
         );
         match status {
             StatusCode::OK => {
+                let mut ret = HashMap::new();
                 let batch_get_docs: Vec<BatchGetDocument> = serde_json::from_slice(&body_bytes)?;
                 for batch_get_doc in batch_get_docs.into_iter() {
                     match batch_get_doc {
@@ -468,13 +875,7 @@ impl Firestore {
                 }
                 Ok(ret)
             }
-            _ => {
-                error!("Non-success status code {} in batch_get", status);
-                Err(storage::Error::Other(format!(
-                    "Non-success status code {} in batch_get",
-                    status,
-                )))
-            }
+            _ => Err(firestore_error("batch_get", status, &body_bytes)),
         }
     }
 
@@ -482,8 +883,70 @@ impl Firestore {
         transaction.commit().await
     }
 
+    /// Atomically apply `writes` outside of a transaction
+    async fn commit_writes(&self, writes: Vec<Write>) -> storage::Result<()> {
+        let database = format!(
+            "projects/{}/databases/(default)/documents",
+            self.firebase_project_id,
+        );
+        let body = CommitRequest {
+            writes,
+            transaction: None,
+        };
+        Transaction::commit_internal(
+            self.base_url.clone(),
+            database,
+            Arc::clone(&self.client),
+            Arc::clone(&self._oauth_token),
+            body,
+        )
+        .await
+    }
+
+    /// Apply `writes` outside of a transaction, each on its own, returning their outcomes in order
+    async fn batch_write(&self, writes: Vec<Write>) -> storage::Result<Vec<storage::Result<()>>> {
+        let count = writes.len();
+        let uri = format!(
+            "{}/projects/{}/databases/(default)/documents:batchWrite",
+            self.base_url, self.firebase_project_id,
+        );
+        let body = BatchWriteRequest { writes };
+        let req = build_firestore_request(
+            Method::POST,
+            &uri,
+            &*self._oauth_token.read().await,
+            Some(&body),
+        )
+        .await?;
+        debug!("POST {} with {} writes", uri, count);
+        let (status, body_bytes) = self.client.send(req).await?;
+        debug!(
+            "HTTP {} {}",
+            status,
+            String::from_utf8(body_bytes.to_vec()).unwrap_or_else(|_| "<mangled body>".to_owned()),
+        );
+        match status {
+            StatusCode::OK => {
+                let resp: BatchWriteResponse = serde_json::from_slice(&body_bytes)?;
+                if resp.status.len() != count {
+                    return Err(storage::Error::Other(format!(
+                        "Expected {} write statuses from batchWrite, got {}",
+                        count,
+                        resp.status.len()
+                    )));
+                }
+                Ok(resp
+                    .status
+                    .into_iter()
+                    .map(RpcStatus::into_result)
+                    .collect())
+            }
+            _ => Err(firestore_error("batch_write", status, &body_bytes)),
+        }
+    }
+
     async fn delete<T: TryFrom<Document>>(&self, name: &str) -> storage::Result<()> {
-        let uri = format!("https://firestore.googleapis.com/v1/{}", name);
+        let uri = format!("{}/{}", self.base_url, name);
         let req = build_firestore_request::<()>(
             Method::DELETE,
             &uri,
@@ -492,9 +955,7 @@ impl Firestore {
         )
         .await?;
         debug!("DELETE {}", uri);
-        let resp = self.client.request(req).await?;
-        let status = resp.status();
-        let body_bytes = body::to_bytes(resp.into_body()).await.unwrap_or_default();
+        let (status, body_bytes) = self.client.send(req).await?;
         debug!(
             "HTTP {}  {}",
             status,
@@ -502,10 +963,7 @@ impl Firestore {
         );
         match status {
             StatusCode::OK => Ok(()),
-            _ => Err(storage::Error::Other(
-                String::from_utf8(body_bytes.to_vec())
-                    .unwrap_or_else(|_| "<mangled body>".to_owned()),
-            )),
+            _ => Err(firestore_error("delete", status, &body_bytes)),
         }
     }
 
@@ -517,8 +975,8 @@ impl Firestore {
         value: T,
     ) -> storage::Result<()> {
         let uri = format!(
-            "https://firestore.googleapis.com/v1/{}/{}?documentId={}",
-            parent, collection_id, document_id
+            "{}/{}/{}?documentId={}",
+            self.base_url, parent, collection_id, document_id
         );
         let doc: Document = value.into();
         let req = build_firestore_request(
@@ -529,9 +987,7 @@ impl Firestore {
         )
         .await?;
         debug!("POST {} {:?}", uri, req);
-        let resp = self.client.request(req).await?;
-        let status = resp.status();
-        let body_bytes = body::to_bytes(resp.into_body()).await.unwrap_or_default();
+        let (status, body_bytes) = self.client.send(req).await?;
         debug!(
             "HTTP {} {}",
             status,
@@ -540,40 +996,19 @@ impl Firestore {
         match status {
             StatusCode::OK => Ok(()),
             _ => {
-                if let Ok(e) = serde_json::from_slice::<FirestoreErrorResponse>(&body_bytes) {
-                    match e.status {
-                        FirestoreErrorCode::ABORTED =>
-                            Err(storage::Error::Transaction(
-                                "Document contention, try again later".to_owned(),
-                            )),
-                        FirestoreErrorCode::ALREADY_EXISTS =>
-                            Err(storage::Error::Conflict(format!(
-                                "Could not create document with id {} under parent '{}' collection '{}' as it already exists",
-                                document_id,
-                                parent,
-                                collection_id,
-                            ))),
-                        _ =>
-                            Err(storage::Error::Other(format!(
-                                "Error creating document with id {} under parent '{}' collection '{}': HTTP {} {}",
-                                document_id,
-                                parent,
-                                collection_id,
-                                status,
-                                String::from_utf8(body_bytes.to_vec())
-                                    .unwrap_or_else(|_| "<mangled body>".to_owned()),
-                            ))),
+                let e = parse_error(status, &body_bytes);
+                match e.code {
+                    ServiceErrorCode::AlreadyExists => Err(storage::Error::Conflict(format!(
+                        "Could not create document with id {} under parent '{}' collection '{}' as it already exists",
+                        document_id, parent, collection_id,
+                    ))),
+                    _ => {
+                        error!(
+                            "Error creating document with id {} under parent '{}' collection '{}': {}",
+                            document_id, parent, collection_id, e
+                        );
+                        Err(storage::Error::Service(e))
                     }
-                } else {
-                    Err(storage::Error::Other(format!(
-                        "Error creating document with id {} under parent '{}' collection '{}': HTTP {} {}",
-                        document_id,
-                        parent,
-                        collection_id,
-                        status,
-                        String::from_utf8(body_bytes.to_vec())
-                            .unwrap_or_else(|_| "<mangled body>".to_owned()),
-                    )))
                 }
             }
         }
@@ -582,33 +1017,33 @@ impl Firestore {
     async fn get<T: TryFrom<Document>>(
         &self,
         name: &str,
+        mask: Option<&FieldMask>,
         transaction: Option<&Transaction>,
     ) -> storage::Result<Option<T>> {
-        // Check transaction read cache
+        // Read through batchGet inside a transaction, as the emulator never answers a single
+        // document GET with a transaction parameter. This also consults the transaction's read
+        // cache and pending writes.
         if let Some(t) = transaction {
-            if let Some(doc) = t.read_cache.read().await.get(name).cloned() {
-                // Cache hit
-                match doc.try_into() {
-                    Ok(ret) => {
-                        debug!("Transaction read cache hit for {}", name);
-                        return Ok(Some(ret));
-                    },
-                    Err(_) => error!("Transaction read cache hit but failed to convert Document into requested type."),
-                }
-            }
+            let database = format!("projects/{}/databases/(default)", self.firebase_project_id);
+            let doc = self
+                .batch_get(&database, vec![name.to_owned()], mask, Some(t))
+                .await?
+                .remove(name)
+                .flatten();
+            return match doc {
+                Some(doc) => match doc.try_into() {
+                    Ok(ret) => Ok(Some(ret)),
+                    Err(_) => Err(storage::Error::Other(
+                        "Failed to convert from Document to requested type.".to_owned(),
+                    )),
+                },
+                None => Ok(None),
+            };
         }
 
-        let uri = match transaction {
-            Some(t) => format!(
-                "https://firestore.googleapis.com/v1/{}?transaction={}",
-                name,
-                percent_encoding::utf8_percent_encode(
-                    &t.transaction_id,
-                    percent_encoding::NON_ALPHANUMERIC
-                )
-                .to_string()
-            ),
-            None => format!("https://firestore.googleapis.com/v1/{}", name),
+        let uri = match mask {
+            Some(mask) => format!("{}/{}?{}", self.base_url, name, mask.to_query_params()),
+            None => format!("{}/{}", self.base_url, name),
         };
         let req = Request::builder()
             .method(Method::GET)
@@ -621,9 +1056,7 @@ impl Firestore {
             .body(Body::empty())
             .unwrap();
         debug!("GET {}", uri);
-        let resp = self.client.request(req).await?;
-        let status = resp.status();
-        let body_bytes = body::to_bytes(resp.into_body()).await.unwrap_or_default();
+        let (status, body_bytes) = self.client.send(req).await?;
         debug!(
             "HTTP {} {}",
             status,
@@ -632,12 +1065,6 @@ impl Firestore {
         match status {
             StatusCode::OK => {
                 let doc: Document = serde_json::from_slice(&body_bytes)?;
-
-                // Add to transaction read cache
-                if let Some(t) = transaction {
-                    t.cache_read(name.to_owned(), doc.clone()).await;
-                }
-
                 let result: Result<T, _> = doc.try_into();
                 match result {
                     Ok(ret) => Ok(Some(ret)),
@@ -647,13 +1074,7 @@ impl Firestore {
                 }
             }
             StatusCode::NOT_FOUND => Ok(None),
-            _ => {
-                error!("Non-success status code {} in get", status);
-                Err(storage::Error::Other(format!(
-                    "Non-success status code {} in get",
-                    status
-                )))
-            }
+            _ => Err(firestore_error("get", status, &body_bytes)),
         }
     }
 
@@ -661,70 +1082,27 @@ impl Firestore {
         &self,
         parent: &str,
         collection_id: &str,
+        mask: Option<&FieldMask>,
     ) -> storage::Result<Vec<T>> {
         const PAGE_SIZE: usize = 100;
         let mut ret = vec![];
         let mut next_page_token = None;
         loop {
-            let uri: String;
-            if let Some(token) = next_page_token {
-                uri = format!(
-                    "https://firestore.googleapis.com/v1/{}/{}?pageSize={}&pageToken={}",
-                    parent, collection_id, PAGE_SIZE, token
-                );
-            } else {
-                uri = format!(
-                    "https://firestore.googleapis.com/v1/{}/{}?pageSize={}",
-                    parent, collection_id, PAGE_SIZE
-                );
-            }
-
-            let req = build_firestore_request::<()>(
-                Method::GET,
-                &uri,
-                &*self._oauth_token.read().await,
-                None,
-            )
-            .await?;
-            debug!("GET {}", uri);
-            let resp = self.client.request(req).await?;
-            let status = resp.status();
-            let body_bytes = body::to_bytes(resp.into_body()).await.unwrap_or_default();
-            debug!(
-                "HTTP {} {}",
-                status,
-                String::from_utf8(body_bytes.to_vec())
-                    .unwrap_or_else(|_| "<mangled body>".to_owned()),
-            );
-
-            let list_response: ListDocumentsResponse;
-            match status {
-                StatusCode::OK => {
-                    list_response = serde_json::from_slice(&body_bytes)?;
-                    if let Some(docs) = list_response.documents {
-                        for doc in docs {
-                            let result = doc.try_into();
-                            match result {
-                                Ok(t) => ret.push(t),
-                                Err(_) => {
-                                    error!("Failed to convert from Document to requested type.");
-                                }
-                            };
-                        }
+            let (docs, page_token) = self
+                .list_page(parent, collection_id, PAGE_SIZE, next_page_token, mask)
+                .await?;
+            for doc in docs {
+                let result = doc.try_into();
+                match result {
+                    Ok(t) => ret.push(t),
+                    Err(_) => {
+                        error!("Failed to convert from Document to requested type.");
                     }
-
-                    next_page_token = list_response.next_page_token;
-                }
-                _ => {
-                    error!("Non-success status code {} for list", status);
-                    return Err(storage::Error::Other(format!(
-                        "Non-success status code {} for list",
-                        status
-                    )));
-                }
+                };
             }
 
-            if let None = next_page_token {
+            next_page_token = page_token;
+            if next_page_token.is_none() {
                 break;
             }
         }
@@ -732,9 +1110,52 @@ impl Firestore {
         Ok(ret)
     }
 
+    async fn list_page(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        page_size: usize,
+        page_token: Option<String>,
+        mask: Option<&FieldMask>,
+    ) -> storage::Result<(Vec<Document>, Option<String>)> {
+        let uri = list_page_uri(
+            &self.base_url,
+            parent,
+            collection_id,
+            page_size,
+            page_token.as_deref(),
+            mask,
+        );
+        let req = build_firestore_request::<()>(
+            Method::GET,
+            &uri,
+            &*self._oauth_token.read().await,
+            None,
+        )
+        .await?;
+        debug!("GET {}", uri);
+        let (status, body_bytes) = self.client.send(req).await?;
+        debug!(
+            "HTTP {} {}",
+            status,
+            String::from_utf8(body_bytes.to_vec()).unwrap_or_else(|_| "<mangled body>".to_owned()),
+        );
+
+        match status {
+            StatusCode::OK => {
+                let list_response: ListDocumentsResponse = serde_json::from_slice(&body_bytes)?;
+                Ok((
+                    list_response.documents.unwrap_or_default(),
+                    list_response.next_page_token,
+                ))
+            }
+            _ => Err(firestore_error("list", status, &body_bytes)),
+        }
+    }
+
     async fn patch<T: Into<Document>>(&self, document_name: &str, value: T) -> storage::Result<()> {
         // TODO return indication of whether it was an insert or an update if possible
-        let uri = format!("https://firestore.googleapis.com/v1/{}", document_name);
+        let uri = format!("{}/{}", self.base_url, document_name);
         let doc: Document = value.into();
         let req = build_firestore_request(
             Method::PATCH,
@@ -744,9 +1165,7 @@ impl Firestore {
         )
         .await?;
         debug!("PATCH {} {:?}", uri, req);
-        let resp = self.client.request(req).await?;
-        let status = resp.status();
-        let body_bytes = body::to_bytes(resp.into_body()).await.unwrap_or_default();
+        let (status, body_bytes) = self.client.send(req).await?;
         debug!(
             "HTTP {} {}",
             status,
@@ -754,31 +1173,55 @@ impl Firestore {
         );
         match status {
             StatusCode::OK => Ok(()),
-            _ => Err(storage::Error::Other(format!(
-                "Error patching document '{}': HTTP {} {}",
-                document_name,
-                status,
-                String::from_utf8(body_bytes.to_vec())
-                    .unwrap_or_else(|_| "<mangled body>".to_owned()),
-            ))),
+            _ => Err(firestore_error("patch", status, &body_bytes)),
         }
     }
 
-    #[allow(dead_code)]
-    #[allow(unused_variables)]
-    async fn rollback(&self, transaction_id: String) -> storage::Result<()> {
-        // TODO Firestore::rollback
-        todo!()
+    async fn rollback(&self, transaction: Transaction) -> storage::Result<()> {
+        transaction.rollback().await
     }
 
-    #[allow(dead_code)]
     async fn run_query<T: TryFrom<Document>>(
         &self,
-        _parent: &str,
-        _structured_query: StructuredQuery,
-    ) -> storage::Result<T> {
-        // TODO Firestore::run_query
-        todo!()
+        parent: &str,
+        structured_query: StructuredQuery,
+        transaction: Option<&Transaction>,
+    ) -> storage::Result<Vec<T>> {
+        let uri = format!("{}/{}:runQuery", self.base_url, parent);
+        let body = RunQueryRequest {
+            structured_query,
+            transaction: transaction.map(|t| t.transaction_id.clone()),
+        };
+        let req = build_firestore_request(
+            Method::POST,
+            &uri,
+            &*self._oauth_token.read().await,
+            Some(&body),
+        )
+        .await?;
+        debug!("POST {} {:?}", uri, req);
+        let (status, body_bytes) = self.client.send(req).await?;
+        debug!(
+            "HTTP {} {}",
+            status,
+            String::from_utf8(body_bytes.to_vec()).unwrap_or_else(|_| "<mangled body>".to_owned()),
+        );
+        match status {
+            StatusCode::OK => {
+                // Every document comes back in its own response, alongside responses that only
+                // report progress and carry no document
+                let responses: Vec<RunQueryResponse> = serde_json::from_slice(&body_bytes)?;
+                let mut ret = vec![];
+                for doc in responses.into_iter().filter_map(|r| r.document) {
+                    match doc.try_into() {
+                        Ok(t) => ret.push(t),
+                        Err(_) => error!("Failed to convert from Document to requested type."),
+                    }
+                }
+                Ok(ret)
+            }
+            _ => Err(firestore_error("run_query", status, &body_bytes)),
+        }
     }
 }
 
@@ -786,10 +1229,12 @@ impl Firestore {
 #[serde(rename_all = "camelCase")]
 pub struct Document {
     pub name: String,
+    // Firestore leaves this out for documents with no fields, such as those read with a mask
+    #[serde(default)]
     pub fields: HashMap<String, DocumentField>,
-    #[serde(skip_serializing)]
+    #[serde(default, skip_serializing)]
     pub create_time: String,
-    #[serde(skip_serializing)]
+    #[serde(default, skip_serializing)]
     pub update_time: String,
 }
 
@@ -815,6 +1260,25 @@ impl Document {
         }
     }
 
+    /// The id of the document a subcollection document belongs to, e.g. the user id of
+    /// `users/{user_id}/characters/{character_id}`
+    pub fn extract_parent_id(&self) -> Result<Uuid, String> {
+        let segments: Vec<&str> = self.name.split('/').collect();
+        if segments.len() < 4 {
+            return Err(format!("Document '{}' has no parent document", self.name));
+        }
+        let id = segments[segments.len() - 3];
+        Uuid::parse_str(id).map_err(|_| format!("Unable to convert id '{}' to a uuid", id))
+    }
+
+    pub fn extract_boolean(&self, field_name: &str) -> Result<bool, String> {
+        if let Some(doc_field) = self.fields.get(field_name) {
+            doc_field.extract_boolean()
+        } else {
+            Err(format!("Missing field {}", field_name))
+        }
+    }
+
     pub fn extract_double<T: From<f64> + From<i32> + Float>(
         &self,
         field_name: &str,
@@ -854,16 +1318,40 @@ impl Document {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub enum DocumentField {
+    // Firestore expects `{"nullValue": null}` rather than a bare variant name
+    #[serde(serialize_with = "serialize_null")]
     NullValue,
     ArrayValue(DocumentArrayValue),
+    BooleanValue(bool),
+    #[serde(with = "base64_bytes")]
+    BytesValue(Vec<u8>),
     DoubleValue(f64),
+    GeoPointValue(LatLng),
     IntegerValue(String),
     MapValue(DocumentMapValue),
+    /// Full resource name of another document
+    ReferenceValue(String),
     StringValue(String),
     TimestampValue(DateTime<Utc>),
 }
 
 impl DocumentField {
+    pub fn extract_boolean(&self) -> Result<bool, String> {
+        if let DocumentField::BooleanValue(b) = self {
+            Ok(*b)
+        } else {
+            Err(format!("Error parsing BooleanValue from {:?}", self))
+        }
+    }
+
+    pub fn extract_bytes(&self) -> Result<Vec<u8>, String> {
+        if let DocumentField::BytesValue(bytes) = self {
+            Ok(bytes.clone())
+        } else {
+            Err(format!("Error parsing BytesValue from {:?}", self))
+        }
+    }
+
     pub fn extract_double<T: From<f64> + From<i32> + Float>(&self) -> Result<T, String> {
         if let DocumentField::DoubleValue(ret) = self {
             Ok((*ret).into())
@@ -908,6 +1396,29 @@ impl DocumentField {
         }
     }
 
+    pub fn extract_geo_point(&self) -> Result<LatLng, String> {
+        if let DocumentField::GeoPointValue(lat_lng) = self {
+            Ok(*lat_lng)
+        } else {
+            Err(format!("Error parsing GeoPointValue from {:?}", self))
+        }
+    }
+
+    pub fn extract_reference(&self) -> Result<String, String> {
+        if let DocumentField::ReferenceValue(name) = self {
+            Ok(name.to_string())
+        } else {
+            Err(format!("Error parsing ReferenceValue from {:?}", self))
+        }
+    }
+
+    /// The id of the document referred to, taken from the last segment of its name
+    pub fn extract_reference_id(&self) -> Result<Uuid, String> {
+        let name = self.extract_reference()?;
+        let id = name.rsplit('/').next().unwrap_or_default();
+        Uuid::parse_str(id).map_err(|_| format!("Unable to convert id '{}' to a uuid", id))
+    }
+
     pub fn extract_timestamp(&self) -> Result<DateTime<Utc>, String> {
         if let DocumentField::TimestampValue(dt) = self {
             Ok(*dt)
@@ -917,6 +1428,30 @@ impl DocumentField {
     }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LatLng {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+fn serialize_null<S: serde::Serializer>(serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_unit()
+}
+
+/// Firestore sends bytes as base64 strings
+mod base64_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        base64::decode(&s).map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DocumentArrayValue {
@@ -951,7 +1486,52 @@ struct BeginTransactionResponse {
 #[serde(rename_all = "camelCase")]
 struct CommitRequest {
     writes: Vec<Write>,
-    transaction: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchWriteRequest {
+    writes: Vec<Write>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchWriteResponse {
+    #[serde(default)]
+    status: Vec<RpcStatus>,
+}
+
+/// A `google.rpc.Status`. Fields with default values are left out of responses, so an empty
+/// status is `OK`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RpcStatus {
+    #[serde(default)]
+    code: i32,
+    #[serde(default)]
+    message: String,
+}
+
+impl RpcStatus {
+    fn into_result(self) -> storage::Result<()> {
+        if self.code == 0 {
+            return Ok(());
+        }
+        match ServiceErrorCode::from_rpc_code(self.code) {
+            // A write precondition did not hold
+            ServiceErrorCode::AlreadyExists
+            | ServiceErrorCode::FailedPrecondition
+            | ServiceErrorCode::NotFound => Err(storage::Error::Conflict(self.message)),
+            code => Err(storage::Error::Service(ServiceError {
+                code,
+                // The batch as a whole succeeded
+                http_status: StatusCode::OK.as_u16(),
+                message: self.message,
+            })),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -963,14 +1543,44 @@ struct RollbackRequest {
 #[derive(Debug, Serialize)]
 #[serde(untagged, rename_all = "camelCase")]
 enum Write {
-    Update { update: Document },
-    Delete { delete: String },
+    #[serde(rename_all = "camelCase")]
+    Update {
+        update: Document,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        current_document: Option<Precondition>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Transform {
+        update: Document,
+        update_mask: FieldMask,
+        update_transforms: Vec<FieldTransform>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        current_document: Option<Precondition>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Delete {
+        delete: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        current_document: Option<Precondition>,
+    },
+}
+
+impl Write {
+    fn document_name(&self) -> &str {
+        match self {
+            Write::Update { update, .. } => &update.name,
+            Write::Transform { update, .. } => &update.name,
+            Write::Delete { delete, .. } => delete,
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct BatchGetRequest {
     documents: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mask: Option<FieldMask>,
     transaction: Option<String>,
 }
 
@@ -981,57 +1591,39 @@ enum BatchGetDocument {
     Missing { missing: String },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct RunQueryResponse {
+struct RunQueryRequest {
+    structured_query: StructuredQuery,
+    #[serde(skip_serializing_if = "Option::is_none")]
     transaction: Option<String>,
-    document: Option<Document>,
-    read_time: Option<String>,
-    skipped_results: Option<u32>,
 }
 
-// TODO
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct StructuredQuery {
-    select: String,
-    from: Vec<String>,
-    r#where: String,
-    order_by: Vec<String>,
-    start_at: String,
-    end_at: String,
-    offset: u32,
-    limit: u32,
+struct RunQueryResponse {
+    document: Option<Document>,
 }
 
 #[derive(Debug, Deserialize)]
-struct FirestoreErrorResponse {
-    code: u32,
-    message: String,
-    status: FirestoreErrorCode,
+#[serde(untagged)]
+enum FirestoreErrorBody {
+    Single { error: FirestoreErrorResponse },
+    // Streamed responses such as batchGet and runQuery report errors inside an array
+    Streamed(Vec<FirestoreErrorBody>),
 }
 
-/// Canonical error codes from https://firebase.google.com/docs/firestore/use-rest-api#error_codes
-#[allow(non_camel_case_types)]
 #[derive(Debug, Deserialize)]
-enum FirestoreErrorCode {
-    ABORTED,
-    ALREADY_EXISTS,
-    DEADLINE_EXCEEDED,
-    FAILED_PRECONDITION,
-    INTERNAL,
-    INVALID_ARGUMENT,
-    NOT_FOUND,
-    PERMISSION_DENIED,
-    RESOURCE_EXHAUTED,
-    UNAUTHENTICATED,
-    UNAVAILABLE,
+struct FirestoreErrorResponse {
+    message: String,
+    status: ServiceErrorCode,
 }
 
 pub struct Transaction {
+    base_url: String,
     database: String,
     drop_tx: mpsc::Sender<(String, String)>,
-    http_client: Arc<Client<HttpsConnector<HttpConnector>>>,
+    http_client: Arc<HttpClient>,
     oauth_token: Arc<RwLock<String>>,
     read_cache: Arc<RwLock<HashMap<String, Document>>>,
     transaction_id: String,
@@ -1040,13 +1632,15 @@ pub struct Transaction {
 
 impl Transaction {
     fn new(
+        base_url: String,
         database: String,
         drop_tx: mpsc::Sender<(String, String)>,
-        http_client: Arc<Client<HttpsConnector<HttpConnector>>>,
+        http_client: Arc<HttpClient>,
         oauth_token: Arc<RwLock<String>>,
         id: String,
     ) -> Transaction {
         Transaction {
+            base_url,
             database,
             drop_tx,
             http_client,
@@ -1057,9 +1651,25 @@ impl Transaction {
         }
     }
 
+    /// Discard the buffered writes and release the transaction on the server. Unlike `abort`,
+    /// failures are returned to the caller rather than retried in the background.
+    pub async fn rollback(self) -> storage::Result<()> {
+        if self.writes.lock().expect("Poisoned lock").take().is_none() {
+            return Err(TransactionError::InvalidState.into());
+        }
+        Transaction::rollback_internal(
+            &self.base_url,
+            &self.database,
+            &self.http_client,
+            &self.oauth_token,
+            &self.transaction_id,
+        )
+        .await
+    }
+
     pub async fn abort(self) {
-        let mut mutex_guard = self.writes.lock().expect("Poisoned lock");
-        if let None = mutex_guard.take() {
+        let writes = self.writes.lock().expect("Poisoned lock").take();
+        if let None = writes {
             warn!("Attempted to abort invalid transaction");
         } else {
             let database = self.database.clone();
@@ -1095,6 +1705,7 @@ impl Transaction {
     }
 
     pub async fn commit(self) -> storage::Result<()> {
+        let base_url = self.base_url.clone();
         let database = self.database.clone();
         let http_client = Arc::clone(&self.http_client);
         let oauth_token = Arc::clone(&self.oauth_token);
@@ -1109,19 +1720,20 @@ impl Transaction {
         }
         let body = CommitRequest {
             writes,
-            transaction: transaction_id,
+            transaction: Some(transaction_id),
         };
 
-        Transaction::commit_internal(database, http_client, oauth_token, body).await
+        Transaction::commit_internal(base_url, database, http_client, oauth_token, body).await
     }
 
     async fn commit_internal(
+        base_url: String,
         database: String,
-        http_client: Arc<Client<HttpsConnector<HttpConnector>>>,
+        http_client: Arc<HttpClient>,
         oauth_token: Arc<RwLock<String>>,
         request_body: CommitRequest,
     ) -> storage::Result<()> {
-        let uri = format!("https://firestore.googleapis.com/v1/{}:commit", database);
+        let uri = format!("{}/{}:commit", base_url, database);
         debug!("{}", serde_json::to_string_pretty(&request_body)?);
         let req = build_firestore_request(
             Method::POST,
@@ -1131,9 +1743,7 @@ impl Transaction {
         )
         .await?;
         debug!("POST {} {:?}", uri, req);
-        let resp = http_client.request(req).await?;
-        let status = resp.status();
-        let body_bytes = body::to_bytes(resp.into_body()).await.unwrap_or_default();
+        let (status, body_bytes) = http_client.send(req).await?;
         debug!(
             "HTTP {} {}",
             status,
@@ -1144,22 +1754,135 @@ impl Transaction {
                 // TODO interpret write results
                 Ok(())
             }
-            StatusCode::CONFLICT => {
-                // Write contention
-                Err(storage::Error::Transaction(
-                    "Document contention, try again later".to_owned(),
-                ))
-            }
             _ => {
-                error!("Non-success status code {} in commit", status);
-                Err(storage::Error::Other(format!(
-                    "Non-success status code {} in commit",
-                    status
-                )))
+                let e = parse_error(status, &body_bytes);
+                match e.code {
+                    // A write precondition did not hold
+                    ServiceErrorCode::AlreadyExists
+                    | ServiceErrorCode::FailedPrecondition
+                    | ServiceErrorCode::NotFound => Err(storage::Error::Conflict(e.message)),
+                    _ => {
+                        error!("Error in commit: {}", e);
+                        Err(storage::Error::Service(e))
+                    }
+                }
             }
         }
     }
 
+    async fn rollback_internal(
+        base_url: &str,
+        database: &str,
+        http_client: &HttpClient,
+        oauth_token: &RwLock<String>,
+        transaction_id: &str,
+    ) -> storage::Result<()> {
+        let uri = format!("{}/{}:rollback", base_url, database);
+        let body = RollbackRequest {
+            transaction: transaction_id.to_owned(),
+        };
+        let req =
+            build_firestore_request(Method::POST, &uri, &*oauth_token.read().await, Some(&body))
+                .await?;
+        debug!("POST {} {:?}", uri, req);
+        let (status, body_bytes) = http_client.send(req).await?;
+        debug!(
+            "HTTP {} {}",
+            status,
+            String::from_utf8(body_bytes.to_vec()).unwrap_or_else(|_| "<mangled body>".to_owned()),
+        );
+        match status {
+            StatusCode::OK => Ok(()),
+            _ => Err(firestore_error("rollback", status, &body_bytes)),
+        }
+    }
+
+    /// Roll back an aborted or dropped transaction, retrying transient failures with exponential
+    /// backoff, as there is no caller left to report them to
+    async fn abort_with_retries(
+        base_url: String,
+        database: String,
+        http_client: Arc<HttpClient>,
+        oauth_token: Arc<RwLock<String>>,
+        transaction_id: String,
+    ) {
+        let mut delay = ABORT_RETRY_BASE_DELAY;
+        let mut retries = ABORT_RETRIES;
+        loop {
+            let result = Transaction::rollback_internal(
+                &base_url,
+                &database,
+                &http_client,
+                &oauth_token,
+                &transaction_id,
+            )
+            .await;
+            match result {
+                Ok(_) => {
+                    debug!("Successfully dropped transaction {}", transaction_id);
+                    break;
+                }
+                Err(e) if retries > 0 && e.is_retryable() => {
+                    warn!(
+                        "Failed to drop transaction {}, {} retries remaining. Error: {}",
+                        transaction_id, retries, e
+                    );
+                    retries -= 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    error!("Error dropping transaction {}: {}", transaction_id, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn has_pending_writes(&self, name: &str) -> bool {
+        match self.writes.lock().expect("Poisoned lock").as_ref() {
+            Some(writes) => writes.iter().any(|w| w.document_name() == name),
+            None => false,
+        }
+    }
+
+    /// Apply the writes buffered so far for the document `name` over its state as read from the
+    /// server, so that reads in the transaction see its own writes. Preconditions are not
+    /// checked here, they are left for the server to check on commit.
+    fn apply_pending_writes(&self, name: &str, mut doc: Option<Document>) -> Option<Document> {
+        let mutex_guard = self.writes.lock().expect("Poisoned lock");
+        let writes = match mutex_guard.as_ref() {
+            Some(writes) => writes,
+            None => return doc,
+        };
+        for write in writes.iter().filter(|w| w.document_name() == name) {
+            doc = match write {
+                Write::Update { update, .. } => {
+                    let mut updated = update.clone();
+                    if let Some(existing) = doc {
+                        updated.create_time = existing.create_time;
+                    }
+                    Some(updated)
+                }
+                // The update mask of a transform write is always empty, so only the transforms
+                // change the document
+                Write::Transform {
+                    update,
+                    update_transforms,
+                    ..
+                } => {
+                    let mut transformed = doc.unwrap_or_else(|| update.clone());
+                    for transform in update_transforms.iter() {
+                        transform.apply(&mut transformed, Utc::now());
+                    }
+                    Some(transformed)
+                }
+                Write::Delete { .. } => None,
+            };
+        }
+        doc
+    }
+
     async fn cache_read(&self, name: String, doc: Document) {
         let mut write_guard = self.read_cache.write().await;
         write_guard.insert(name, doc);
@@ -1171,6 +1894,21 @@ impl Transaction {
     }
 }
 
+#[async_trait]
+impl StoreTransaction for Transaction {
+    async fn abort(self) {
+        Transaction::abort(self).await
+    }
+
+    async fn rollback(self) -> storage::Result<()> {
+        Transaction::rollback(self).await
+    }
+
+    async fn commit(self) -> storage::Result<()> {
+        Transaction::commit(self).await
+    }
+}
+
 impl Drop for Transaction {
     fn drop(&mut self) {
         let mut mutex_guard = self.writes.lock().expect("Poisoned lock");
@@ -1189,12 +1927,6 @@ impl Drop for Transaction {
     }
 }
 
-#[derive(Debug)]
-pub enum TransactionType {
-    ReadOnly,
-    ReadWrite,
-}
-
 #[derive(Debug)]
 enum TransactionError {
     InvalidState,
@@ -1225,108 +1957,71 @@ struct ReadWriteTransactionOptions {
     retry_transaction: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct JsonKey {
-    r#type: String,
-    project_id: String,
-    private_key_id: String,
-    private_key: String,
-    client_email: String,
-    auth_uri: String,
-    token_uri: String,
-    auth_provider_x509_cert_url: String,
-    client_x509_cert_url: String,
-}
-
-async fn read_json_key<P: Into<PathBuf>>(json_key_path: P) -> storage::Result<JsonKey> {
-    let contents = fs::read_to_string(json_key_path.into()).await?;
-    Ok(serde_json::from_str(&contents)?)
-}
-
-/// OpenID Connect claims data structure
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    /// Email address of the service account
-    iss: String,
-    /// Space-delimited list of the permissions requested
-    scope: String,
-    /// Intended target of assertion, should just be https://oauth2.googleapis.com/token
-    aud: String,
-    /// Expiration time of the assertion, as seconds since epoch. Maximum of 1 hour after issuance
-    exp: usize,
-    /// Assertion issuance time, as seconds since epoch
-    iat: usize,
-}
-
-async fn build_jwt(email: &str, private_key: &str) -> storage::Result<String> {
-    let now = Utc::now().timestamp() as usize;
-    let claims = Claims {
-        iss: email.to_string(),
-        scope: "https://www.googleapis.com/auth/datastore".to_owned(),
-        aud: "https://oauth2.googleapis.com/token".to_owned(),
-        exp: now + 3600,
-        iat: now,
-    };
-
-    let token = encode(
-        &Header::new(Algorithm::RS256),
-        &claims,
-        &EncodingKey::from_rsa_pem(private_key.as_ref())?,
-    )?;
-    Ok(token)
+/// Parse the body of a non-success response from Firestore. Falls back to the code usually sent
+/// with the HTTP status if the body is not a Firestore error.
+fn parse_error(status: StatusCode, body_bytes: &[u8]) -> ServiceError {
+    let mut body = serde_json::from_slice::<FirestoreErrorBody>(body_bytes).ok();
+    while let Some(FirestoreErrorBody::Streamed(mut bodies)) = body {
+        body = if bodies.is_empty() {
+            None
+        } else {
+            Some(bodies.swap_remove(0))
+        };
+    }
+    match body {
+        Some(FirestoreErrorBody::Single { error }) => {
+            ServiceError::new(error.status, status.as_u16(), error.message)
+        }
+        _ => ServiceError::new(
+            ServiceErrorCode::from_http_status(status.as_u16()),
+            status.as_u16(),
+            String::from_utf8(body_bytes.to_vec()).unwrap_or_else(|_| "<mangled body>".to_owned()),
+        ),
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OAuth2Request {
-    grant_type: String,
-    assertion: String,
+/// Renew tokens `TOKEN_REFRESH_MARGIN` before they expire, or halfway through their lifetime if
+/// they are too short-lived for that
+fn token_refresh_delay(expires_in: time::Duration) -> time::Duration {
+    if expires_in > TOKEN_REFRESH_MARGIN * 2 {
+        expires_in - TOKEN_REFRESH_MARGIN
+    } else {
+        expires_in / 2
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct OAuth2Response {
-    access_token: String,
-    expires_in: usize,
-    token_type: String,
+/// Log and wrap the error in a non-success response to `operation`
+fn firestore_error(operation: &str, status: StatusCode, body_bytes: &[u8]) -> storage::Error {
+    let e = parse_error(status, body_bytes);
+    error!("Error in {}: {}", operation, e);
+    storage::Error::Service(e)
 }
 
-async fn get_oauth_token(
-    jwt: String,
-    http_client: &Client<HttpsConnector<HttpConnector>>,
-) -> storage::Result<(String, usize)> {
-    let sw = time::Instant::now();
-
-    let request_body = OAuth2Request {
-        grant_type: "urn:ietf:params:oauth:grant-type:jwt-bearer".to_owned(),
-        assertion: jwt,
-    };
-    let request = Request::builder()
-        .method(Method::POST)
-        .uri("https://oauth2.googleapis.com/token")
-        .body(Body::from(serde_json::to_string_pretty(&request_body)?))
-        .unwrap();
-    let response = http_client.request(request).await?;
-    let status = response.status();
-    let body_bytes = body::to_bytes(response.into_body())
-        .await
-        .unwrap_or_default();
-
-    match status {
-        StatusCode::OK => {
-            let body: OAuth2Response = serde_json::from_slice(&body_bytes)?;
-            debug!("Response: {} {:?}", status, body);
-            info!("Obtained OAuth token, took {:?}", sw.elapsed());
-            Ok((body.access_token, body.expires_in))
-        }
-        _ => {
-            let body_str = String::from_utf8(body_bytes.to_vec())
-                .unwrap_or_else(|_| "<mangled body>".to_owned());
-            info!("Failed to obtain OAuth token, took {:?}", sw.elapsed());
-            Err(storage::Error::OAuth(format!(
-                "OAuth flow returned HTTP {} with body content: {}",
-                status, body_str
-            )))
-        }
+/// The URI to list a page of `collection_id` under `parent`. Page tokens are opaque, so they are
+/// percent-encoded in case they contain characters reserved in a query string.
+fn list_page_uri(
+    base_url: &str,
+    parent: &str,
+    collection_id: &str,
+    page_size: usize,
+    page_token: Option<&str>,
+    mask: Option<&FieldMask>,
+) -> String {
+    let mut uri = format!(
+        "{}/{}/{}?pageSize={}",
+        base_url, parent, collection_id, page_size
+    );
+    if let Some(token) = page_token {
+        uri = format!(
+            "{}&pageToken={}",
+            uri,
+            utf8_percent_encode(token, NON_ALPHANUMERIC)
+        );
+    }
+    if let Some(mask) = mask {
+        uri = format!("{}&{}", uri, mask.to_query_params());
     }
+    uri
 }
 
 async fn build_firestore_request<T>(
@@ -1359,53 +2054,116 @@ where
 
 #[cfg(test)]
 mod tests {
-    extern crate env_logger;
     use super::*;
 
-    fn logging_init() {
-        let _ = env_logger::builder().is_test(true).try_init();
-    }
-
-    static FAKE_JSON_KEY_PATH: &str = "fake_service_account.json";
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn can_read_key_from_json() {
-        logging_init();
+    #[test]
+    fn can_round_trip_all_field_types() {
+        let json = serde_json::json!({
+            "name": "projects/p/databases/(default)/documents/users/a",
+            "fields": {
+                "null": { "nullValue": null },
+                "locked": { "booleanValue": true },
+                "bytes": { "bytesValue": "AAH/" },
+                "count": { "integerValue": "3" },
+                "ratio": { "doubleValue": 0.5 },
+                "location": { "geoPointValue": { "latitude": -33.8, "longitude": 151.2 } },
+                "owner": {
+                    "referenceValue":
+                        "projects/p/databases/(default)/documents/users/9b2c4a3e-7a3f-4bb6-9d7a-8e7e0a2cf7a4"
+                },
+                "tags": { "arrayValue": { "values": [{ "stringValue": "a" }] } },
+                "created": { "timestampValue": "2021-02-03T04:05:06.123456Z" },
+            },
+            "createTime": "2021-02-03T04:05:06.123456Z",
+            "updateTime": "2021-02-03T04:05:06.123456Z",
+        });
+        let doc: Document = serde_json::from_value(json.clone()).unwrap();
+
+        assert_eq!(doc.extract_boolean("locked"), Ok(true));
+        assert_eq!(doc.fields["bytes"].extract_bytes(), Ok(vec![0, 1, 255]));
+        assert_eq!(
+            doc.fields["location"].extract_geo_point(),
+            Ok(LatLng {
+                latitude: -33.8,
+                longitude: 151.2
+            })
+        );
+        assert_eq!(
+            doc.fields["owner"].extract_reference_id(),
+            Ok(Uuid::parse_str("9b2c4a3e-7a3f-4bb6-9d7a-8e7e0a2cf7a4").unwrap())
+        );
+        assert!(doc.fields["count"].extract_boolean().is_err());
 
-        match read_json_key(FAKE_JSON_KEY_PATH).await {
-            Ok(_) => assert!(true),
-            Err(e) => assert!(false, format!("{:?}", e)),
-        }
+        let round_tripped = serde_json::to_value(&doc).unwrap();
+        assert_eq!(round_tripped["fields"], json["fields"]);
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn can_build_jwt() {
-        logging_init();
+    #[test]
+    fn can_parse_error_responses() {
+        let body =
+            br#"{"error": {"code": 409, "message": "Too much contention", "status": "ABORTED"}}"#;
+        let e = parse_error(StatusCode::CONFLICT, body);
+        assert_eq!(e.code, ServiceErrorCode::Aborted);
+        assert_eq!(e.message, "Too much contention");
+        assert!(e.is_retryable());
+
+        let body =
+            br#"[{"error": {"code": 403, "message": "Denied", "status": "PERMISSION_DENIED"}}]"#;
+        let e = parse_error(StatusCode::FORBIDDEN, body);
+        assert_eq!(e.code, ServiceErrorCode::PermissionDenied);
+        assert!(!e.is_retryable());
+
+        let body = br#"{"error": {"code": 400, "message": "New", "status": "SOMETHING_NEW"}}"#;
+        assert_eq!(
+            parse_error(StatusCode::BAD_REQUEST, body).code,
+            ServiceErrorCode::Unknown
+        );
 
-        let key = read_json_key(FAKE_JSON_KEY_PATH).await.unwrap();
-        match build_jwt(&key.client_email, &key.private_key).await {
-            Ok(_) => assert!(true),
-            Err(e) => assert!(false, format!("{:?}", e)),
-        }
+        let e = parse_error(StatusCode::SERVICE_UNAVAILABLE, b"upstream connect error");
+        assert_eq!(e.code, ServiceErrorCode::Unavailable);
+        assert_eq!(e.message, "upstream connect error");
     }
 
-    #[cfg(feature = "test_requires_secrets")]
-    static JSON_KEY_PATH: &str = "../secrets/service_account.json";
-
-    #[cfg(feature = "test_requires_secrets")]
-    #[tokio::test(flavor = "multi_thread")]
-    async fn can_get_oauth_token() {
-        logging_init();
-
-        let key = read_json_key(JSON_KEY_PATH).await.unwrap();
-        let jwt = build_jwt(&key.client_email, &key.private_key)
-            .await
-            .unwrap();
-
-        let mut https = HttpsConnector::new();
-        https.https_only(true);
-        let client = Client::builder().build::<_, hyper::Body>(https);
+    #[test]
+    fn page_tokens_are_percent_encoded() {
+        let uri = list_page_uri(
+            "http://localhost/v1",
+            "projects/p/databases/(default)/documents",
+            "cards",
+            10,
+            Some("a&b=c#d+e"),
+            Some(&FieldMask::new(&["name"])),
+        );
+        assert_eq!(
+            uri,
+            "http://localhost/v1/projects/p/databases/(default)/documents/cards\
+             ?pageSize=10&pageToken=a%26b%3Dc%23d%2Be&mask.fieldPaths=name"
+        );
+        assert!(uri.parse::<hyper::Uri>().is_ok());
+    }
 
-        get_oauth_token(jwt, &client).await.unwrap();
+    #[test]
+    fn can_parse_batch_write_statuses() {
+        let body = br#"{
+            "writeResults": [{"updateTime": "2020-01-01T00:00:00Z"}, {}, {}],
+            "status": [{}, {"code": 6, "message": "Document already exists"}, {"code": 14}]
+        }"#;
+        let resp: BatchWriteResponse = serde_json::from_slice(body).unwrap();
+        let results: Vec<_> = resp
+            .status
+            .into_iter()
+            .map(RpcStatus::into_result)
+            .collect();
+        assert!(results[0].is_ok());
+        match &results[1] {
+            Err(storage::Error::Conflict(message)) => {
+                assert_eq!(message, "Document already exists")
+            }
+            other => panic!("Expected conflict, got {:?}", other),
+        }
+        match &results[2] {
+            Err(storage::Error::Service(e)) => assert_eq!(e.code, ServiceErrorCode::Unavailable),
+            other => panic!("Expected service error, got {:?}", other),
+        }
     }
 }
@@ -0,0 +1,320 @@
+use crate as storage;
+use hyper::{
+    body::{self, Body, Bytes},
+    client::{Client, HttpConnector},
+    Request, StatusCode,
+};
+use hyper_tls::HttpsConnector;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Deadlines, connection pool settings and circuit breaker thresholds for the HTTP client that
+/// talks to a remote document store
+#[derive(Clone, Debug)]
+pub struct HttpOptions {
+    /// How long to wait for a whole response, including its body, before failing with
+    /// `Error::Timeout`
+    pub request_timeout: Duration,
+    pub connect_timeout: Duration,
+    /// How long an unused pooled connection is kept open
+    pub pool_idle_timeout: Duration,
+    pub pool_max_idle_per_host: usize,
+    /// Consecutive failed requests after which the circuit breaker opens, and requests fail
+    /// immediately with `Error::Unavailable`. 0 disables the circuit breaker.
+    pub circuit_breaker_threshold: u32,
+    /// How long the circuit breaker stays open before letting a single request through to check
+    /// whether the backend has recovered
+    pub circuit_breaker_reset: Duration,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            request_timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            pool_idle_timeout: Duration::from_secs(90),
+            pool_max_idle_per_host: 32,
+            circuit_breaker_threshold: 5,
+            circuit_breaker_reset: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A hyper client that gives up on requests after a deadline, and stops sending requests for a
+/// while once the backend has failed several in a row.
+///
+/// A request counts as failed if it could not be sent, timed out, got a 5xx or 429 response, or
+/// its response body could not be read in full.
+pub struct HttpClient {
+    client: Client<HttpsConnector<HttpConnector>>,
+    request_timeout: Duration,
+    breaker: CircuitBreaker,
+}
+
+impl HttpClient {
+    /// `https_only` refuses to send requests to plain `http` URIs
+    pub fn new(options: &HttpOptions, https_only: bool) -> HttpClient {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(options.connect_timeout));
+        let mut https = HttpsConnector::new_with_connector(http);
+        https.https_only(https_only);
+        let client = Client::builder()
+            .pool_idle_timeout(options.pool_idle_timeout)
+            .pool_max_idle_per_host(options.pool_max_idle_per_host)
+            .build::<_, Body>(https);
+
+        HttpClient {
+            client,
+            request_timeout: options.request_timeout,
+            breaker: CircuitBreaker::new(
+                options.circuit_breaker_threshold,
+                options.circuit_breaker_reset,
+            ),
+        }
+    }
+
+    /// Send `req` and read the whole response body
+    pub async fn send(&self, req: Request<Body>) -> storage::Result<(StatusCode, Bytes)> {
+        let target = format!("{} {}", req.method(), req.uri());
+        if !self.breaker.try_acquire() {
+            return Err(storage::Error::Unavailable(format!(
+                "Not sending {}, circuit breaker is open after repeated failures",
+                target
+            )));
+        }
+
+        let result = tokio::time::timeout(self.request_timeout, async {
+            let resp = self.client.request(req).await?;
+            let status = resp.status();
+            let body_bytes = body::to_bytes(resp.into_body()).await?;
+            Ok((status, body_bytes))
+        })
+        .await
+        .unwrap_or_else(|_| {
+            Err(storage::Error::Timeout(format!(
+                "No response to {} within {:?}",
+                target, self.request_timeout
+            )))
+        });
+
+        match result {
+            Ok((status, _))
+                if !status.is_server_error() && status != StatusCode::TOO_MANY_REQUESTS =>
+            {
+                self.breaker.record_success()
+            }
+            _ => self.breaker.record_failure(),
+        }
+        result
+    }
+}
+
+/// Closed while requests succeed. Opens after `threshold` consecutive failures, and then lets a
+/// single trial request through every `reset_timeout` until one succeeds.
+struct CircuitBreaker {
+    threshold: u32,
+    reset_timeout: Duration,
+    state: Mutex<BreakerState>,
+}
+
+enum BreakerState {
+    Closed { consecutive_failures: u32 },
+    Open { next_trial: Instant },
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, reset_timeout: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            reset_timeout,
+            state: Mutex::new(BreakerState::Closed {
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    /// Whether a request may be sent now
+    fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { ref mut next_trial } => {
+                let now = Instant::now();
+                if now >= *next_trial {
+                    // Hold back everything else until this trial has had time to finish
+                    *next_trial = now + self.reset_timeout;
+                    info!("Circuit breaker is open, sending a trial request");
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let BreakerState::Open { .. } = *state {
+            info!("Trial request succeeded, closing circuit breaker");
+        }
+        *state = BreakerState::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let open = match *state {
+            BreakerState::Closed {
+                ref mut consecutive_failures,
+            } => {
+                *consecutive_failures += 1;
+                if *consecutive_failures >= self.threshold {
+                    warn!(
+                        "Opening circuit breaker for {:?} after {} consecutive failures",
+                        self.reset_timeout, consecutive_failures
+                    );
+                    true
+                } else {
+                    false
+                }
+            }
+            BreakerState::Open { .. } => {
+                warn!(
+                    "Trial request failed, keeping circuit breaker open for {:?}",
+                    self.reset_timeout
+                );
+                true
+            }
+        };
+        if open {
+            *state = BreakerState::Open {
+                next_trial: Instant::now() + self.reset_timeout,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_breaker_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.try_acquire());
+
+        breaker.record_failure();
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn circuit_breaker_lets_one_trial_through_after_reset_timeout() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record_failure();
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record_failure();
+        assert!(!breaker.try_acquire());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+        breaker.record_success();
+        assert!(breaker.try_acquire());
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn circuit_breaker_can_be_disabled() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..100 {
+            breaker.record_failure();
+        }
+        assert!(breaker.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn truncated_bodies_are_retryable_failures() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Promise a longer body than is sent before closing the connection
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{\"truncated\"")
+                    .await;
+            }
+        });
+
+        let options = HttpOptions {
+            circuit_breaker_threshold: 1,
+            circuit_breaker_reset: Duration::from_secs(60),
+            ..HttpOptions::default()
+        };
+        let client = HttpClient::new(&options, false);
+        let request = || {
+            Request::get(format!("http://{}/", addr))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        match client.send(request()).await {
+            Err(e @ storage::Error::Hyper(_)) => assert!(e.is_retryable()),
+            other => panic!("Expected a failed body read, got {:?}", other),
+        }
+        match client.send(request()).await {
+            Err(storage::Error::Unavailable(_)) => (),
+            other => panic!("Expected the circuit breaker to be open, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_time_out_and_then_fail_fast() {
+        // Accept connections but never respond
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let options = HttpOptions {
+            request_timeout: Duration::from_millis(100),
+            circuit_breaker_threshold: 1,
+            circuit_breaker_reset: Duration::from_secs(60),
+            ..HttpOptions::default()
+        };
+        let client = HttpClient::new(&options, false);
+        let request = || {
+            Request::get(format!("http://{}/", addr))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        match client.send(request()).await {
+            Err(e @ storage::Error::Timeout(_)) => assert!(e.is_retryable()),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+        match client.send(request()).await {
+            Err(e @ storage::Error::Unavailable(_)) => assert!(!e.is_retryable()),
+            other => panic!("Expected the circuit breaker to be open, got {:?}", other),
+        }
+    }
+}
@@ -1,8 +1,29 @@
 #[macro_use]
 extern crate log;
 
+pub mod collection;
+pub mod convert;
+pub mod credentials;
+pub mod document_serde;
+pub mod dump;
+pub mod file;
 pub mod firestore;
+pub mod http_client;
+pub mod local;
+pub mod memory;
+pub mod migration;
+pub mod query;
+pub mod retry;
+pub mod sqlite;
+pub mod transform;
+
+mod store;
+pub use store::{
+    BatchWrite, BatchWriteOperation, DocumentStore, Page, Precondition, StoreTransaction,
+    TransactionType,
+};
 
 mod error;
 pub use error::Error;
 pub use error::Result;
+pub use error::{ServiceError, ServiceErrorCode};
@@ -0,0 +1,1374 @@
+use crate as storage;
+use crate::firestore::Document;
+use crate::query::{FieldMask, StructuredQuery};
+use crate::store::{check_batch, convert_document};
+use crate::transform::FieldTransform;
+use crate::{
+    BatchWrite, BatchWriteOperation, DocumentStore, Page, Precondition, StoreTransaction,
+    TransactionType,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    sync::{Arc, Mutex},
+};
+use uuid::Uuid;
+
+/// A `DocumentStore` for backends that keep their documents on this node, such as
+/// `memory::MemoryBackend`.
+///
+/// Documents are named by their path relative to the database root, e.g.
+/// `users/{user_id}/characters/{character_id}`, and collections are addressed with the same
+/// parent path scheme as `FirestoreClient`.
+///
+/// Read-write transactions are optimistic: the update time of every document read through the
+/// transaction is recorded, and the commit fails with `storage::Error::Transaction` if any of them
+/// changed in the meantime. Reads of a single document also see the writes buffered in the
+/// transaction so far. Read-only transactions read from a snapshot taken when they begin.
+///
+/// Queries are evaluated in-process over every document of the collection. A query in a
+/// read-write transaction records the version of each document it scans, but does not detect
+/// documents added to the collection before the commit.
+pub struct LocalStore<B: LocalBackend> {
+    backend: Arc<B>,
+    parent_path: String,
+    collection_id: String,
+}
+
+impl<B: LocalBackend> LocalStore<B> {
+    pub fn new(
+        backend: Arc<B>,
+        collection_parent_path: Option<String>,
+        collection_id: String,
+    ) -> LocalStore<B> {
+        LocalStore {
+            backend,
+            parent_path: collection_parent_path.unwrap_or_default(),
+            collection_id,
+        }
+    }
+
+    fn collection_path(&self) -> String {
+        if self.parent_path.is_empty() {
+            self.collection_id.clone()
+        } else {
+            format!("{}/{}", self.parent_path, self.collection_id)
+        }
+    }
+
+    fn document_name(&self, id: &Uuid) -> String {
+        format!("{}/{}", self.collection_path(), id)
+    }
+
+    async fn read(
+        &self,
+        name: &str,
+        transaction: Option<&LocalTransaction<B>>,
+    ) -> storage::Result<Option<Document>> {
+        match transaction {
+            Some(t) => t.read(name).await,
+            None => self.backend.read(name).await,
+        }
+    }
+
+    async fn read_one<T: TryFrom<Document>>(
+        &self,
+        id: &Uuid,
+        mask: Option<&FieldMask>,
+        transaction: Option<&LocalTransaction<B>>,
+    ) -> storage::Result<Option<T>> {
+        match self.read(&self.document_name(id), transaction).await? {
+            Some(doc) => match apply_mask(doc, mask).try_into() {
+                Ok(ret) => Ok(Some(ret)),
+                Err(_) => Err(storage::Error::Other(
+                    "Failed to convert from Document to requested type.".to_owned(),
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn batch_read<T: TryFrom<Document>>(
+        &self,
+        ids: &[Uuid],
+        mask: Option<&FieldMask>,
+        transaction: Option<&LocalTransaction<B>>,
+    ) -> storage::Result<HashMap<Uuid, Option<T>>> {
+        let mut ret = HashMap::new();
+        for id in ids.iter() {
+            let opt = match self.read(&self.document_name(id), transaction).await? {
+                Some(doc) => match apply_mask(doc, mask).try_into() {
+                    Ok(t) => Some(t),
+                    Err(_) => {
+                        // For now treat conversion error as missing doc
+                        error!("Failed to convert Document to requested type");
+                        None
+                    }
+                },
+                None => None,
+            };
+            ret.insert(*id, opt);
+        }
+        Ok(ret)
+    }
+
+    async fn read_all<T: TryFrom<Document>>(
+        &self,
+        mask: Option<&FieldMask>,
+    ) -> storage::Result<Vec<T>> {
+        let docs = self
+            .backend
+            .read_collection(&self.collection_path())
+            .await?;
+        let mut ret = vec![];
+        for doc in docs.into_iter() {
+            match apply_mask(doc, mask).try_into() {
+                Ok(t) => ret.push(t),
+                Err(_) => error!("Failed to convert from Document to requested type."),
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn write(
+        &self,
+        write: LocalWrite,
+        transaction: Option<&LocalTransaction<B>>,
+    ) -> storage::Result<()> {
+        match transaction {
+            Some(t) => t.append_write(write),
+            None => self.backend.commit(HashMap::new(), vec![write]).await,
+        }
+    }
+}
+
+#[async_trait]
+impl<B: LocalBackend> DocumentStore for LocalStore<B> {
+    type Transaction = LocalTransaction<B>;
+
+    fn subcollection(&self, subcollection_relative_path: String, subcollection_id: String) -> Self {
+        LocalStore {
+            backend: Arc::clone(&self.backend),
+            parent_path: format!("{}/{}", self.collection_path(), subcollection_relative_path),
+            collection_id: subcollection_id,
+        }
+    }
+
+    async fn begin_transaction(
+        &self,
+        transaction_type: TransactionType,
+    ) -> storage::Result<Self::Transaction> {
+        let snapshot = match transaction_type {
+            TransactionType::ReadOnly => Some(self.backend.snapshot().await?),
+            TransactionType::ReadWrite => None,
+        };
+        Ok(LocalTransaction {
+            backend: Arc::clone(&self.backend),
+            snapshot,
+            read_versions: Mutex::new(HashMap::new()),
+            writes: Mutex::new(Some(vec![])),
+        })
+    }
+
+    async fn batch_write(
+        &self,
+        writes: Vec<BatchWrite>,
+    ) -> storage::Result<Vec<storage::Result<()>>> {
+        check_batch(&writes)?;
+        let writes = writes
+            .into_iter()
+            .map(|write| {
+                let name = self.document_name(&write.id);
+                let local_write = match write.operation {
+                    BatchWriteOperation::Upsert(document) => LocalWrite::Set { name, document },
+                    BatchWriteOperation::Transform(transforms) => {
+                        LocalWrite::Transform { name, transforms }
+                    }
+                    BatchWriteOperation::Delete => LocalWrite::Delete { name },
+                };
+                match write.precondition {
+                    Some(precondition) => local_write.with_precondition(precondition),
+                    None => local_write,
+                }
+            })
+            .collect();
+        self.backend.commit_each(writes).await
+    }
+
+    async fn batch_get<T: TryFrom<Document> + Send>(
+        &self,
+        ids: &[Uuid],
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<HashMap<Uuid, Option<T>>> {
+        self.batch_read(ids, None, transaction).await
+    }
+
+    async fn batch_get_masked<T: TryFrom<Document> + Send>(
+        &self,
+        ids: &[Uuid],
+        mask: &FieldMask,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<HashMap<Uuid, Option<T>>> {
+        self.batch_read(ids, Some(mask), transaction).await
+    }
+
+    async fn delete<T: TryFrom<Document>>(
+        &self,
+        id: &Uuid,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()> {
+        let write = LocalWrite::Delete {
+            name: self.document_name(id),
+        };
+        self.write(write, transaction).await
+    }
+
+    async fn delete_recursive(&self, id: &Uuid) -> storage::Result<usize> {
+        // Local backends have no limit on the size of a commit, so everything goes at once
+        let name = self.document_name(id);
+        let mut writes: Vec<LocalWrite> = self
+            .backend
+            .read_descendants(&name)
+            .await?
+            .into_iter()
+            .map(|doc| LocalWrite::Delete { name: doc.name })
+            .collect();
+        let deleted = writes.len();
+        writes.push(LocalWrite::Delete { name });
+        self.backend.commit(HashMap::new(), writes).await?;
+        Ok(deleted)
+    }
+
+    async fn delete_if(
+        &self,
+        id: &Uuid,
+        precondition: Precondition,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()> {
+        let write = LocalWrite::Delete {
+            name: self.document_name(id),
+        };
+        self.write(write.with_precondition(precondition), transaction)
+            .await
+    }
+
+    async fn get<T: TryFrom<Document> + Send>(
+        &self,
+        id: &Uuid,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<Option<T>> {
+        self.read_one(id, None, transaction).await
+    }
+
+    async fn get_masked<T: TryFrom<Document> + Send>(
+        &self,
+        id: &Uuid,
+        mask: &FieldMask,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<Option<T>> {
+        self.read_one(id, Some(mask), transaction).await
+    }
+
+    async fn insert<T: Into<Document> + Send>(&self, id: &Uuid, value: T) -> storage::Result<()> {
+        let write = LocalWrite::Create {
+            name: self.document_name(id),
+            document: value.into(),
+        };
+        self.write(write, None).await
+    }
+
+    async fn list<T: TryFrom<Document> + Send>(&self) -> storage::Result<Vec<T>> {
+        self.read_all(None).await
+    }
+
+    async fn list_masked<T: TryFrom<Document> + Send>(
+        &self,
+        mask: &FieldMask,
+    ) -> storage::Result<Vec<T>> {
+        self.read_all(Some(mask)).await
+    }
+
+    async fn list_page<T: TryFrom<Document> + Send>(
+        &self,
+        page_size: usize,
+        page_token: Option<String>,
+    ) -> storage::Result<Page<T>> {
+        let mut docs = self
+            .backend
+            .read_collection(&self.collection_path())
+            .await?;
+        docs.sort_by(|a, b| a.name.cmp(&b.name));
+        let page_size = page_size.max(1);
+
+        // The token is the name of the last document of the previous page
+        let start = match page_token {
+            Some(token) => docs.partition_point(|doc| doc.name <= token),
+            None => 0,
+        };
+        let mut docs: Vec<_> = docs.into_iter().skip(start).collect();
+        let next_page_token = if docs.len() > page_size {
+            docs.truncate(page_size);
+            docs.last().map(|doc| doc.name.clone())
+        } else {
+            None
+        };
+
+        let mut items = vec![];
+        for doc in docs.into_iter() {
+            items.push(convert_document(doc)?);
+        }
+        Ok(Page {
+            items,
+            next_page_token,
+        })
+    }
+
+    async fn run_query<T: TryFrom<Document> + Send>(
+        &self,
+        query: StructuredQuery,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<Vec<T>> {
+        let collection_path = self.collection_path();
+        let docs = match transaction {
+            Some(t) => t.read_collection(&collection_path).await?,
+            None => self.backend.read_collection(&collection_path).await?,
+        };
+        let mut ret = vec![];
+        for doc in query.apply(docs).into_iter() {
+            match doc.try_into() {
+                Ok(t) => ret.push(t),
+                Err(_) => error!("Failed to convert from Document to requested type."),
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn run_collection_group_query<T: TryFrom<Document> + Send>(
+        &self,
+        collection_id: &str,
+        query: StructuredQuery,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<Vec<T>> {
+        let docs = match transaction {
+            Some(t) => {
+                t.read_collection_group(&self.parent_path, collection_id)
+                    .await?
+            }
+            None => {
+                self.backend
+                    .read_collection_group(&self.parent_path, collection_id)
+                    .await?
+            }
+        };
+        let mut ret = vec![];
+        for doc in query.apply(docs).into_iter() {
+            match doc.try_into() {
+                Ok(t) => ret.push(t),
+                Err(_) => error!("Failed to convert from Document to requested type."),
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn transform(
+        &self,
+        id: &Uuid,
+        transforms: Vec<FieldTransform>,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()> {
+        let write = LocalWrite::Transform {
+            name: self.document_name(id),
+            transforms,
+        };
+        self.write(write, transaction).await
+    }
+
+    async fn transform_if(
+        &self,
+        id: &Uuid,
+        transforms: Vec<FieldTransform>,
+        precondition: Precondition,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()> {
+        let write = LocalWrite::Transform {
+            name: self.document_name(id),
+            transforms,
+        };
+        self.write(write.with_precondition(precondition), transaction)
+            .await
+    }
+
+    async fn upsert<T: Into<Document> + Send>(
+        &self,
+        id: &Uuid,
+        value: T,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()> {
+        let write = LocalWrite::Set {
+            name: self.document_name(id),
+            document: value.into(),
+        };
+        self.write(write, transaction).await
+    }
+
+    async fn upsert_if<T: Into<Document> + Send>(
+        &self,
+        id: &Uuid,
+        value: T,
+        precondition: Precondition,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()> {
+        let write = LocalWrite::Set {
+            name: self.document_name(id),
+            document: value.into(),
+        };
+        self.write(write.with_precondition(precondition), transaction)
+            .await
+    }
+}
+
+/// Read access to the documents held by a `LocalBackend`, or to a snapshot of them.
+#[async_trait]
+pub trait LocalReader: Send + Sync {
+    /// Read a single document by name
+    async fn read(&self, name: &str) -> storage::Result<Option<Document>>;
+
+    /// Read all documents directly inside a collection, ordered by name
+    async fn read_collection(&self, collection_path: &str) -> storage::Result<Vec<Document>>;
+
+    /// Read all documents inside any collection with id `collection_id` nested anywhere under
+    /// `parent_path`, ordered by name. An empty `parent_path` is the database root.
+    async fn read_collection_group(
+        &self,
+        parent_path: &str,
+        collection_id: &str,
+    ) -> storage::Result<Vec<Document>>;
+
+    /// Read every document nested under the document `name`, in any of its subcollections at any
+    /// depth, ordered by name
+    async fn read_descendants(&self, name: &str) -> storage::Result<Vec<Document>>;
+}
+
+/// The storage primitives a backend must provide to be used through a `LocalStore`.
+#[async_trait]
+pub trait LocalBackend: LocalReader + 'static {
+    type Snapshot: LocalReader + 'static;
+
+    /// Take a consistent view of every document, for read-only transactions
+    async fn snapshot(&self) -> storage::Result<Self::Snapshot>;
+
+    /// Atomically apply `writes`, provided that each document in `expected_versions` still has the
+    /// recorded update time (`None` meaning the document did not exist).
+    ///
+    /// Fails with `storage::Error::Transaction` if any expectation is not met, or with
+    /// `storage::Error::Conflict` if a `LocalWrite::Create` targets an existing document or the
+    /// precondition of a `LocalWrite::Conditional` does not hold.
+    async fn commit(
+        &self,
+        expected_versions: HashMap<String, Option<String>>,
+        writes: Vec<LocalWrite>,
+    ) -> storage::Result<()>;
+
+    /// Apply each of `writes` on its own, returning their outcomes in order, for
+    /// `DocumentStore::batch_write`. By default every write is a separate `commit`.
+    async fn commit_each(
+        &self,
+        writes: Vec<LocalWrite>,
+    ) -> storage::Result<Vec<storage::Result<()>>> {
+        let mut results = Vec::with_capacity(writes.len());
+        for write in writes.into_iter() {
+            results.push(self.commit(HashMap::new(), vec![write]).await);
+        }
+        Ok(results)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum LocalWrite {
+    Create {
+        name: String,
+        document: Document,
+    },
+    Set {
+        name: String,
+        document: Document,
+    },
+    Transform {
+        name: String,
+        transforms: Vec<FieldTransform>,
+    },
+    Delete {
+        name: String,
+    },
+    /// Another write that is only applied if `precondition` holds
+    Conditional {
+        precondition: Precondition,
+        write: Box<LocalWrite>,
+    },
+}
+
+impl LocalWrite {
+    pub fn name(&self) -> &str {
+        match self {
+            LocalWrite::Create { name, .. } => name,
+            LocalWrite::Set { name, .. } => name,
+            LocalWrite::Transform { name, .. } => name,
+            LocalWrite::Delete { name } => name,
+            LocalWrite::Conditional { write, .. } => write.name(),
+        }
+    }
+
+    pub fn with_precondition(self, precondition: Precondition) -> LocalWrite {
+        LocalWrite::Conditional {
+            precondition,
+            write: Box::new(self),
+        }
+    }
+
+    /// Compute the new state of the document targeted by this write, given its current state.
+    /// Returns `None` if the document should no longer exist.
+    pub fn apply(
+        self,
+        existing: Option<&Document>,
+        commit_time: &str,
+    ) -> storage::Result<Option<Document>> {
+        match self {
+            LocalWrite::Create { name, document } => match existing {
+                Some(_) => Err(storage::Error::Conflict(format!(
+                    "Could not create document '{}' as it already exists",
+                    name
+                ))),
+                None => Ok(Some(stamp(document, name, commit_time, None))),
+            },
+            LocalWrite::Set { name, document } => Ok(Some(stamp(
+                document,
+                name,
+                commit_time,
+                existing.map(|doc| doc.create_time.clone()),
+            ))),
+            LocalWrite::Transform { name, transforms } => {
+                let mut document = existing
+                    .cloned()
+                    .unwrap_or_else(|| Document::new(HashMap::new()));
+                let request_time = DateTime::parse_from_rfc3339(commit_time)
+                    .map_or_else(|_| Utc::now(), |t| t.with_timezone(&Utc));
+                for transform in transforms.iter() {
+                    transform.apply(&mut document, request_time);
+                }
+                Ok(Some(stamp(
+                    document,
+                    name,
+                    commit_time,
+                    existing.map(|doc| doc.create_time.clone()),
+                )))
+            }
+            LocalWrite::Delete { .. } => Ok(None),
+            LocalWrite::Conditional {
+                precondition,
+                write,
+            } => {
+                precondition.check(write.name(), existing)?;
+                write.apply(existing, commit_time)
+            }
+        }
+    }
+
+    /// Like `apply`, but without checking preconditions or that a created document is new. Those
+    /// are only known to hold once the write is committed.
+    fn apply_unchecked(self, existing: Option<&Document>, commit_time: &str) -> Option<Document> {
+        let write = match self {
+            LocalWrite::Conditional { write, .. } => {
+                return write.apply_unchecked(existing, commit_time)
+            }
+            LocalWrite::Create { name, document } => LocalWrite::Set { name, document },
+            write => write,
+        };
+        write.apply(existing, commit_time).ok().flatten()
+    }
+}
+
+/// Check the versions recorded by a read-write transaction against the current documents
+pub fn check_versions<F>(
+    expected_versions: &HashMap<String, Option<String>>,
+    mut current: F,
+) -> storage::Result<()>
+where
+    F: FnMut(&str) -> storage::Result<Option<String>>,
+{
+    for (name, expected) in expected_versions.iter() {
+        if current(name)? != *expected {
+            debug!("Document {} changed since it was read in transaction", name);
+            return Err(storage::Error::Transaction(
+                "Document contention, try again later".to_owned(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the document named `name` is in a collection with id `collection_id` nested anywhere
+/// under `parent_path`, for backends implementing `LocalReader::read_collection_group`
+pub fn in_collection_group(name: &str, parent_path: &str, collection_id: &str) -> bool {
+    let under_parent = parent_path.is_empty()
+        || (name.starts_with(parent_path) && name[parent_path.len()..].starts_with('/'));
+    let mut segments = name.rsplit('/');
+    segments.next();
+    under_parent && segments.next() == Some(collection_id)
+}
+
+/// Generate the update time for a commit. Update times are strictly increasing within this
+/// process, so they can double as document versions.
+pub fn next_commit_time() -> String {
+    static LAST_COMMIT_TIME: Mutex<Option<DateTime<Utc>>> = Mutex::new(None);
+
+    let mut last_commit_time = LAST_COMMIT_TIME.lock().expect("Poisoned lock");
+    let mut commit_time = Utc::now();
+    if let Some(last) = *last_commit_time {
+        if commit_time <= last {
+            commit_time = last + chrono::Duration::nanoseconds(1);
+        }
+    }
+    *last_commit_time = Some(commit_time);
+    commit_time.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+/// Run blocking storage work, such as file or database IO, on the blocking thread pool
+pub async fn run_blocking<T, F>(f: F) -> storage::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> storage::Result<T> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(ret) => ret,
+        Err(e) => Err(storage::Error::Other(format!(
+            "Storage task failed to complete: {}",
+            e
+        ))),
+    }
+}
+
+fn apply_mask(doc: Document, mask: Option<&FieldMask>) -> Document {
+    match mask {
+        Some(mask) => mask.apply(doc),
+        None => doc,
+    }
+}
+
+fn stamp(
+    mut document: Document,
+    name: String,
+    commit_time: &str,
+    create_time: Option<String>,
+) -> Document {
+    document.name = name;
+    document.create_time = create_time.unwrap_or_else(|| commit_time.to_owned());
+    document.update_time = commit_time.to_owned();
+    document
+}
+
+pub struct LocalTransaction<B: LocalBackend> {
+    backend: Arc<B>,
+    snapshot: Option<B::Snapshot>,
+    read_versions: Mutex<HashMap<String, Option<String>>>,
+    writes: Mutex<Option<Vec<LocalWrite>>>,
+}
+
+impl<B: LocalBackend> LocalTransaction<B> {
+    async fn read(&self, name: &str) -> storage::Result<Option<Document>> {
+        match self.snapshot {
+            Some(ref snapshot) => snapshot.read(name).await,
+            None => {
+                let doc = self.backend.read(name).await?;
+                self.read_versions
+                    .lock()
+                    .expect("Poisoned lock")
+                    .entry(name.to_owned())
+                    .or_insert_with(|| doc.as_ref().map(|d| d.update_time.clone()));
+                Ok(self.apply_pending_writes(name, doc))
+            }
+        }
+    }
+
+    /// Apply the writes buffered so far for the document `name` over its committed state, so
+    /// that reads in the transaction see its own writes
+    fn apply_pending_writes(&self, name: &str, mut doc: Option<Document>) -> Option<Document> {
+        if let Some(writes) = self.writes.lock().expect("Poisoned lock").as_ref() {
+            let pending_time = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+            for write in writes.iter().filter(|w| w.name() == name) {
+                doc = write.clone().apply_unchecked(doc.as_ref(), &pending_time);
+            }
+        }
+        doc
+    }
+
+    async fn read_collection(&self, collection_path: &str) -> storage::Result<Vec<Document>> {
+        match self.snapshot {
+            Some(ref snapshot) => snapshot.read_collection(collection_path).await,
+            None => {
+                let docs = self.backend.read_collection(collection_path).await?;
+                self.record_versions(&docs);
+                Ok(docs)
+            }
+        }
+    }
+
+    async fn read_collection_group(
+        &self,
+        parent_path: &str,
+        collection_id: &str,
+    ) -> storage::Result<Vec<Document>> {
+        match self.snapshot {
+            Some(ref snapshot) => {
+                snapshot
+                    .read_collection_group(parent_path, collection_id)
+                    .await
+            }
+            None => {
+                let docs = self
+                    .backend
+                    .read_collection_group(parent_path, collection_id)
+                    .await?;
+                self.record_versions(&docs);
+                Ok(docs)
+            }
+        }
+    }
+
+    fn record_versions(&self, docs: &[Document]) {
+        let mut read_versions = self.read_versions.lock().expect("Poisoned lock");
+        for doc in docs.iter() {
+            read_versions
+                .entry(doc.name.clone())
+                .or_insert_with(|| Some(doc.update_time.clone()));
+        }
+    }
+
+    fn append_write(&self, write: LocalWrite) -> storage::Result<()> {
+        if self.snapshot.is_some() {
+            return Err(storage::Error::Transaction(
+                "Cannot write in a read-only transaction".to_owned(),
+            ));
+        }
+        match self.writes.lock().expect("Poisoned lock").as_mut() {
+            Some(v) => {
+                v.push(write);
+                Ok(())
+            }
+            None => Err(storage::Error::Other(
+                "Transaction is no longer valid".to_owned(),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl<B: LocalBackend> StoreTransaction for LocalTransaction<B> {
+    async fn abort(self) {
+        self.writes.lock().expect("Poisoned lock").take();
+    }
+
+    async fn rollback(self) -> storage::Result<()> {
+        match self.writes.lock().expect("Poisoned lock").take() {
+            Some(_) => Ok(()),
+            None => Err(storage::Error::Other(
+                "Transaction is no longer valid".to_owned(),
+            )),
+        }
+    }
+
+    async fn commit(self) -> storage::Result<()> {
+        let writes = self.writes.lock().expect("Poisoned lock").take();
+        match writes {
+            Some(writes) if self.snapshot.is_none() => {
+                let expected_versions = self.read_versions.into_inner().expect("Poisoned lock");
+                self.backend.commit(expected_versions, writes).await
+            }
+            Some(_) => Ok(()),
+            None => {
+                warn!("Attempted to commit a transaction that is in an invalid state.");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    //! Tests shared by every `LocalBackend`. Each backend's own tests pick the ones that apply
+    //! with `local_backend_tests!`.
+
+    use super::*;
+    use crate::firestore::DocumentField;
+    use crate::query::{Direction, Filter, DOCUMENT_NAME_FIELD};
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) struct Counter {
+        pub(crate) id: Uuid,
+        pub(crate) count: i64,
+    }
+
+    impl TryFrom<Document> for Counter {
+        type Error = String;
+
+        fn try_from(value: Document) -> Result<Self, Self::Error> {
+            Ok(Counter {
+                id: value.extract_id()?,
+                count: value.extract_integer("count")?,
+            })
+        }
+    }
+
+    impl From<Counter> for Document {
+        fn from(value: Counter) -> Self {
+            let mut fields = HashMap::new();
+            fields.insert(
+                "count".to_owned(),
+                DocumentField::IntegerValue(value.count.to_string()),
+            );
+            Document::new(fields)
+        }
+    }
+
+    /// Generate a test for each of the named tests in this module, run against the backend
+    /// returned by `$backend`, an async fn that also returns anything the backend needs kept
+    /// alive, like its directory
+    macro_rules! local_backend_tests {
+        ($backend:path; $($test:ident),* $(,)?) => {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    let (backend, _guard) = $backend().await;
+                    crate::local::tests::$test(backend).await;
+                }
+            )*
+        };
+    }
+    pub(crate) use local_backend_tests;
+
+    fn counters<B: LocalBackend>(backend: &Arc<B>) -> LocalStore<B> {
+        LocalStore::new(Arc::clone(backend), None, "counters".to_owned())
+    }
+
+    pub(crate) async fn can_upsert_then_get<B: LocalBackend>(backend: Arc<B>) {
+        let store = counters(&backend);
+        let counter = Counter {
+            id: Uuid::new_v4(),
+            count: 3,
+        };
+        store
+            .upsert(&counter.id, counter.clone(), None)
+            .await
+            .unwrap();
+        let ret = store.get::<Counter>(&counter.id, None).await.unwrap();
+        assert_eq!(ret, Some(counter));
+    }
+
+    pub(crate) async fn insert_conflicts_with_existing_document<B: LocalBackend>(backend: Arc<B>) {
+        let store = counters(&backend);
+        let counter = Counter {
+            id: Uuid::new_v4(),
+            count: 0,
+        };
+        store.insert(&counter.id, counter.clone()).await.unwrap();
+        let id = counter.id;
+        match store.insert(&id, counter).await {
+            Err(storage::Error::Conflict(_)) => (),
+            other => panic!("Expected conflict, got {:?}", other),
+        }
+    }
+
+    pub(crate) async fn list_only_includes_direct_children<B: LocalBackend>(backend: Arc<B>) {
+        let store = counters(&backend);
+        let parent = Counter {
+            id: Uuid::new_v4(),
+            count: 1,
+        };
+        store
+            .upsert(&parent.id, parent.clone(), None)
+            .await
+            .unwrap();
+        let sub_store = store.subcollection(parent.id.to_string(), "counters".to_owned());
+        let child = Counter {
+            id: Uuid::new_v4(),
+            count: 2,
+        };
+        sub_store
+            .upsert(&child.id, child.clone(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(store.list::<Counter>().await.unwrap(), vec![parent]);
+        assert_eq!(sub_store.list::<Counter>().await.unwrap(), vec![child]);
+    }
+
+    pub(crate) async fn can_list_in_pages<B: LocalBackend>(backend: Arc<B>) {
+        let store = counters(&backend);
+        let mut ids = vec![];
+        for count in 0..5 {
+            let counter = Counter {
+                id: Uuid::new_v4(),
+                count,
+            };
+            ids.push(counter.id);
+            store.insert(&counter.id, counter.clone()).await.unwrap();
+        }
+        ids.sort_by_key(|id| id.to_string());
+
+        let mut listed = vec![];
+        let mut page_token = None;
+        loop {
+            let page = store.list_page::<Counter>(2, page_token).await.unwrap();
+            assert!(page.items.len() <= 2);
+            listed.extend(page.items.into_iter().map(|c| c.id));
+            page_token = page.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+        assert_eq!(listed, ids);
+
+        // A document that fails to convert fails the page rather than being dropped
+        store
+            .upsert(&Uuid::new_v4(), Document::new(HashMap::new()), None)
+            .await
+            .unwrap();
+        assert!(store.list_page::<Counter>(10, None).await.is_err());
+    }
+
+    pub(crate) async fn can_query_collection_group<B: LocalBackend>(backend: Arc<B>) {
+        let users = LocalStore::new(Arc::clone(&backend), None, "users".to_owned());
+        let user_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for (i, user_id) in user_ids.iter().enumerate() {
+            let counters = users.subcollection(user_id.to_string(), "counters".to_owned());
+            for count in 0..3 {
+                let counter = Counter {
+                    id: Uuid::new_v4(),
+                    count: count + 10 * i as i64,
+                };
+                counters.insert(&counter.id, counter.clone()).await.unwrap();
+            }
+        }
+        // Top-level collections with the same id are part of the group, others are not
+        let counter = Counter {
+            id: Uuid::new_v4(),
+            count: 100,
+        };
+        LocalStore::new(Arc::clone(&backend), None, "counters".to_owned())
+            .insert(&counter.id, counter.clone())
+            .await
+            .unwrap();
+        users
+            .subcollection(user_ids[0].to_string(), "others".to_owned())
+            .insert(&counter.id, counter.clone())
+            .await
+            .unwrap();
+
+        let query = StructuredQuery::new()
+            .filter(Filter::greater_than_or_equal(
+                "count",
+                DocumentField::IntegerValue("1".to_owned()),
+            ))
+            .order_by("count", Direction::Ascending);
+        let counts: Vec<i64> = users
+            .run_collection_group_query::<Counter>("counters", query.clone(), None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.count)
+            .collect();
+        assert_eq!(counts, vec![1, 2, 10, 11, 12, 100]);
+
+        // Queries from a document only see the group below it
+        let counts: Vec<i64> = users
+            .subcollection(user_ids[1].to_string(), "counters".to_owned())
+            .run_collection_group_query::<Counter>("counters", query, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.count)
+            .collect();
+        assert_eq!(counts, vec![10, 11, 12]);
+    }
+
+    pub(crate) async fn can_delete_recursively<B: LocalBackend>(backend: Arc<B>) {
+        let users = LocalStore::new(Arc::clone(&backend), None, "users".to_owned());
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for id in ids.iter() {
+            users
+                .insert(id, Counter { id: *id, count: 0 })
+                .await
+                .unwrap();
+            let counters = users.subcollection(id.to_string(), "counters".to_owned());
+            counters
+                .insert(id, Counter { id: *id, count: 1 })
+                .await
+                .unwrap();
+            counters
+                .subcollection(id.to_string(), "nested".to_owned())
+                .insert(id, Counter { id: *id, count: 2 })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(users.delete_recursive(&ids[0]).await.unwrap(), 2);
+        assert_eq!(users.get::<Counter>(&ids[0], None).await.unwrap(), None);
+        let remaining: Vec<Uuid> = users
+            .run_collection_group_query::<Counter>("nested", StructuredQuery::new(), None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(remaining, vec![ids[1]]);
+        assert_eq!(users.list_ids().await.unwrap(), vec![ids[1]]);
+    }
+
+    pub(crate) async fn masked_reads_only_return_masked_fields<B: LocalBackend>(backend: Arc<B>) {
+        let store = counters(&backend);
+        let counter = Counter {
+            id: Uuid::new_v4(),
+            count: 4,
+        };
+        store
+            .upsert(&counter.id, counter.clone(), None)
+            .await
+            .unwrap();
+
+        let mask = FieldMask::new(&[DOCUMENT_NAME_FIELD]);
+        let doc = store
+            .get_masked::<Document>(&counter.id, &mask, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc.extract_id(), Ok(counter.id));
+        assert!(doc.fields.is_empty());
+        let docs = store.list_masked::<Document>(&mask).await.unwrap();
+        assert!(docs.iter().all(|d| d.fields.is_empty()));
+        let ret = store
+            .batch_get_masked::<Counter>(&[counter.id], &FieldMask::new(&["count"]), None)
+            .await
+            .unwrap();
+        assert_eq!(ret[&counter.id], Some(counter.clone()));
+        assert_eq!(store.list_ids().await.unwrap(), vec![counter.id]);
+    }
+
+    pub(crate) async fn concurrent_read_write_transactions_conflict<B: LocalBackend>(
+        backend: Arc<B>,
+    ) {
+        let store = counters(&backend);
+        let id = Uuid::new_v4();
+        store
+            .upsert(&id, Counter { id, count: 0 }, None)
+            .await
+            .unwrap();
+
+        let t1 = store
+            .begin_transaction(TransactionType::ReadWrite)
+            .await
+            .unwrap();
+        let t2 = store
+            .begin_transaction(TransactionType::ReadWrite)
+            .await
+            .unwrap();
+        let mut c1 = store.get::<Counter>(&id, Some(&t1)).await.unwrap().unwrap();
+        let mut c2 = store.get::<Counter>(&id, Some(&t2)).await.unwrap().unwrap();
+        c1.count += 1;
+        c2.count += 1;
+        store.upsert(&id, c1, Some(&t1)).await.unwrap();
+        store.upsert(&id, c2, Some(&t2)).await.unwrap();
+
+        t1.commit().await.unwrap();
+        match t2.commit().await {
+            Err(storage::Error::Transaction(_)) => (),
+            other => panic!("Expected transaction error, got {:?}", other),
+        }
+        let ret = store.get::<Counter>(&id, None).await.unwrap().unwrap();
+        assert_eq!(ret.count, 1);
+    }
+
+    pub(crate) async fn concurrent_transforms_do_not_conflict<B: LocalBackend>(backend: Arc<B>) {
+        let store = counters(&backend);
+        let id = Uuid::new_v4();
+        let increment = || {
+            vec![FieldTransform::increment(
+                "count",
+                DocumentField::IntegerValue("2".to_owned()),
+            )]
+        };
+
+        let t1 = store
+            .begin_transaction(TransactionType::ReadWrite)
+            .await
+            .unwrap();
+        let t2 = store
+            .begin_transaction(TransactionType::ReadWrite)
+            .await
+            .unwrap();
+        store.transform(&id, increment(), Some(&t1)).await.unwrap();
+        store.transform(&id, increment(), Some(&t2)).await.unwrap();
+        t1.commit().await.unwrap();
+        t2.commit().await.unwrap();
+        store.transform(&id, increment(), None).await.unwrap();
+
+        let ret = store.get::<Counter>(&id, None).await.unwrap().unwrap();
+        assert_eq!(ret.count, 6);
+    }
+
+    pub(crate) async fn writes_check_preconditions<B: LocalBackend>(backend: Arc<B>) {
+        let store = counters(&backend);
+        let id = Uuid::new_v4();
+        let increment = vec![FieldTransform::increment(
+            "count",
+            DocumentField::IntegerValue("1".to_owned()),
+        )];
+        match store
+            .transform_if(&id, increment.clone(), Precondition::Exists(true), None)
+            .await
+        {
+            Err(storage::Error::Conflict(_)) => (),
+            other => panic!("Expected conflict, got {:?}", other),
+        }
+        match store
+            .upsert_if(
+                &id,
+                Counter { id, count: 1 },
+                Precondition::Exists(true),
+                None,
+            )
+            .await
+        {
+            Err(storage::Error::Conflict(_)) => (),
+            other => panic!("Expected conflict, got {:?}", other),
+        }
+        store
+            .upsert_if(
+                &id,
+                Counter { id, count: 1 },
+                Precondition::Exists(false),
+                None,
+            )
+            .await
+            .unwrap();
+
+        // Compare-and-swap on the update time of the document that was read
+        let doc = store.get::<Document>(&id, None).await.unwrap().unwrap();
+        let read_update_time = Precondition::UpdateTime(doc.update_time);
+        store
+            .upsert_if(
+                &id,
+                Counter { id, count: 2 },
+                read_update_time.clone(),
+                None,
+            )
+            .await
+            .unwrap();
+        match store
+            .upsert_if(
+                &id,
+                Counter { id, count: 3 },
+                read_update_time.clone(),
+                None,
+            )
+            .await
+        {
+            Err(storage::Error::Conflict(_)) => (),
+            other => panic!("Expected conflict, got {:?}", other),
+        }
+
+        // Inside a transaction the precondition is checked on commit
+        let t = store
+            .begin_transaction(TransactionType::ReadWrite)
+            .await
+            .unwrap();
+        store
+            .delete_if(&id, read_update_time, Some(&t))
+            .await
+            .unwrap();
+        match t.commit().await {
+            Err(storage::Error::Conflict(_)) => (),
+            other => panic!("Expected conflict, got {:?}", other),
+        }
+        let ret = store.get::<Counter>(&id, None).await.unwrap().unwrap();
+        assert_eq!(ret.count, 2);
+    }
+
+    pub(crate) async fn batch_writes_report_each_outcome<B: LocalBackend>(backend: Arc<B>) {
+        let store = counters(&backend);
+        let ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        store
+            .insert(
+                &ids[1],
+                Counter {
+                    id: ids[1],
+                    count: 1,
+                },
+            )
+            .await
+            .unwrap();
+        store
+            .insert(
+                &ids[2],
+                Counter {
+                    id: ids[2],
+                    count: 2,
+                },
+            )
+            .await
+            .unwrap();
+
+        let results = store
+            .batch_write(vec![
+                BatchWrite::insert(
+                    ids[0],
+                    Counter {
+                        id: ids[0],
+                        count: 0,
+                    },
+                ),
+                BatchWrite::insert(
+                    ids[1],
+                    Counter {
+                        id: ids[1],
+                        count: 10,
+                    },
+                ),
+                BatchWrite::transform(
+                    ids[2],
+                    vec![FieldTransform::increment(
+                        "count",
+                        DocumentField::IntegerValue("3".to_owned()),
+                    )],
+                ),
+                BatchWrite::delete(ids[3]).with_precondition(Precondition::Exists(true)),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_ok());
+        match &results[1] {
+            Err(storage::Error::Conflict(_)) => (),
+            other => panic!("Expected conflict, got {:?}", other),
+        }
+        assert!(results[2].is_ok());
+        match &results[3] {
+            Err(storage::Error::Conflict(_)) => (),
+            other => panic!("Expected conflict, got {:?}", other),
+        }
+
+        let ret = store.batch_get::<Counter>(&ids, None).await.unwrap();
+        assert_eq!(
+            ret[&ids[0]],
+            Some(Counter {
+                id: ids[0],
+                count: 0
+            })
+        );
+        assert_eq!(
+            ret[&ids[1]],
+            Some(Counter {
+                id: ids[1],
+                count: 1
+            })
+        );
+        assert_eq!(
+            ret[&ids[2]],
+            Some(Counter {
+                id: ids[2],
+                count: 5
+            })
+        );
+        assert_eq!(ret[&ids[3]], None);
+
+        match store
+            .batch_write(vec![BatchWrite::delete(ids[0]), BatchWrite::delete(ids[0])])
+            .await
+        {
+            Err(storage::Error::Other(_)) => (),
+            other => panic!("Expected duplicate writes to be rejected, got {:?}", other),
+        }
+        assert!(store.get::<Counter>(&ids[0], None).await.unwrap().is_some());
+    }
+
+    pub(crate) async fn transactions_read_their_own_writes<B: LocalBackend>(backend: Arc<B>) {
+        let store = counters(&backend);
+        let id = Uuid::new_v4();
+        let other_id = Uuid::new_v4();
+        store
+            .upsert(&id, Counter { id, count: 1 }, None)
+            .await
+            .unwrap();
+
+        let t = store
+            .begin_transaction(TransactionType::ReadWrite)
+            .await
+            .unwrap();
+        store
+            .upsert(&id, Counter { id, count: 2 }, Some(&t))
+            .await
+            .unwrap();
+        store
+            .transform(
+                &id,
+                vec![FieldTransform::increment(
+                    "count",
+                    DocumentField::IntegerValue("3".to_owned()),
+                )],
+                Some(&t),
+            )
+            .await
+            .unwrap();
+        store
+            .upsert(
+                &other_id,
+                Counter {
+                    id: other_id,
+                    count: 7,
+                },
+                Some(&t),
+            )
+            .await
+            .unwrap();
+        store.delete::<Counter>(&other_id, Some(&t)).await.unwrap();
+
+        let ret = store
+            .batch_get::<Counter>(&[id, other_id], Some(&t))
+            .await
+            .unwrap();
+        assert_eq!(ret[&id], Some(Counter { id, count: 5 }));
+        assert_eq!(ret[&other_id], None);
+        // Nothing is visible outside the transaction until it is committed
+        let ret = store.get::<Counter>(&id, None).await.unwrap().unwrap();
+        assert_eq!(ret.count, 1);
+
+        t.rollback().await.unwrap();
+        let ret = store.get::<Counter>(&id, None).await.unwrap().unwrap();
+        assert_eq!(ret.count, 1);
+    }
+
+    pub(crate) async fn read_only_transaction_reads_snapshot<B: LocalBackend>(backend: Arc<B>) {
+        let store = counters(&backend);
+        let id = Uuid::new_v4();
+        store
+            .upsert(&id, Counter { id, count: 0 }, None)
+            .await
+            .unwrap();
+
+        let t = store
+            .begin_transaction(TransactionType::ReadOnly)
+            .await
+            .unwrap();
+        store
+            .upsert(&id, Counter { id, count: 5 }, None)
+            .await
+            .unwrap();
+        let ret = store.get::<Counter>(&id, Some(&t)).await.unwrap().unwrap();
+        assert_eq!(ret.count, 0);
+        assert!(store.delete::<Counter>(&id, Some(&t)).await.is_err());
+        t.commit().await.unwrap();
+
+        let ret = store.get::<Counter>(&id, None).await.unwrap().unwrap();
+        assert_eq!(ret.count, 5);
+    }
+}
@@ -0,0 +1,191 @@
+use crate as storage;
+use crate::firestore::Document;
+use crate::local::{self, LocalBackend, LocalReader, LocalStore, LocalWrite};
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+};
+
+/// A `DocumentStore` that keeps every document in memory. Nothing is persisted, which makes it
+/// suitable for tests and throwaway servers.
+pub type MemoryStore = LocalStore<MemoryBackend>;
+
+type DocumentMap = BTreeMap<String, Document>;
+
+/// Documents keyed by their full name. The map is copied on write while a read-only transaction
+/// holds a snapshot of it, so snapshots are cheap to take.
+#[derive(Default)]
+pub struct MemoryBackend {
+    documents: RwLock<Arc<DocumentMap>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    fn current(&self) -> Arc<DocumentMap> {
+        Arc::clone(&self.documents.read().expect("Poisoned lock"))
+    }
+}
+
+#[async_trait]
+impl LocalReader for MemoryBackend {
+    async fn read(&self, name: &str) -> storage::Result<Option<Document>> {
+        Ok(self.current().get(name).cloned())
+    }
+
+    async fn read_collection(&self, collection_path: &str) -> storage::Result<Vec<Document>> {
+        Ok(read_collection(&self.current(), collection_path))
+    }
+
+    async fn read_collection_group(
+        &self,
+        parent_path: &str,
+        collection_id: &str,
+    ) -> storage::Result<Vec<Document>> {
+        Ok(read_collection_group(
+            &self.current(),
+            parent_path,
+            collection_id,
+        ))
+    }
+
+    async fn read_descendants(&self, name: &str) -> storage::Result<Vec<Document>> {
+        Ok(read_descendants(&self.current(), name))
+    }
+}
+
+#[async_trait]
+impl LocalBackend for MemoryBackend {
+    type Snapshot = MemorySnapshot;
+
+    async fn snapshot(&self) -> storage::Result<Self::Snapshot> {
+        Ok(MemorySnapshot(self.current()))
+    }
+
+    async fn commit(
+        &self,
+        expected_versions: HashMap<String, Option<String>>,
+        writes: Vec<LocalWrite>,
+    ) -> storage::Result<()> {
+        let mut guard = self.documents.write().expect("Poisoned lock");
+        local::check_versions(&expected_versions, |name| {
+            Ok(guard.get(name).map(|doc| doc.update_time.clone()))
+        })?;
+
+        // Work out every change before applying any, so that a failed write leaves the store
+        // untouched
+        let commit_time = local::next_commit_time();
+        let mut changes: HashMap<String, Option<Document>> = HashMap::new();
+        for write in writes.into_iter() {
+            let name = write.name().to_owned();
+            let existing = match changes.get(&name) {
+                Some(change) => change.as_ref(),
+                None => guard.get(&name),
+            };
+            let change = write.apply(existing, &commit_time)?;
+            changes.insert(name, change);
+        }
+
+        let documents = Arc::make_mut(&mut guard);
+        for (name, change) in changes.into_iter() {
+            match change {
+                Some(doc) => documents.insert(name, doc),
+                None => documents.remove(&name),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// The state of a `MemoryBackend` at the time a read-only transaction began
+pub struct MemorySnapshot(Arc<DocumentMap>);
+
+#[async_trait]
+impl LocalReader for MemorySnapshot {
+    async fn read(&self, name: &str) -> storage::Result<Option<Document>> {
+        Ok(self.0.get(name).cloned())
+    }
+
+    async fn read_collection(&self, collection_path: &str) -> storage::Result<Vec<Document>> {
+        Ok(read_collection(&self.0, collection_path))
+    }
+
+    async fn read_collection_group(
+        &self,
+        parent_path: &str,
+        collection_id: &str,
+    ) -> storage::Result<Vec<Document>> {
+        Ok(read_collection_group(&self.0, parent_path, collection_id))
+    }
+
+    async fn read_descendants(&self, name: &str) -> storage::Result<Vec<Document>> {
+        Ok(read_descendants(&self.0, name))
+    }
+}
+
+fn read_collection(documents: &DocumentMap, collection_path: &str) -> Vec<Document> {
+    let prefix = format!("{}/", collection_path);
+    documents
+        .range(prefix.clone()..)
+        .take_while(|(name, _)| name.starts_with(&prefix))
+        .filter(|(name, _)| !name[prefix.len()..].contains('/'))
+        .map(|(_, doc)| doc.clone())
+        .collect()
+}
+
+fn read_collection_group(
+    documents: &DocumentMap,
+    parent_path: &str,
+    collection_id: &str,
+) -> Vec<Document> {
+    let prefix = if parent_path.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", parent_path)
+    };
+    documents
+        .range(prefix.clone()..)
+        .take_while(|(name, _)| name.starts_with(&prefix))
+        .filter(|(name, _)| local::in_collection_group(name, parent_path, collection_id))
+        .map(|(_, doc)| doc.clone())
+        .collect()
+}
+
+fn read_descendants(documents: &DocumentMap, name: &str) -> Vec<Document> {
+    let prefix = format!("{}/", name);
+    documents
+        .range(prefix.clone()..)
+        .take_while(|(name, _)| name.starts_with(&prefix))
+        .map(|(_, doc)| doc.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local::tests::local_backend_tests;
+
+    async fn backend() -> (Arc<MemoryBackend>, ()) {
+        (Arc::new(MemoryBackend::new()), ())
+    }
+
+    local_backend_tests!(
+        backend;
+        can_upsert_then_get,
+        insert_conflicts_with_existing_document,
+        list_only_includes_direct_children,
+        can_list_in_pages,
+        can_query_collection_group,
+        can_delete_recursively,
+        masked_reads_only_return_masked_fields,
+        concurrent_read_write_transactions_conflict,
+        concurrent_transforms_do_not_conflict,
+        writes_check_preconditions,
+        batch_writes_report_each_outcome,
+        transactions_read_their_own_writes,
+        read_only_transaction_reads_snapshot,
+    );
+}
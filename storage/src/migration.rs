@@ -0,0 +1,288 @@
+use crate as storage;
+use crate::firestore::{Document, DocumentField};
+use crate::{BatchWrite, DocumentStore, Precondition};
+use std::{convert::TryFrom, fmt::Debug, ops::AddAssign};
+
+/// The field that holds the schema version a document was written with. Documents written
+/// before versions were stored have no such field, and are at version 0.
+pub const SCHEMA_VERSION_FIELD: &str = "_schema_version";
+
+/// Documents listed per request while migrating a collection
+const MIGRATION_PAGE_SIZE: usize = 300;
+
+/// A step that upgrades a document from `from_version` to the version after it, e.g. by filling
+/// in a field that older documents lack
+#[derive(Clone, Copy)]
+pub struct Migration {
+    pub from_version: u32,
+    pub description: &'static str,
+    pub upgrade: fn(&mut Document) -> Result<(), String>,
+}
+
+/// A model whose documents carry a schema version, and the migrations that upgrade documents
+/// written with older versions. Usually derived with `#[firestore(version = ...)]`.
+pub trait Versioned {
+    const SCHEMA_VERSION: u32;
+
+    /// One migration from each version before `SCHEMA_VERSION`, in any order
+    fn migrations() -> &'static [Migration];
+}
+
+/// What `migrate_collection` found, or would have done in a dry run
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MigrationReport {
+    pub scanned: usize,
+    /// Documents that were behind the current schema version, and were (or would be) upgraded
+    pub upgraded: usize,
+    /// Documents that could not be upgraded or converted, or that changed while being upgraded.
+    /// Each failure is logged.
+    pub failed: usize,
+}
+
+impl AddAssign for MigrationReport {
+    fn add_assign(&mut self, other: MigrationReport) {
+        self.scanned += other.scanned;
+        self.upgraded += other.upgraded;
+        self.failed += other.failed;
+    }
+}
+
+/// The schema version `document` was written with
+pub fn schema_version(document: &Document) -> Result<u32, String> {
+    match document.fields.get(SCHEMA_VERSION_FIELD) {
+        Some(field) => field.extract_integer(),
+        None => Ok(0),
+    }
+}
+
+/// Run the migrations that take `document` from its schema version up to `version`, and stamp it
+/// with `version`. Returns the version the document was at.
+///
+/// Documents already at `version` are left alone, as are documents from a newer version, which
+/// are assumed to only add fields that this version ignores.
+pub fn upgrade_document(
+    document: &mut Document,
+    version: u32,
+    migrations: &[Migration],
+) -> Result<u32, String> {
+    let from_version = schema_version(document)?;
+    let mut current = from_version;
+    while current < version {
+        let migration = migrations
+            .iter()
+            .find(|m| m.from_version == current)
+            .ok_or_else(|| format!("No migration from schema version {}", current))?;
+        (migration.upgrade)(document).map_err(|e| {
+            format!(
+                "Migration from schema version {} ({}) failed: {}",
+                current, migration.description, e
+            )
+        })?;
+        current += 1;
+    }
+    if from_version < version {
+        document.fields.insert(
+            SCHEMA_VERSION_FIELD.to_owned(),
+            DocumentField::IntegerValue(version.to_string()),
+        );
+    }
+    Ok(from_version)
+}
+
+/// Upgrade every document in `store` that is behind `T::SCHEMA_VERSION`, and write it back if
+/// the document has not changed since it was read. With `dry_run`, only log what would be
+/// upgraded.
+///
+/// Each upgraded document must convert to `T`, so a dry run also finds documents that the
+/// migrations do not fix.
+pub async fn migrate_collection<T, S>(store: &S, dry_run: bool) -> storage::Result<MigrationReport>
+where
+    T: Versioned + TryFrom<Document>,
+    T::Error: Debug,
+    S: DocumentStore,
+{
+    let mut report = MigrationReport::default();
+    let mut page_token = None;
+    loop {
+        let page = store
+            .list_page::<Document>(MIGRATION_PAGE_SIZE, page_token)
+            .await?;
+        let mut writes = vec![];
+        let mut names = vec![];
+        for mut doc in page.items.into_iter() {
+            report.scanned += 1;
+            let read_update_time = Precondition::UpdateTime(doc.update_time.clone());
+            let upgraded =
+                upgrade_document(&mut doc, T::SCHEMA_VERSION, T::migrations()).and_then(|from| {
+                    T::try_from(doc.clone())
+                        .map(|_| from)
+                        .map_err(|e| format!("Upgraded document does not convert: {:?}", e))
+                });
+            let from_version = match upgraded {
+                Ok(from_version) if from_version >= T::SCHEMA_VERSION => continue,
+                Ok(from_version) => from_version,
+                Err(e) => {
+                    warn!("Cannot upgrade {}: {}", doc.name, e);
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            info!(
+                "{} {} from schema version {} to {}",
+                if dry_run {
+                    "Would upgrade"
+                } else {
+                    "Upgrading"
+                },
+                doc.name,
+                from_version,
+                T::SCHEMA_VERSION
+            );
+            report.upgraded += 1;
+            if !dry_run {
+                let id = doc.extract_id().map_err(storage::Error::Other)?;
+                names.push(doc.name.clone());
+                writes.push(BatchWrite::upsert(id, doc).with_precondition(read_update_time));
+            }
+        }
+
+        if !writes.is_empty() {
+            let results = store.batch_write(writes).await?;
+            for (name, result) in names.iter().zip(results) {
+                if let Err(e) = result {
+                    warn!("Failed to write upgraded {}: {:?}", name, e);
+                    report.upgraded -= 1;
+                    report.failed += 1;
+                }
+            }
+        }
+        page_token = match page.next_page_token {
+            Some(token) => Some(token),
+            None => break,
+        };
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local::tests::Counter;
+    use crate::memory::{MemoryBackend, MemoryStore};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    /// Version 0 had `total` only, version 1 added `label`, version 2 renamed `total` to `count`
+    static COUNTER_MIGRATIONS: &[Migration] = &[
+        Migration {
+            from_version: 1,
+            description: "Rename total to count",
+            upgrade: |doc| {
+                let total = doc.fields.remove("total").ok_or("Missing field 'total'")?;
+                doc.fields.insert("count".to_owned(), total);
+                Ok(())
+            },
+        },
+        Migration {
+            from_version: 0,
+            description: "Add label",
+            upgrade: |doc| {
+                doc.fields.insert(
+                    "label".to_owned(),
+                    DocumentField::StringValue("none".to_owned()),
+                );
+                Ok(())
+            },
+        },
+    ];
+
+    impl Versioned for Counter {
+        const SCHEMA_VERSION: u32 = 2;
+
+        fn migrations() -> &'static [Migration] {
+            COUNTER_MIGRATIONS
+        }
+    }
+
+    fn version_0(total: i64) -> Document {
+        let mut fields = HashMap::new();
+        fields.insert(
+            "total".to_owned(),
+            DocumentField::IntegerValue(total.to_string()),
+        );
+        Document::new(fields)
+    }
+
+    #[test]
+    fn documents_are_upgraded_one_version_at_a_time() {
+        let mut doc = version_0(3);
+        assert_eq!(upgrade_document(&mut doc, 2, COUNTER_MIGRATIONS), Ok(0));
+        assert_eq!(schema_version(&doc), Ok(2));
+        assert_eq!(doc.extract_integer::<i64>("count"), Ok(3));
+        assert_eq!(doc.extract_string("label"), Ok("none".to_owned()));
+
+        // Up to date, and newer, documents are left alone
+        assert_eq!(upgrade_document(&mut doc, 2, COUNTER_MIGRATIONS), Ok(2));
+        assert_eq!(upgrade_document(&mut doc, 1, COUNTER_MIGRATIONS), Ok(2));
+        assert_eq!(schema_version(&doc), Ok(2));
+        assert_eq!(doc.extract_integer::<i64>("count"), Ok(3));
+
+        let mut doc = version_0(3);
+        assert!(upgrade_document(&mut doc, 3, COUNTER_MIGRATIONS)
+            .unwrap_err()
+            .contains("No migration from schema version 2"));
+    }
+
+    #[tokio::test]
+    async fn can_migrate_collection() {
+        let store = MemoryStore::new(Arc::new(MemoryBackend::new()), None, "counters".to_owned());
+        let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        store.insert(&ids[0], version_0(1)).await.unwrap();
+        let mut up_to_date = version_0(2);
+        upgrade_document(&mut up_to_date, 2, COUNTER_MIGRATIONS).unwrap();
+        store.insert(&ids[1], up_to_date).await.unwrap();
+        // Cannot be upgraded
+        store
+            .insert(&ids[2], Document::new(HashMap::new()))
+            .await
+            .unwrap();
+
+        let expected = MigrationReport {
+            scanned: 3,
+            upgraded: 1,
+            failed: 1,
+        };
+        assert_eq!(
+            migrate_collection::<Counter, _>(&store, true)
+                .await
+                .unwrap(),
+            expected
+        );
+        let doc = store.get::<Document>(&ids[0], None).await.unwrap().unwrap();
+        assert_eq!(schema_version(&doc), Ok(0));
+
+        assert_eq!(
+            migrate_collection::<Counter, _>(&store, false)
+                .await
+                .unwrap(),
+            expected
+        );
+        let doc = store.get::<Document>(&ids[0], None).await.unwrap().unwrap();
+        assert_eq!(schema_version(&doc), Ok(2));
+        assert_eq!(
+            store.get::<Counter>(&ids[0], None).await.unwrap(),
+            Some(Counter {
+                id: ids[0],
+                count: 1
+            })
+        );
+
+        let mut report = migrate_collection::<Counter, _>(&store, false)
+            .await
+            .unwrap();
+        assert_eq!(report.upgraded, 0);
+        report += expected;
+        assert_eq!(report.scanned, 6);
+    }
+}
@@ -0,0 +1,813 @@
+use crate::firestore::{Document, DocumentArrayValue, DocumentField, DocumentMapValue};
+use serde::Serialize;
+use std::{cmp::Ordering, collections::HashMap};
+
+/// The field path that refers to the name of a document rather than one of its fields. Filters
+/// and cursors on it take `DocumentField::ReferenceValue`s, as in Firestore.
+pub const DOCUMENT_NAME_FIELD: &str = "__name__";
+
+/// A query over the documents of a collection, mirroring Firestore's `StructuredQuery`.
+///
+/// Build one up with the methods below and pass it to `DocumentStore::run_query`, which fills in
+/// the collection to query. Field paths are dot-separated, e.g. `stat_base.physical` for a field
+/// nested inside a map. Documents that are missing a field used in `order_by` are left out of the
+/// results, as they are in Firestore.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StructuredQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    select: Option<Projection>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    from: Vec<CollectionSelector>,
+    #[serde(rename = "where", skip_serializing_if = "Option::is_none")]
+    filter: Option<Filter>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    order_by: Vec<Order>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_at: Option<Cursor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_at: Option<Cursor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<u32>,
+}
+
+impl StructuredQuery {
+    pub fn new() -> StructuredQuery {
+        StructuredQuery::default()
+    }
+
+    /// Only return the given fields of each document. Select `DOCUMENT_NAME_FIELD` alone to
+    /// return just the document names.
+    pub fn select(mut self, field_paths: &[&str]) -> StructuredQuery {
+        self.select = Some(Projection {
+            fields: field_paths.iter().map(|p| FieldReference::new(p)).collect(),
+        });
+        self
+    }
+
+    /// Only return documents matching `filter`. Calling this more than once requires documents to
+    /// match every filter.
+    pub fn filter(mut self, filter: Filter) -> StructuredQuery {
+        self.filter = Some(match self.filter.take() {
+            Some(existing) => Filter::and(vec![existing, filter]),
+            None => filter,
+        });
+        self
+    }
+
+    pub fn order_by(mut self, field_path: &str, direction: Direction) -> StructuredQuery {
+        self.order_by.push(Order {
+            field: FieldReference::new(field_path),
+            direction,
+        });
+        self
+    }
+
+    /// Start at the first document whose `order_by` values are equal to or after `values`
+    pub fn start_at(mut self, values: Vec<DocumentField>) -> StructuredQuery {
+        self.start_at = Some(Cursor {
+            values,
+            before: true,
+        });
+        self
+    }
+
+    /// Start at the first document whose `order_by` values are after `values`
+    pub fn start_after(mut self, values: Vec<DocumentField>) -> StructuredQuery {
+        self.start_at = Some(Cursor {
+            values,
+            before: false,
+        });
+        self
+    }
+
+    /// End at the last document whose `order_by` values are equal to or before `values`
+    pub fn end_at(mut self, values: Vec<DocumentField>) -> StructuredQuery {
+        self.end_at = Some(Cursor {
+            values,
+            before: false,
+        });
+        self
+    }
+
+    /// End at the last document whose `order_by` values are before `values`
+    pub fn end_before(mut self, values: Vec<DocumentField>) -> StructuredQuery {
+        self.end_at = Some(Cursor {
+            values,
+            before: true,
+        });
+        self
+    }
+
+    pub fn offset(mut self, offset: u32) -> StructuredQuery {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn limit(mut self, limit: u32) -> StructuredQuery {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Target the query at the collection with the given id, under the parent the query is run
+    /// against
+    pub(crate) fn for_collection(mut self, collection_id: &str) -> StructuredQuery {
+        self.from = vec![CollectionSelector {
+            collection_id: collection_id.to_owned(),
+            all_descendants: false,
+        }];
+        self
+    }
+
+    /// Target the query at every collection with the given id nested anywhere under the parent
+    /// the query is run against
+    pub(crate) fn for_collection_group(mut self, collection_id: &str) -> StructuredQuery {
+        self.from = vec![CollectionSelector {
+            collection_id: collection_id.to_owned(),
+            all_descendants: true,
+        }];
+        self
+    }
+
+    /// Target the query at every document nested anywhere under the parent the query is run
+    /// against, whatever collection it is in
+    pub(crate) fn for_all_descendants(mut self) -> StructuredQuery {
+        self.from = vec![CollectionSelector {
+            collection_id: String::new(),
+            all_descendants: true,
+        }];
+        self
+    }
+
+    /// Evaluate the query in-process against every document of the collection, for stores that
+    /// have no query engine of their own
+    pub fn apply(&self, documents: Vec<Document>) -> Vec<Document> {
+        let mut matching: Vec<(Vec<DocumentField>, Document)> = documents
+            .into_iter()
+            .filter(|doc| match self.filter {
+                Some(ref filter) => filter.matches(doc),
+                None => true,
+            })
+            .filter_map(|doc| {
+                let order_values = self
+                    .order_by
+                    .iter()
+                    .map(|o| get_field(&doc, &o.field.field_path))
+                    .collect::<Option<Vec<_>>>()?;
+                Some((order_values, doc))
+            })
+            .collect();
+
+        // Ties are broken by document name, in the direction of the last ordering
+        let name_direction = self
+            .order_by
+            .last()
+            .map_or(Direction::Ascending, |o| o.direction);
+        matching.sort_by(|(a_values, a), (b_values, b)| {
+            self.compare_order_values(a_values, b_values)
+                .then_with(|| name_direction.apply(a.name.cmp(&b.name)))
+        });
+
+        let results = matching
+            .into_iter()
+            .filter(|(values, _)| match self.start_at {
+                Some(ref cursor) => match self.compare_order_values(values, &cursor.values) {
+                    Ordering::Less => false,
+                    Ordering::Equal => cursor.before,
+                    Ordering::Greater => true,
+                },
+                None => true,
+            })
+            .filter(|(values, _)| match self.end_at {
+                Some(ref cursor) => match self.compare_order_values(values, &cursor.values) {
+                    Ordering::Less => true,
+                    Ordering::Equal => !cursor.before,
+                    Ordering::Greater => false,
+                },
+                None => true,
+            })
+            .skip(self.offset.unwrap_or(0) as usize)
+            .take(self.limit.map_or(usize::MAX, |l| l as usize))
+            .map(|(_, doc)| doc);
+
+        match self.select {
+            Some(ref projection) => results.map(|doc| projection.apply(doc)).collect(),
+            None => results.collect(),
+        }
+    }
+
+    /// Compare the `order_by` values of a document against those of another document or a
+    /// cursor. A cursor may give fewer values than there are orderings, in which case only those
+    /// are compared.
+    fn compare_order_values(&self, a: &[DocumentField], b: &[DocumentField]) -> Ordering {
+        self.order_by
+            .iter()
+            .zip(a.iter().zip(b.iter()))
+            .map(|(order, (a, b))| order.direction.apply(compare_values(a, b)))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Filter {
+    CompositeFilter(CompositeFilter),
+    FieldFilter(FieldFilter),
+}
+
+impl Filter {
+    /// Match documents that match every one of `filters`
+    pub fn and(filters: Vec<Filter>) -> Filter {
+        Filter::CompositeFilter(CompositeFilter {
+            op: CompositeOperator::And,
+            filters,
+        })
+    }
+
+    /// Match documents that match at least one of `filters`
+    pub fn or(filters: Vec<Filter>) -> Filter {
+        Filter::CompositeFilter(CompositeFilter {
+            op: CompositeOperator::Or,
+            filters,
+        })
+    }
+
+    pub fn equal(field_path: &str, value: DocumentField) -> Filter {
+        Filter::field(field_path, FieldOperator::Equal, value)
+    }
+
+    pub fn not_equal(field_path: &str, value: DocumentField) -> Filter {
+        Filter::field(field_path, FieldOperator::NotEqual, value)
+    }
+
+    pub fn less_than(field_path: &str, value: DocumentField) -> Filter {
+        Filter::field(field_path, FieldOperator::LessThan, value)
+    }
+
+    pub fn less_than_or_equal(field_path: &str, value: DocumentField) -> Filter {
+        Filter::field(field_path, FieldOperator::LessThanOrEqual, value)
+    }
+
+    pub fn greater_than(field_path: &str, value: DocumentField) -> Filter {
+        Filter::field(field_path, FieldOperator::GreaterThan, value)
+    }
+
+    pub fn greater_than_or_equal(field_path: &str, value: DocumentField) -> Filter {
+        Filter::field(field_path, FieldOperator::GreaterThanOrEqual, value)
+    }
+
+    /// Match documents where the field is an array containing `value`
+    pub fn array_contains(field_path: &str, value: DocumentField) -> Filter {
+        Filter::field(field_path, FieldOperator::ArrayContains, value)
+    }
+
+    /// Match documents where the field is an array containing any of `values`
+    pub fn array_contains_any(field_path: &str, values: Vec<DocumentField>) -> Filter {
+        Filter::field(
+            field_path,
+            FieldOperator::ArrayContainsAny,
+            array_value(values),
+        )
+    }
+
+    /// Match documents where the field is equal to any of `values`
+    pub fn is_in(field_path: &str, values: Vec<DocumentField>) -> Filter {
+        Filter::field(field_path, FieldOperator::In, array_value(values))
+    }
+
+    /// Match documents where the field is present and not equal to any of `values`
+    pub fn not_in(field_path: &str, values: Vec<DocumentField>) -> Filter {
+        Filter::field(field_path, FieldOperator::NotIn, array_value(values))
+    }
+
+    fn field(field_path: &str, op: FieldOperator, value: DocumentField) -> Filter {
+        Filter::FieldFilter(FieldFilter {
+            field: FieldReference::new(field_path),
+            op,
+            value,
+        })
+    }
+
+    pub fn matches(&self, doc: &Document) -> bool {
+        match self {
+            Filter::CompositeFilter(f) => match f.op {
+                CompositeOperator::And => f.filters.iter().all(|filter| filter.matches(doc)),
+                CompositeOperator::Or => f.filters.iter().any(|filter| filter.matches(doc)),
+            },
+            Filter::FieldFilter(f) => match get_field(doc, &f.field.field_path) {
+                Some(field) => f.op.matches(&field, &f.value),
+                None => false,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CompositeFilter {
+    op: CompositeOperator,
+    filters: Vec<Filter>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum CompositeOperator {
+    And,
+    Or,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldFilter {
+    field: FieldReference,
+    op: FieldOperator,
+    value: DocumentField,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum FieldOperator {
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    Equal,
+    NotEqual,
+    ArrayContains,
+    In,
+    ArrayContainsAny,
+    NotIn,
+}
+
+impl FieldOperator {
+    fn matches(&self, field: &DocumentField, value: &DocumentField) -> bool {
+        // Range comparisons only match values of the same type, as in Firestore
+        let same_type = type_order(field) == type_order(value);
+        let cmp = compare_values(field, value);
+        match self {
+            FieldOperator::LessThan => same_type && cmp == Ordering::Less,
+            FieldOperator::LessThanOrEqual => same_type && cmp != Ordering::Greater,
+            FieldOperator::GreaterThan => same_type && cmp == Ordering::Greater,
+            FieldOperator::GreaterThanOrEqual => same_type && cmp != Ordering::Less,
+            FieldOperator::Equal => cmp == Ordering::Equal,
+            FieldOperator::NotEqual => !is_null(field) && cmp != Ordering::Equal,
+            FieldOperator::ArrayContains => array_elements(field)
+                .iter()
+                .any(|e| compare_values(e, value) == Ordering::Equal),
+            FieldOperator::ArrayContainsAny => array_elements(field).iter().any(|e| {
+                array_elements(value)
+                    .iter()
+                    .any(|v| compare_values(e, v) == Ordering::Equal)
+            }),
+            FieldOperator::In => array_elements(value)
+                .iter()
+                .any(|v| compare_values(field, v) == Ordering::Equal),
+            FieldOperator::NotIn => {
+                !is_null(field)
+                    && !array_elements(value)
+                        .iter()
+                        .any(|v| compare_values(field, v) == Ordering::Equal)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+impl Direction {
+    fn apply(&self, ordering: Ordering) -> Ordering {
+        match self {
+            Direction::Ascending => ordering,
+            Direction::Descending => ordering.reverse(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Order {
+    field: FieldReference,
+    direction: Direction,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Cursor {
+    values: Vec<DocumentField>,
+    before: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CollectionSelector {
+    // Left out to select collections of any id
+    #[serde(skip_serializing_if = "String::is_empty")]
+    collection_id: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    all_descendants: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FieldReference {
+    field_path: String,
+}
+
+impl FieldReference {
+    fn new(field_path: &str) -> FieldReference {
+        FieldReference {
+            field_path: field_path.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Projection {
+    fields: Vec<FieldReference>,
+}
+
+impl Projection {
+    fn apply(&self, doc: Document) -> Document {
+        project(doc, self.fields.iter().map(|f| f.field_path.as_str()))
+    }
+}
+
+/// The set of fields to return when reading documents, mirroring Firestore's `DocumentMask`.
+///
+/// Fields not in the mask are left out of the returned documents, so they usually need converting
+/// into a type other than the full model, such as `Document` itself. A mask of just
+/// `DOCUMENT_NAME_FIELD` returns documents with no fields at all.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldMask {
+    field_paths: Vec<String>,
+}
+
+impl FieldMask {
+    pub fn new(field_paths: &[&str]) -> FieldMask {
+        FieldMask {
+            field_paths: field_paths.iter().map(|p| (*p).to_owned()).collect(),
+        }
+    }
+
+    /// Remove every field not in the mask from `doc`
+    pub fn apply(&self, doc: Document) -> Document {
+        project(doc, self.field_paths.iter().map(|p| p.as_str()))
+    }
+
+    /// The mask as URL query parameters, for Firestore's GET endpoints
+    pub(crate) fn to_query_params(&self) -> String {
+        self.field_paths
+            .iter()
+            .map(|p| format!("mask.fieldPaths={}", p))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+fn project<'a, I: Iterator<Item = &'a str>>(mut doc: Document, field_paths: I) -> Document {
+    let mut fields = HashMap::new();
+    for field_path in field_paths {
+        if field_path == DOCUMENT_NAME_FIELD {
+            continue;
+        }
+        if let Some(value) = get_field(&doc, field_path) {
+            set_field(&mut fields, field_path, value);
+        }
+    }
+    doc.fields = fields;
+    doc
+}
+
+/// Look up a possibly nested field of a document by its dot-separated path
+pub(crate) fn get_field(doc: &Document, field_path: &str) -> Option<DocumentField> {
+    if field_path == DOCUMENT_NAME_FIELD {
+        return Some(DocumentField::ReferenceValue(doc.name.clone()));
+    }
+    let mut segments = field_path.split('.');
+    let mut current = doc.fields.get(segments.next()?)?;
+    for segment in segments {
+        current = match current {
+            DocumentField::MapValue(DocumentMapValue {
+                fields: Some(fields),
+            }) => fields.get(segment)?,
+            _ => return None,
+        };
+    }
+    Some(current.clone())
+}
+
+pub(crate) fn set_field(
+    fields: &mut HashMap<String, DocumentField>,
+    field_path: &str,
+    value: DocumentField,
+) {
+    match field_path.find('.') {
+        Some(idx) => {
+            let entry = fields
+                .entry(field_path[..idx].to_owned())
+                .or_insert_with(|| {
+                    DocumentField::MapValue(DocumentMapValue {
+                        fields: Some(HashMap::new()),
+                    })
+                });
+            if let DocumentField::MapValue(DocumentMapValue {
+                fields: Some(ref mut nested),
+            }) = entry
+            {
+                set_field(nested, &field_path[idx + 1..], value);
+            }
+        }
+        None => {
+            fields.insert(field_path.to_owned(), value);
+        }
+    }
+}
+
+fn array_value(values: Vec<DocumentField>) -> DocumentField {
+    DocumentField::ArrayValue(DocumentArrayValue {
+        values: Some(values),
+    })
+}
+
+fn array_elements(field: &DocumentField) -> &[DocumentField] {
+    match field {
+        DocumentField::ArrayValue(DocumentArrayValue {
+            values: Some(values),
+        }) => values,
+        _ => &[],
+    }
+}
+
+fn is_null(field: &DocumentField) -> bool {
+    matches!(field, DocumentField::NullValue)
+}
+
+/// Rank of each type in Firestore's ordering of values of different types
+fn type_order(field: &DocumentField) -> u8 {
+    match field {
+        DocumentField::NullValue => 0,
+        DocumentField::BooleanValue(_) => 1,
+        DocumentField::IntegerValue(_) | DocumentField::DoubleValue(_) => 2,
+        DocumentField::TimestampValue(_) => 3,
+        DocumentField::StringValue(_) => 4,
+        DocumentField::BytesValue(_) => 5,
+        DocumentField::ReferenceValue(_) => 6,
+        DocumentField::GeoPointValue(_) => 7,
+        DocumentField::ArrayValue(_) => 8,
+        DocumentField::MapValue(_) => 9,
+    }
+}
+
+/// Compare two values the way Firestore orders them: first by type, then by value. Integers and
+/// doubles are compared numerically with each other, and NaN sorts before every other number.
+pub fn compare_values(a: &DocumentField, b: &DocumentField) -> Ordering {
+    match (a, b) {
+        (DocumentField::IntegerValue(a), DocumentField::IntegerValue(b)) => {
+            match (a.parse::<i64>(), b.parse::<i64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            }
+        }
+        (
+            DocumentField::IntegerValue(_) | DocumentField::DoubleValue(_),
+            DocumentField::IntegerValue(_) | DocumentField::DoubleValue(_),
+        ) => {
+            let (a, b) = (as_f64(a), as_f64(b));
+            match (a.is_nan(), b.is_nan()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Less,
+                (false, true) => Ordering::Greater,
+                (false, false) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            }
+        }
+        (DocumentField::TimestampValue(a), DocumentField::TimestampValue(b)) => a.cmp(b),
+        (DocumentField::BooleanValue(a), DocumentField::BooleanValue(b)) => a.cmp(b),
+        (DocumentField::StringValue(a), DocumentField::StringValue(b)) => a.cmp(b),
+        (DocumentField::BytesValue(a), DocumentField::BytesValue(b)) => a.cmp(b),
+        (DocumentField::ReferenceValue(a), DocumentField::ReferenceValue(b)) => {
+            a.split('/').cmp(b.split('/'))
+        }
+        (DocumentField::GeoPointValue(a), DocumentField::GeoPointValue(b)) => a
+            .latitude
+            .partial_cmp(&b.latitude)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| {
+                a.longitude
+                    .partial_cmp(&b.longitude)
+                    .unwrap_or(Ordering::Equal)
+            }),
+        (DocumentField::ArrayValue(_), DocumentField::ArrayValue(_)) => {
+            let (a, b) = (array_elements(a), array_elements(b));
+            a.iter()
+                .zip(b.iter())
+                .map(|(a, b)| compare_values(a, b))
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or_else(|| a.len().cmp(&b.len()))
+        }
+        (DocumentField::MapValue(a), DocumentField::MapValue(b)) => {
+            let empty = HashMap::new();
+            let mut a: Vec<_> = a.fields.as_ref().unwrap_or(&empty).iter().collect();
+            let mut b: Vec<_> = b.fields.as_ref().unwrap_or(&empty).iter().collect();
+            a.sort_by(|x, y| x.0.cmp(y.0));
+            b.sort_by(|x, y| x.0.cmp(y.0));
+            a.iter()
+                .zip(b.iter())
+                .map(|((ak, av), (bk, bv))| ak.cmp(bk).then_with(|| compare_values(av, bv)))
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or_else(|| a.len().cmp(&b.len()))
+        }
+        _ => type_order(a).cmp(&type_order(b)),
+    }
+}
+
+pub(crate) fn as_f64(field: &DocumentField) -> f64 {
+    match field {
+        DocumentField::IntegerValue(s) => s.parse::<i64>().map_or(f64::NAN, |i| i as f64),
+        DocumentField::DoubleValue(d) => *d,
+        _ => f64::NAN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(id: &str, count: i64, tags: &[&str]) -> Document {
+        let mut fields = HashMap::new();
+        fields.insert(
+            "count".to_owned(),
+            DocumentField::IntegerValue(count.to_string()),
+        );
+        fields.insert(
+            "tags".to_owned(),
+            array_value(
+                tags.iter()
+                    .map(|t| DocumentField::StringValue((*t).to_owned()))
+                    .collect(),
+            ),
+        );
+        let mut doc = Document::new(fields);
+        doc.name = format!("counters/{}", id);
+        doc
+    }
+
+    fn names(docs: Vec<Document>) -> Vec<String> {
+        docs.into_iter().map(|d| d.name).collect()
+    }
+
+    fn int(i: i64) -> DocumentField {
+        DocumentField::IntegerValue(i.to_string())
+    }
+
+    fn string(s: &str) -> DocumentField {
+        DocumentField::StringValue(s.to_owned())
+    }
+
+    fn reference(name: &str) -> DocumentField {
+        DocumentField::ReferenceValue(name.to_owned())
+    }
+
+    fn docs() -> Vec<Document> {
+        vec![
+            doc("a", 3, &["red"]),
+            doc("b", 1, &["red", "blue"]),
+            doc("c", 2, &[]),
+            doc("d", 2, &["blue"]),
+        ]
+    }
+
+    #[test]
+    fn can_filter_order_and_limit() {
+        let query = StructuredQuery::new()
+            .filter(Filter::greater_than_or_equal("count", int(2)))
+            .order_by("count", Direction::Descending)
+            .limit(2);
+        assert_eq!(names(query.apply(docs())), vec!["counters/a", "counters/d"]);
+    }
+
+    #[test]
+    fn can_combine_filters() {
+        let query = StructuredQuery::new()
+            .filter(Filter::or(vec![
+                Filter::array_contains("tags", string("blue")),
+                Filter::equal("count", int(3)),
+            ]))
+            .filter(Filter::not_in("count", vec![int(1)]));
+        assert_eq!(names(query.apply(docs())), vec!["counters/a", "counters/d"]);
+
+        let query = StructuredQuery::new()
+            .filter(Filter::array_contains_any("tags", vec![string("blue")]))
+            .filter(Filter::is_in("count", vec![int(1), int(2)]));
+        assert_eq!(names(query.apply(docs())), vec!["counters/b", "counters/d"]);
+    }
+
+    #[test]
+    fn can_page_with_cursors() {
+        let query = StructuredQuery::new()
+            .order_by("count", Direction::Ascending)
+            .order_by(DOCUMENT_NAME_FIELD, Direction::Ascending);
+        let page = query
+            .clone()
+            .start_after(vec![int(2), reference("counters/c")])
+            .apply(docs());
+        assert_eq!(names(page), vec!["counters/d", "counters/a"]);
+
+        let page = query
+            .clone()
+            .start_at(vec![int(2)])
+            .end_before(vec![int(3)])
+            .offset(1)
+            .apply(docs());
+        assert_eq!(names(page), vec!["counters/d"]);
+    }
+
+    #[test]
+    fn document_names_are_references() {
+        let query = StructuredQuery::new()
+            .filter(Filter::equal(DOCUMENT_NAME_FIELD, reference("counters/b")));
+        assert_eq!(names(query.apply(docs())), vec!["counters/b"]);
+
+        let query =
+            StructuredQuery::new().filter(Filter::equal(DOCUMENT_NAME_FIELD, string("counters/b")));
+        assert!(query.apply(docs()).is_empty());
+    }
+
+    #[test]
+    fn range_filters_only_match_the_same_type() {
+        let mut mixed = doc("e", 0, &[]);
+        mixed
+            .fields
+            .insert("count".to_owned(), string("not a number"));
+        let query = StructuredQuery::new().filter(Filter::less_than("count", int(2)));
+        let mut all = docs();
+        all.push(mixed);
+        assert_eq!(names(query.apply(all)), vec!["counters/b"]);
+    }
+
+    #[test]
+    fn can_select_fields() {
+        let query = StructuredQuery::new().select(&[DOCUMENT_NAME_FIELD]);
+        let ret = query.apply(docs());
+        assert_eq!(ret.len(), 4);
+        assert!(ret.iter().all(|d| d.fields.is_empty()));
+    }
+
+    #[test]
+    fn field_mask_keeps_nested_fields() {
+        let mut inner = HashMap::new();
+        inner.insert("physical".to_owned(), int(1));
+        inner.insert("mental".to_owned(), int(2));
+        let mut doc = doc("a", 3, &["red"]);
+        doc.fields.insert(
+            "stats".to_owned(),
+            DocumentField::MapValue(DocumentMapValue {
+                fields: Some(inner),
+            }),
+        );
+
+        let ret = FieldMask::new(&["count", "stats.mental"]).apply(doc);
+        assert_eq!(ret.name, "counters/a");
+        assert_eq!(ret.fields.len(), 2);
+        assert_eq!(ret.extract_integer::<i64>("count").unwrap(), 3);
+        match ret.fields.get("stats") {
+            Some(DocumentField::MapValue(DocumentMapValue {
+                fields: Some(stats),
+            })) => {
+                assert_eq!(stats.len(), 1);
+                assert!(stats.contains_key("mental"));
+            }
+            other => panic!("Expected stats map, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn serialises_to_firestore_format() {
+        let query = StructuredQuery::new()
+            .filter(Filter::array_contains("tags", string("red")))
+            .order_by("count", Direction::Ascending)
+            .limit(1)
+            .for_collection("counters");
+        assert_eq!(
+            serde_json::to_value(&query).unwrap(),
+            serde_json::json!({
+                "from": [{ "collectionId": "counters" }],
+                "where": {
+                    "fieldFilter": {
+                        "field": { "fieldPath": "tags" },
+                        "op": "ARRAY_CONTAINS",
+                        "value": { "stringValue": "red" },
+                    }
+                },
+                "orderBy": [{ "field": { "fieldPath": "count" }, "direction": "ASCENDING" }],
+                "limit": 1,
+            })
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use rand::Rng;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How many times, and how far apart, to run an operation that fails with a retryable error.
+///
+/// Each retry waits for a random delay of up to `base_delay * 2^n` ("full jitter"), capped at
+/// `max_delay`, so that clients which conflicted with each other do not retry in lockstep.
+/// Clones share the same metrics.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    base_delay: Duration,
+    max_delay: Duration,
+    metrics: Arc<RetryMetrics>,
+}
+
+/// Counters for every operation run through a `RetryPolicy` and its clones
+#[derive(Debug, Default)]
+pub struct RetryMetrics {
+    retries: AtomicU64,
+    recovered: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+/// A point-in-time copy of `RetryMetrics`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct RetryStats {
+    /// Attempts made after the first, across all operations
+    pub retries: u64,
+    /// Operations that failed with a retryable error and then succeeded
+    pub recovered: u64,
+    /// Operations that were still failing with a retryable error after the last attempt
+    pub exhausted: u64,
+}
+
+impl RetryPolicy {
+    /// `max_attempts` includes the first attempt, so a policy with 1 never retries
+    pub fn new(max_attempts: usize, base_delay: Duration, max_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            base_delay,
+            max_delay,
+            metrics: Arc::new(RetryMetrics::default()),
+        }
+    }
+
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    pub fn metrics(&self) -> RetryStats {
+        self.metrics.snapshot()
+    }
+
+    /// The delay before the retry following the failed attempt `attempt`, starting from 1
+    pub fn delay_for(&self, attempt: usize) -> Duration {
+        let exponent = (attempt.saturating_sub(1)).min(31) as u32;
+        let ceiling = self
+            .base_delay
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        if ceiling.is_zero() {
+            ceiling
+        } else {
+            rand::thread_rng().gen_range(Duration::ZERO..=ceiling)
+        }
+    }
+
+    /// Run `f` until it succeeds, fails with an error that `is_retryable` rejects, or has been
+    /// attempted `max_attempts` times, returning the last result.
+    ///
+    /// `f` is called afresh for every attempt, so a transaction should be begun inside it rather
+    /// than reused across attempts. `operation` names the operation in logs.
+    pub async fn run<T, E, F, Fut, P>(
+        &self,
+        operation: &str,
+        is_retryable: P,
+        mut f: F,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Debug,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        P: Fn(&E) -> bool,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if is_retryable(&e) => {
+                    if attempt >= self.max_attempts {
+                        self.metrics.exhausted.fetch_add(1, Ordering::Relaxed);
+                        warn!(
+                            "{} failed with a retryable error after {} attempts: {:?}",
+                            operation, attempt, e
+                        );
+                        break Err(e);
+                    }
+                    let delay = self.delay_for(attempt);
+                    info!(
+                        "{} caught retryable error on attempt {} of {}, retrying in {:?}: {:?}",
+                        operation, attempt, self.max_attempts, delay, e
+                    );
+                    self.metrics.retries.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                ret => {
+                    if attempt > 1 && ret.is_ok() {
+                        self.metrics.recovered.fetch_add(1, Ordering::Relaxed);
+                    }
+                    break ret;
+                }
+            }
+        }
+    }
+}
+
+/// Three attempts, with delays of up to 300ms and then 600ms between them
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(3, Duration::from_millis(300), Duration::from_secs(5))
+    }
+}
+
+impl RetryMetrics {
+    pub fn snapshot(&self) -> RetryStats {
+        RetryStats {
+            retries: self.retries.load(Ordering::Relaxed),
+            recovered: self.recovered.load(Ordering::Relaxed),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn delays_grow_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy::new(10, Duration::from_millis(100), Duration::from_millis(500));
+        for _ in 0..100 {
+            assert!(policy.delay_for(1) <= Duration::from_millis(100));
+            assert!(policy.delay_for(2) <= Duration::from_millis(200));
+            assert!(policy.delay_for(3) <= Duration::from_millis(400));
+            assert!(policy.delay_for(4) <= Duration::from_millis(500));
+            assert!(policy.delay_for(64) <= Duration::from_millis(500));
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_only_retryable_errors_up_to_max_attempts() {
+        let policy = RetryPolicy::new(3, Duration::ZERO, Duration::ZERO);
+        let calls = AtomicUsize::new(0);
+
+        let ret: Result<(), &str> = policy
+            .run(
+                "always_aborts",
+                |e| *e == "aborted",
+                || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err("aborted")
+                },
+            )
+            .await;
+        assert_eq!(ret, Err("aborted"));
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 3);
+
+        let ret: Result<(), &str> = policy
+            .run(
+                "not_found",
+                |e| *e == "aborted",
+                || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err("not found")
+                },
+            )
+            .await;
+        assert_eq!(ret, Err("not found"));
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 1);
+
+        let ret: Result<usize, &str> = policy
+            .run(
+                "aborts_once",
+                |e| *e == "aborted",
+                || async {
+                    match calls.fetch_add(1, Ordering::SeqCst) {
+                        0 => Err("aborted"),
+                        n => Ok(n),
+                    }
+                },
+            )
+            .await;
+        assert_eq!(ret, Ok(1));
+
+        assert_eq!(
+            policy.clone().metrics(),
+            RetryStats {
+                retries: 3,
+                recovered: 1,
+                exhausted: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,397 @@
+use crate as storage;
+use crate::firestore::{Document, DocumentField};
+use crate::local::{self, run_blocking, LocalBackend, LocalReader, LocalStore, LocalWrite};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior, NO_PARAMS};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// A `DocumentStore` that persists documents to a SQLite database file
+pub type SqliteStore = LocalStore<SqliteBackend>;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS documents (
+    name TEXT PRIMARY KEY NOT NULL,
+    collection_path TEXT NOT NULL,
+    fields TEXT NOT NULL,
+    create_time TEXT NOT NULL,
+    update_time TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS documents_by_collection ON documents (collection_path, name);
+";
+
+/// Every document is stored as a row keyed by its full name, with its fields serialised in the
+/// same JSON format Firestore uses.
+///
+/// The database is opened in WAL mode, so that read-only transactions can hold their own
+/// connection with an open read transaction as a snapshot, without blocking writers. This also
+/// means the database must be a file; `:memory:` databases are not supported.
+pub struct SqliteBackend {
+    path: PathBuf,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteBackend {
+    pub async fn open<P: Into<PathBuf>>(path: P) -> storage::Result<SqliteBackend> {
+        let path = path.into();
+        let path_clone = path.clone();
+        let connection = run_blocking(move || {
+            let connection = open_connection(&path_clone)?;
+            connection.execute_batch(SCHEMA)?;
+            Ok(connection)
+        })
+        .await?;
+        info!("Opened SQLite database at {}", path.display());
+        Ok(SqliteBackend {
+            path,
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> storage::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> storage::Result<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        run_blocking(move || f(&mut connection.lock().expect("Poisoned lock"))).await
+    }
+}
+
+#[async_trait]
+impl LocalReader for SqliteBackend {
+    async fn read(&self, name: &str) -> storage::Result<Option<Document>> {
+        let name = name.to_owned();
+        self.with_connection(move |conn| read(conn, &name)).await
+    }
+
+    async fn read_collection(&self, collection_path: &str) -> storage::Result<Vec<Document>> {
+        let collection_path = collection_path.to_owned();
+        self.with_connection(move |conn| read_collection(conn, &collection_path))
+            .await
+    }
+
+    async fn read_collection_group(
+        &self,
+        parent_path: &str,
+        collection_id: &str,
+    ) -> storage::Result<Vec<Document>> {
+        let parent_path = parent_path.to_owned();
+        let collection_id = collection_id.to_owned();
+        self.with_connection(move |conn| read_collection_group(conn, &parent_path, &collection_id))
+            .await
+    }
+
+    async fn read_descendants(&self, name: &str) -> storage::Result<Vec<Document>> {
+        let name = name.to_owned();
+        self.with_connection(move |conn| read_descendants(conn, &name))
+            .await
+    }
+}
+
+#[async_trait]
+impl LocalBackend for SqliteBackend {
+    type Snapshot = SqliteSnapshot;
+
+    async fn snapshot(&self) -> storage::Result<Self::Snapshot> {
+        let path = self.path.clone();
+        let connection = run_blocking(move || {
+            let connection = open_connection(&path)?;
+            // A read transaction only takes its snapshot on the first read, so read something now
+            connection.execute_batch("BEGIN DEFERRED")?;
+            connection.query_row("SELECT COUNT(*) FROM documents", NO_PARAMS, |row| {
+                row.get::<_, i64>(0)
+            })?;
+            Ok(connection)
+        })
+        .await?;
+        Ok(SqliteSnapshot {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn commit(
+        &self,
+        expected_versions: HashMap<String, Option<String>>,
+        writes: Vec<LocalWrite>,
+    ) -> storage::Result<()> {
+        self.with_connection(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            local::check_versions(&expected_versions, |name| {
+                Ok(read(&tx, name)?.map(|doc| doc.update_time))
+            })?;
+
+            let commit_time = local::next_commit_time();
+            for write in writes.into_iter() {
+                let name = write.name().to_owned();
+                let existing = read(&tx, &name)?;
+                let updated = write.apply(existing.as_ref(), &commit_time)?;
+                store(&tx, &name, updated)?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Apply every write in a single SQLite transaction, so that a large batch is only synced to
+    /// disk once, while still letting each write fail on its own
+    async fn commit_each(
+        &self,
+        writes: Vec<LocalWrite>,
+    ) -> storage::Result<Vec<storage::Result<()>>> {
+        self.with_connection(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let commit_time = local::next_commit_time();
+            let mut results = Vec::with_capacity(writes.len());
+            for write in writes.into_iter() {
+                let name = write.name().to_owned();
+                let existing = read(&tx, &name)?;
+                match write.apply(existing.as_ref(), &commit_time) {
+                    Ok(updated) => {
+                        store(&tx, &name, updated)?;
+                        results.push(Ok(()));
+                    }
+                    Err(e) => results.push(Err(e)),
+                }
+            }
+            tx.commit()?;
+            Ok(results)
+        })
+        .await
+    }
+}
+
+/// A connection holding an open read transaction, taken when a read-only transaction began
+pub struct SqliteSnapshot {
+    connection: Arc<Mutex<Connection>>,
+}
+
+#[async_trait]
+impl LocalReader for SqliteSnapshot {
+    async fn read(&self, name: &str) -> storage::Result<Option<Document>> {
+        let connection = Arc::clone(&self.connection);
+        let name = name.to_owned();
+        run_blocking(move || read(&connection.lock().expect("Poisoned lock"), &name)).await
+    }
+
+    async fn read_collection(&self, collection_path: &str) -> storage::Result<Vec<Document>> {
+        let connection = Arc::clone(&self.connection);
+        let collection_path = collection_path.to_owned();
+        run_blocking(move || {
+            read_collection(&connection.lock().expect("Poisoned lock"), &collection_path)
+        })
+        .await
+    }
+
+    async fn read_collection_group(
+        &self,
+        parent_path: &str,
+        collection_id: &str,
+    ) -> storage::Result<Vec<Document>> {
+        let connection = Arc::clone(&self.connection);
+        let parent_path = parent_path.to_owned();
+        let collection_id = collection_id.to_owned();
+        run_blocking(move || {
+            read_collection_group(
+                &connection.lock().expect("Poisoned lock"),
+                &parent_path,
+                &collection_id,
+            )
+        })
+        .await
+    }
+
+    async fn read_descendants(&self, name: &str) -> storage::Result<Vec<Document>> {
+        let connection = Arc::clone(&self.connection);
+        let name = name.to_owned();
+        run_blocking(move || read_descendants(&connection.lock().expect("Poisoned lock"), &name))
+            .await
+    }
+}
+
+fn open_connection(path: &Path) -> storage::Result<Connection> {
+    let connection = Connection::open(path)?;
+    connection.busy_timeout(Duration::from_secs(5))?;
+    connection.query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |row| {
+        row.get::<_, String>(0)
+    })?;
+    Ok(connection)
+}
+
+fn read(conn: &Connection, name: &str) -> storage::Result<Option<Document>> {
+    let row = conn
+        .query_row(
+            "SELECT name, fields, create_time, update_time FROM documents WHERE name = ?1",
+            params![name],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()?;
+    match row {
+        Some(row) => Ok(Some(row_to_document(row)?)),
+        None => Ok(None),
+    }
+}
+
+/// Write the new state of the document `name`, deleting it if `None`
+fn store(conn: &Connection, name: &str, doc: Option<Document>) -> storage::Result<()> {
+    match doc {
+        Some(doc) => {
+            conn.execute(
+                "INSERT OR REPLACE INTO documents (name, collection_path, fields, create_time, update_time) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    doc.name,
+                    collection_path_of(&doc.name),
+                    serde_json::to_string(&doc.fields)?,
+                    doc.create_time,
+                    doc.update_time,
+                ],
+            )?;
+        }
+        None => {
+            conn.execute("DELETE FROM documents WHERE name = ?1", params![name])?;
+        }
+    }
+    Ok(())
+}
+
+fn read_collection(conn: &Connection, collection_path: &str) -> storage::Result<Vec<Document>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, fields, create_time, update_time FROM documents WHERE collection_path = ?1 ORDER BY name",
+    )?;
+    let rows = stmt.query_map(params![collection_path], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    let mut ret = vec![];
+    for row in rows {
+        ret.push(row_to_document(row?)?);
+    }
+    Ok(ret)
+}
+
+fn read_collection_group(
+    conn: &Connection,
+    parent_path: &str,
+    collection_id: &str,
+) -> storage::Result<Vec<Document>> {
+    // LIKE treats `_` in ids as a wildcard, so the matches are narrowed down exactly afterwards
+    let mut stmt = conn.prepare(
+        "SELECT name, fields, create_time, update_time FROM documents WHERE collection_path = ?1 OR collection_path LIKE ?2 ORDER BY name",
+    )?;
+    let rows = stmt.query_map(
+        params![collection_id, format!("%/{}", collection_id)],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+    let mut ret = vec![];
+    for row in rows {
+        let doc = row_to_document(row?)?;
+        if local::in_collection_group(&doc.name, parent_path, collection_id) {
+            ret.push(doc);
+        }
+    }
+    Ok(ret)
+}
+
+fn read_descendants(conn: &Connection, name: &str) -> storage::Result<Vec<Document>> {
+    // Every name starting with `{name}/` sorts between it and `{name}0`, as '0' follows '/'
+    let mut stmt = conn.prepare(
+        "SELECT name, fields, create_time, update_time FROM documents WHERE name > ?1 AND name < ?2 ORDER BY name",
+    )?;
+    let rows = stmt.query_map(params![format!("{}/", name), format!("{}0", name)], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    let mut ret = vec![];
+    for row in rows {
+        ret.push(row_to_document(row?)?);
+    }
+    Ok(ret)
+}
+
+fn row_to_document(
+    (name, fields, create_time, update_time): (String, String, String, String),
+) -> storage::Result<Document> {
+    let fields: HashMap<String, DocumentField> = serde_json::from_str(&fields)?;
+    Ok(Document {
+        name,
+        fields,
+        create_time,
+        update_time,
+    })
+}
+
+fn collection_path_of(name: &str) -> &str {
+    match name.rfind('/') {
+        Some(idx) => &name[..idx],
+        None => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local::tests::{local_backend_tests, Counter};
+    use crate::DocumentStore;
+    use uuid::Uuid;
+
+    async fn open(dir: &tempfile::TempDir) -> Arc<SqliteBackend> {
+        let backend = SqliteBackend::open(dir.path().join("test.sqlite3"))
+            .await
+            .unwrap();
+        Arc::new(backend)
+    }
+
+    async fn counters(dir: &tempfile::TempDir) -> SqliteStore {
+        SqliteStore::new(open(dir).await, None, "counters".to_owned())
+    }
+
+    async fn backend() -> (Arc<SqliteBackend>, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        (open(&dir).await, dir)
+    }
+
+    local_backend_tests!(
+        backend;
+        can_upsert_then_get,
+        insert_conflicts_with_existing_document,
+        list_only_includes_direct_children,
+        can_list_in_pages,
+        can_query_collection_group,
+        can_delete_recursively,
+        masked_reads_only_return_masked_fields,
+        concurrent_read_write_transactions_conflict,
+        concurrent_transforms_do_not_conflict,
+        writes_check_preconditions,
+        batch_writes_report_each_outcome,
+        transactions_read_their_own_writes,
+        read_only_transaction_reads_snapshot,
+    );
+
+    #[tokio::test]
+    async fn documents_persist_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = Uuid::new_v4();
+        {
+            let store = counters(&dir).await;
+            store.insert(&id, Counter { id, count: 7 }).await.unwrap();
+            let sub_store = store.subcollection(id.to_string(), "counters".to_owned());
+            sub_store
+                .insert(&id, Counter { id, count: 8 })
+                .await
+                .unwrap();
+        }
+
+        let store = counters(&dir).await;
+        let ret = store.get::<Counter>(&id, None).await.unwrap();
+        assert_eq!(ret, Some(Counter { id, count: 7 }));
+        assert_eq!(store.list::<Counter>().await.unwrap().len(), 1);
+        let sub_store = store.subcollection(id.to_string(), "counters".to_owned());
+        assert_eq!(
+            sub_store.list::<Counter>().await.unwrap(),
+            vec![Counter { id, count: 8 }]
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use crate as storage;
+use crate::firestore::Document;
+use crate::query::{FieldMask, StructuredQuery, DOCUMENT_NAME_FIELD};
+use crate::transform::FieldTransform;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+};
+use uuid::Uuid;
+
+/// A client for a single collection of documents, addressed by a parent path and a collection id.
+///
+/// This is the interface the engine programs against, so that it is not tied to any particular
+/// storage backend. `FirestoreClient` is the reference implementation.
+#[async_trait]
+pub trait DocumentStore: Send + Sync + Sized {
+    type Transaction: StoreTransaction;
+
+    /// Create a client for a subcollection nested under a document of this collection
+    fn subcollection(&self, subcollection_relative_path: String, subcollection_id: String) -> Self;
+
+    async fn begin_transaction(
+        &self,
+        transaction_type: TransactionType,
+    ) -> storage::Result<Self::Transaction>;
+
+    /// Apply `writes` to documents of this collection outside of a transaction, in as few
+    /// requests as the backend allows. Returns the outcome of each write, in the same order.
+    ///
+    /// The writes are not atomic, and may be applied in any order: each one succeeds or fails on
+    /// its own, e.g. with `storage::Error::Conflict` if its precondition does not hold. A batch
+    /// may write each document at most once. The outer error is only for failures that leave the
+    /// outcome of some writes unknown, such as a request that could not be sent.
+    async fn batch_write(
+        &self,
+        writes: Vec<BatchWrite>,
+    ) -> storage::Result<Vec<storage::Result<()>>>;
+
+    async fn batch_get<T: TryFrom<Document> + Send>(
+        &self,
+        ids: &[Uuid],
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<HashMap<Uuid, Option<T>>>;
+
+    /// Like `batch_get`, but only read the fields in `mask`
+    async fn batch_get_masked<T: TryFrom<Document> + Send>(
+        &self,
+        ids: &[Uuid],
+        mask: &FieldMask,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<HashMap<Uuid, Option<T>>>;
+
+    async fn delete<T: TryFrom<Document>>(
+        &self,
+        id: &Uuid,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()>;
+
+    /// Delete a document along with every document in its subcollections, however deeply nested.
+    /// Returns how many documents were deleted from the subcollections.
+    ///
+    /// This is not atomic: the deletes may be split across several commits to stay within write
+    /// limits, and documents added while it runs may be left behind. The document itself is
+    /// deleted last, so a failed attempt can be retried.
+    async fn delete_recursive(&self, id: &Uuid) -> storage::Result<usize>;
+
+    /// Like `delete`, but only delete the document if `precondition` holds, failing with
+    /// `storage::Error::Conflict` otherwise. Inside a transaction, the failure is returned by
+    /// `commit`.
+    async fn delete_if(
+        &self,
+        id: &Uuid,
+        precondition: Precondition,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()>;
+
+    /// Read a single document. Inside a read-write transaction, this sees the writes made in the
+    /// transaction so far, as do `get_masked`, `batch_get` and `batch_get_masked`. Queries only see
+    /// committed documents.
+    async fn get<T: TryFrom<Document> + Send>(
+        &self,
+        id: &Uuid,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<Option<T>>;
+
+    /// Like `get`, but only read the fields in `mask`
+    async fn get_masked<T: TryFrom<Document> + Send>(
+        &self,
+        id: &Uuid,
+        mask: &FieldMask,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<Option<T>>;
+
+    async fn insert<T: Into<Document> + Send>(&self, id: &Uuid, value: T) -> storage::Result<()>;
+
+    async fn list<T: TryFrom<Document> + Send>(&self) -> storage::Result<Vec<T>>;
+
+    /// List the ids of every document in this collection, without reading any of their fields
+    async fn list_ids(&self) -> storage::Result<Vec<Uuid>> {
+        let query = StructuredQuery::new().select(&[DOCUMENT_NAME_FIELD]);
+        let docs = self.run_query::<Document>(query, None).await?;
+        let mut ret = vec![];
+        for doc in docs.into_iter() {
+            match doc.extract_id() {
+                Ok(id) => ret.push(id),
+                Err(e) => error!("Failed to extract id from Document: {}", e),
+            }
+        }
+        Ok(ret)
+    }
+
+    /// List up to `page_size` documents in order of id, starting after the page that returned
+    /// `page_token`. Unlike `list`, a document that fails to convert fails the whole page.
+    async fn list_page<T: TryFrom<Document> + Send>(
+        &self,
+        page_size: usize,
+        page_token: Option<String>,
+    ) -> storage::Result<Page<T>>;
+
+    /// Like `list`, but only read the fields in `mask`
+    async fn list_masked<T: TryFrom<Document> + Send>(
+        &self,
+        mask: &FieldMask,
+    ) -> storage::Result<Vec<T>>;
+
+    /// Run `query` against the documents of this collection
+    async fn run_query<T: TryFrom<Document> + Send>(
+        &self,
+        query: StructuredQuery,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<Vec<T>>;
+
+    /// Run `query` against every collection with id `collection_id` nested anywhere under the
+    /// parent of this collection, e.g. the `characters` subcollections of every user when run
+    /// from the top-level `users` collection. Results are ordered by document name unless the
+    /// query orders them otherwise, and each document's name says which collection it is from.
+    ///
+    /// Firestore needs a collection group index for each field the query filters or orders on.
+    async fn run_collection_group_query<T: TryFrom<Document> + Send>(
+        &self,
+        collection_id: &str,
+        query: StructuredQuery,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<Vec<T>>;
+
+    /// Atomically apply `transforms` to the fields of a document, in order, without reading it
+    /// first. The document is created if it does not exist.
+    async fn transform(
+        &self,
+        id: &Uuid,
+        transforms: Vec<FieldTransform>,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()>;
+
+    /// Like `transform`, but only apply the transforms if `precondition` holds, failing with
+    /// `storage::Error::Conflict` otherwise. Inside a transaction, the failure is returned by
+    /// `commit`.
+    async fn transform_if(
+        &self,
+        id: &Uuid,
+        transforms: Vec<FieldTransform>,
+        precondition: Precondition,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()>;
+
+    async fn upsert<T: Into<Document> + Send>(
+        &self,
+        id: &Uuid,
+        value: T,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()>;
+
+    /// Like `upsert`, but only write the document if `precondition` holds, failing with
+    /// `storage::Error::Conflict` otherwise. Inside a transaction, the failure is returned by
+    /// `commit`.
+    async fn upsert_if<T: Into<Document> + Send>(
+        &self,
+        id: &Uuid,
+        value: T,
+        precondition: Precondition,
+        transaction: Option<&Self::Transaction>,
+    ) -> storage::Result<()>;
+}
+
+/// Convert a document read from the store, naming it in the error if it fails
+pub(crate) fn convert_document<T: TryFrom<Document>>(doc: Document) -> storage::Result<T> {
+    let name = doc.name.clone();
+    T::try_from(doc).map_err(|_| {
+        storage::Error::Other(format!(
+            "Failed to convert Document '{}' to requested type.",
+            name
+        ))
+    })
+}
+
+/// A single write in a `DocumentStore::batch_write`
+#[derive(Clone, Debug)]
+pub struct BatchWrite {
+    pub id: Uuid,
+    pub operation: BatchWriteOperation,
+    pub precondition: Option<Precondition>,
+}
+
+#[derive(Clone, Debug)]
+pub enum BatchWriteOperation {
+    /// Replace the document, or create it if it does not exist
+    Upsert(Document),
+    /// Apply field transforms, creating the document if it does not exist
+    Transform(Vec<FieldTransform>),
+    Delete,
+}
+
+impl BatchWrite {
+    pub fn upsert<T: Into<Document>>(id: Uuid, value: T) -> BatchWrite {
+        BatchWrite {
+            id,
+            operation: BatchWriteOperation::Upsert(value.into()),
+            precondition: None,
+        }
+    }
+
+    /// Create the document, failing with `storage::Error::Conflict` if it already exists
+    pub fn insert<T: Into<Document>>(id: Uuid, value: T) -> BatchWrite {
+        BatchWrite::upsert(id, value).with_precondition(Precondition::Exists(false))
+    }
+
+    pub fn transform(id: Uuid, transforms: Vec<FieldTransform>) -> BatchWrite {
+        BatchWrite {
+            id,
+            operation: BatchWriteOperation::Transform(transforms),
+            precondition: None,
+        }
+    }
+
+    pub fn delete(id: Uuid) -> BatchWrite {
+        BatchWrite {
+            id,
+            operation: BatchWriteOperation::Delete,
+            precondition: None,
+        }
+    }
+
+    /// Only apply the write if `precondition` holds
+    pub fn with_precondition(mut self, precondition: Precondition) -> BatchWrite {
+        self.precondition = Some(precondition);
+        self
+    }
+}
+
+/// Reject a batch that writes the same document more than once, which Firestore does not allow
+pub(crate) fn check_batch(writes: &[BatchWrite]) -> storage::Result<()> {
+    let mut ids = HashSet::new();
+    for write in writes.iter() {
+        if !ids.insert(write.id) {
+            return Err(storage::Error::Other(format!(
+                "Batch writes document {} more than once",
+                write.id
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// One page of the documents in a collection, as returned by `DocumentStore::list_page`
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Opaque token to pass back for the next page, or `None` if this is the last page
+    pub next_page_token: Option<String>,
+}
+
+/// A transaction started by a `DocumentStore`. Writes are buffered until `commit` is called.
+///
+/// A commit is applied atomically by every backend except `FileBackend`, where a crash part way
+/// through can leave only some of the writes applied.
+#[async_trait]
+pub trait StoreTransaction: Send + Sync {
+    /// Discard the buffered writes. Any failure to release the transaction is logged and retried
+    /// in the background.
+    async fn abort(self);
+
+    /// Discard the buffered writes, reporting any failure to release the transaction
+    async fn rollback(self) -> storage::Result<()>;
+
+    async fn commit(self) -> storage::Result<()>;
+}
+
+#[derive(Debug)]
+pub enum TransactionType {
+    /// Every read sees the same snapshot of the store, and nothing can be written. `FileBackend`
+    /// has no snapshots, and fails to begin these.
+    ReadOnly,
+    /// Reads see the latest committed state plus the transaction's own writes, and the commit
+    /// fails if a document read has changed since
+    ReadWrite,
+}
+
+/// A condition on the current state of a document that must hold for a write to it to be applied,
+/// mirroring Firestore's `Precondition`.
+///
+/// `UpdateTime` takes the `update_time` of a previously read `Document`, which makes a
+/// read-modify-write without a transaction a compare-and-swap.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Precondition {
+    /// The document must exist, or must not exist
+    Exists(bool),
+    /// The document must exist and have last been updated at exactly this time
+    UpdateTime(String),
+}
+
+impl Precondition {
+    /// Check the precondition against the current state of the document named `name`
+    pub fn check(&self, name: &str, existing: Option<&Document>) -> storage::Result<()> {
+        let holds = match (self, existing) {
+            (Precondition::Exists(exists), existing) => *exists == existing.is_some(),
+            (Precondition::UpdateTime(update_time), Some(doc)) => *update_time == doc.update_time,
+            (Precondition::UpdateTime(_), None) => false,
+        };
+        if holds {
+            Ok(())
+        } else {
+            Err(storage::Error::Conflict(format!(
+                "Precondition {:?} failed for document '{}'",
+                self, name
+            )))
+        }
+    }
+}
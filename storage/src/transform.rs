@@ -0,0 +1,190 @@
+use crate::firestore::{Document, DocumentField};
+use crate::query::{self, compare_values};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::cmp::Ordering;
+
+/// An atomic change to a single field of a document, mirroring Firestore's `FieldTransform`.
+///
+/// Transforms are applied by the backend against the current value of the field when the write is
+/// committed, so concurrent transforms of the same field do not conflict with each other. Field
+/// paths are dot-separated, as in queries.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldTransform {
+    field_path: String,
+    #[serde(flatten)]
+    kind: TransformKind,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum TransformKind {
+    Increment(DocumentField),
+    Maximum(DocumentField),
+    Minimum(DocumentField),
+    SetToServerValue(ServerValue),
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum ServerValue {
+    RequestTime,
+}
+
+impl FieldTransform {
+    /// Add `value` to the field. Integers overflowing an i64 are clamped, and adding a double to an
+    /// integer gives a double. A missing or non-numeric field is set to `value`.
+    pub fn increment(field_path: &str, value: DocumentField) -> FieldTransform {
+        FieldTransform::new(field_path, TransformKind::Increment(value))
+    }
+
+    /// Set the field to the larger of its current value and `value`. A missing or non-numeric
+    /// field is set to `value`.
+    pub fn maximum(field_path: &str, value: DocumentField) -> FieldTransform {
+        FieldTransform::new(field_path, TransformKind::Maximum(value))
+    }
+
+    /// Set the field to the smaller of its current value and `value`. A missing or non-numeric
+    /// field is set to `value`.
+    pub fn minimum(field_path: &str, value: DocumentField) -> FieldTransform {
+        FieldTransform::new(field_path, TransformKind::Minimum(value))
+    }
+
+    /// Set the field to the time the write is committed
+    pub fn server_timestamp(field_path: &str) -> FieldTransform {
+        FieldTransform::new(
+            field_path,
+            TransformKind::SetToServerValue(ServerValue::RequestTime),
+        )
+    }
+
+    fn new(field_path: &str, kind: TransformKind) -> FieldTransform {
+        FieldTransform {
+            field_path: field_path.to_owned(),
+            kind,
+        }
+    }
+
+    /// Apply the transform in-process to `doc`, for stores that have no transforms of their own
+    pub fn apply(&self, doc: &mut Document, commit_time: DateTime<Utc>) {
+        let current = query::get_field(doc, &self.field_path).filter(is_number);
+        let value = match (&self.kind, current) {
+            (TransformKind::SetToServerValue(ServerValue::RequestTime), _) => {
+                DocumentField::TimestampValue(commit_time)
+            }
+            (TransformKind::Increment(value), Some(current)) if is_number(value) => {
+                add(&current, value)
+            }
+            (TransformKind::Maximum(value), Some(current)) if is_number(value) => {
+                match compare_values(&current, value) {
+                    Ordering::Less => value.clone(),
+                    _ => current,
+                }
+            }
+            (TransformKind::Minimum(value), Some(current)) if is_number(value) => {
+                match compare_values(&current, value) {
+                    Ordering::Greater => value.clone(),
+                    _ => current,
+                }
+            }
+            (TransformKind::Increment(value), _)
+            | (TransformKind::Maximum(value), _)
+            | (TransformKind::Minimum(value), _) => value.clone(),
+        };
+        query::set_field(&mut doc.fields, &self.field_path, value);
+    }
+}
+
+fn is_number(field: &DocumentField) -> bool {
+    matches!(
+        field,
+        DocumentField::IntegerValue(_) | DocumentField::DoubleValue(_)
+    )
+}
+
+fn add(a: &DocumentField, b: &DocumentField) -> DocumentField {
+    match (a, b) {
+        (DocumentField::IntegerValue(a), DocumentField::IntegerValue(b)) => {
+            match (a.parse::<i64>(), b.parse::<i64>()) {
+                (Ok(a), Ok(b)) => DocumentField::IntegerValue(a.saturating_add(b).to_string()),
+                _ => DocumentField::DoubleValue(f64::NAN),
+            }
+        }
+        _ => DocumentField::DoubleValue(query::as_f64(a) + query::as_f64(b)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn int(i: i64) -> DocumentField {
+        DocumentField::IntegerValue(i.to_string())
+    }
+
+    fn counter(count: i64) -> Document {
+        let mut fields = HashMap::new();
+        fields.insert("count".to_owned(), int(count));
+        Document::new(fields)
+    }
+
+    #[test]
+    fn increment_adds_to_numbers_and_sets_missing_fields() {
+        let mut doc = counter(3);
+        FieldTransform::increment("count", int(-5)).apply(&mut doc, Utc::now());
+        FieldTransform::increment("total", int(2)).apply(&mut doc, Utc::now());
+        assert_eq!(doc.extract_integer::<i64>("count"), Ok(-2));
+        assert_eq!(doc.extract_integer::<i64>("total"), Ok(2));
+
+        FieldTransform::increment("count", DocumentField::DoubleValue(0.5))
+            .apply(&mut doc, Utc::now());
+        assert_eq!(doc.extract_double::<f64>("count"), Ok(-1.5));
+
+        let mut doc = counter(i64::MAX);
+        FieldTransform::increment("count", int(1)).apply(&mut doc, Utc::now());
+        assert_eq!(doc.extract_integer::<i64>("count"), Ok(i64::MAX));
+    }
+
+    #[test]
+    fn maximum_and_minimum_keep_the_extreme_value() {
+        let mut doc = counter(3);
+        FieldTransform::maximum("count", int(1)).apply(&mut doc, Utc::now());
+        assert_eq!(doc.extract_integer::<i64>("count"), Ok(3));
+        FieldTransform::maximum("count", int(7)).apply(&mut doc, Utc::now());
+        assert_eq!(doc.extract_integer::<i64>("count"), Ok(7));
+        FieldTransform::minimum("count", int(4)).apply(&mut doc, Utc::now());
+        assert_eq!(doc.extract_integer::<i64>("count"), Ok(4));
+        FieldTransform::minimum("stats.lowest", int(9)).apply(&mut doc, Utc::now());
+        assert_eq!(
+            query::get_field(&doc, "stats.lowest")
+                .unwrap()
+                .extract_integer::<i64>(),
+            Ok(9)
+        );
+    }
+
+    #[test]
+    fn server_timestamp_sets_commit_time() {
+        let mut doc = counter(0);
+        let now = Utc::now();
+        FieldTransform::server_timestamp("count").apply(&mut doc, now);
+        assert_eq!(doc.extract_timestamp("count"), Ok(now));
+    }
+
+    #[test]
+    fn serialises_to_firestore_format() {
+        let transforms = vec![
+            FieldTransform::increment("currency", int(5)),
+            FieldTransform::server_timestamp("daily_last_claimed"),
+        ];
+        assert_eq!(
+            serde_json::to_value(&transforms).unwrap(),
+            serde_json::json!([
+                { "fieldPath": "currency", "increment": { "integerValue": "5" } },
+                { "fieldPath": "daily_last_claimed", "setToServerValue": "REQUEST_TIME" },
+            ])
+        );
+    }
+}
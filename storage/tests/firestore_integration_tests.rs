@@ -1,7 +1,10 @@
+#![cfg(any(feature = "test_requires_secrets", feature = "test_uses_emulator"))]
+
 extern crate env_logger;
 extern crate pccg_rs_storage;
 
-use pccg_rs_storage::firestore::*;
+use futures::TryStreamExt;
+use pccg_rs_storage::{firestore::*, BatchWrite, DocumentStore, Precondition};
 use std::{
     collections::HashMap,
     convert::{TryFrom, TryInto},
@@ -9,7 +12,10 @@ use std::{
 };
 use uuid::Uuid;
 
+#[cfg(not(feature = "test_uses_emulator"))]
 static JSON_KEY_PATH: &str = "../secrets/service_account.json";
+#[cfg(feature = "test_uses_emulator")]
+static EMULATOR_PROJECT_ID: &str = "pccg-rs-test";
 static UUID_NAMESPACE: &str = "6e81479f-5718-4d5c-aab7-6eb6de4465c2";
 
 fn logging_init() {
@@ -19,6 +25,22 @@ fn logging_init() {
     let _ = env_logger::builder().is_test(true).try_init();
 }
 
+#[cfg(not(feature = "test_uses_emulator"))]
+async fn connect() -> Firestore {
+    Firestore::new(JSON_KEY_PATH, HttpOptions::default())
+        .await
+        .unwrap()
+}
+
+#[cfg(feature = "test_uses_emulator")]
+async fn connect() -> Firestore {
+    let host = std::env::var("FIRESTORE_EMULATOR_HOST")
+        .expect("FIRESTORE_EMULATOR_HOST must be set to run tests against the emulator");
+    Firestore::new_for_emulator(&host, EMULATOR_PROJECT_ID, HttpOptions::default())
+        .await
+        .unwrap()
+}
+
 fn generate_uuid(string: &str) -> Uuid {
     let namespace = Uuid::parse_str(UUID_NAMESPACE).unwrap();
     Uuid::new_v5(&namespace, string.as_bytes())
@@ -28,7 +50,7 @@ fn generate_uuid(string: &str) -> Uuid {
 async fn can_upsert_then_get() {
     logging_init();
 
-    let firestore = Firestore::new(JSON_KEY_PATH).await.unwrap();
+    let firestore = connect().await;
     let firestore = FirestoreClient::new(Arc::new(firestore), None, "_test".to_owned());
     let id = generate_uuid(stringify!(can_upsert_then_get));
     let test_item = TestItem {
@@ -48,7 +70,7 @@ async fn can_upsert_then_get() {
 async fn can_list_empty_collection() {
     logging_init();
 
-    let firestore = Firestore::new(JSON_KEY_PATH).await.unwrap();
+    let firestore = connect().await;
     let firestore = FirestoreClient::new(
         Arc::new(firestore),
         None,
@@ -62,7 +84,7 @@ async fn can_list_empty_collection() {
 async fn can_list_non_empty_collection() {
     logging_init();
 
-    let firestore = Firestore::new(JSON_KEY_PATH).await.unwrap();
+    let firestore = connect().await;
     let firestore = FirestoreClient::new(
         Arc::new(firestore),
         None,
@@ -83,11 +105,47 @@ async fn can_list_non_empty_collection() {
     assert_eq!(ret[0], test_item);
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn can_list_in_pages_and_as_stream() {
+    logging_init();
+
+    let firestore = connect().await;
+    let firestore =
+        FirestoreClient::new(Arc::new(firestore), None, "_test_list_in_pages".to_owned());
+    let mut items = vec![];
+    for number in 0..3 {
+        let id = generate_uuid(&format!("can_list_in_pages_and_as_stream_{}", number));
+        let test_item = TestItem {
+            id,
+            number,
+            test_case: "can_list_in_pages_and_as_stream".to_owned(),
+        };
+        firestore
+            .upsert(&id, test_item.clone(), None)
+            .await
+            .unwrap();
+        items.push(test_item);
+    }
+    items.sort_by_key(|item| item.id.to_string());
+
+    let first = firestore.list_page::<TestItem>(2, None).await.unwrap();
+    assert_eq!(first.items, items[..2].to_vec());
+    let second = firestore
+        .list_page::<TestItem>(2, first.next_page_token)
+        .await
+        .unwrap();
+    assert_eq!(second.items, items[2..].to_vec());
+    assert!(second.next_page_token.is_none());
+
+    let streamed: Vec<TestItem> = firestore.list_stream().try_collect().await.unwrap();
+    assert_eq!(streamed, items);
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn can_list_empty_subcollection() {
     logging_init();
 
-    let firestore = Firestore::new(JSON_KEY_PATH).await.unwrap();
+    let firestore = connect().await;
     let firestore = FirestoreClient::new(Arc::new(firestore), None, "_test".to_owned());
     let id = generate_uuid(stringify!(can_list_empty_subcollection));
     let test_item = TestItem {
@@ -106,7 +164,7 @@ async fn can_list_empty_subcollection() {
 async fn can_list_non_empty_subcollection() {
     logging_init();
 
-    let firestore = Firestore::new(JSON_KEY_PATH).await.unwrap();
+    let firestore = connect().await;
     let firestore = FirestoreClient::new(Arc::new(firestore), None, "_test".to_owned());
     let id = generate_uuid(stringify!(can_list_non_empty_subcollection));
     let test_item = TestItem {
@@ -130,7 +188,7 @@ async fn can_list_non_empty_subcollection() {
 async fn can_upsert_then_batch_get() {
     logging_init();
 
-    let firestore = Firestore::new(JSON_KEY_PATH).await.unwrap();
+    let firestore = connect().await;
     let firestore = FirestoreClient::new(Arc::new(firestore), None, "_test".to_owned());
 
     let id_1 = generate_uuid(&format!("{}_1", stringify!(can_upsert_then_batch_get)));
@@ -172,6 +230,240 @@ async fn can_upsert_then_batch_get() {
     assert_eq!(ret[&id_3], None);
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn can_run_query() {
+    logging_init();
+
+    let firestore = connect().await;
+    let firestore = FirestoreClient::new(Arc::new(firestore), None, "_test_query".to_owned());
+    let mut items = vec![];
+    for number in 0..4 {
+        let id = generate_uuid(&format!("{}_{}", stringify!(can_run_query), number));
+        let test_item = TestItem {
+            id,
+            number,
+            test_case: "can_run_query".to_owned(),
+        };
+        firestore
+            .upsert(&id, test_item.clone(), None)
+            .await
+            .unwrap();
+        items.push(test_item);
+    }
+
+    let query = StructuredQuery::new()
+        .filter(Filter::equal(
+            "test_case",
+            DocumentField::StringValue("can_run_query".to_owned()),
+        ))
+        .filter(Filter::greater_than(
+            "number",
+            DocumentField::IntegerValue("0".to_owned()),
+        ))
+        .order_by("number", Direction::Descending)
+        .limit(2);
+    let ret = firestore
+        .run_query::<TestItem>(query.clone(), None)
+        .await
+        .unwrap();
+    assert_eq!(ret, vec![items[3].clone(), items[2].clone()]);
+
+    let t = firestore
+        .begin_transaction(TransactionType::ReadOnly)
+        .await
+        .unwrap();
+    let ret = firestore
+        .run_query::<TestItem>(
+            query.start_after(vec![DocumentField::IntegerValue("2".to_owned())]),
+            Some(&t),
+        )
+        .await
+        .unwrap();
+    assert_eq!(ret, vec![items[1].clone()]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn can_read_with_field_mask() {
+    logging_init();
+
+    let firestore = connect().await;
+    let firestore = FirestoreClient::new(Arc::new(firestore), None, "_test_mask".to_owned());
+    let id = generate_uuid(stringify!(can_read_with_field_mask));
+    let test_item = TestItem {
+        id,
+        number: 5,
+        test_case: "can_read_with_field_mask".to_owned(),
+    };
+    firestore
+        .upsert(&id, test_item.clone(), None)
+        .await
+        .unwrap();
+
+    let mask = FieldMask::new(&["number"]);
+    let doc = firestore
+        .get_masked::<Document>(&id, &mask, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(doc.extract_id(), Ok(id));
+    assert_eq!(doc.fields.len(), 1);
+    assert_eq!(doc.extract_integer::<u32>("number"), Ok(5));
+
+    let ret = firestore
+        .batch_get_masked::<Document>(&[id], &mask, None)
+        .await
+        .unwrap();
+    assert_eq!(ret[&id].as_ref().unwrap().fields.len(), 1);
+
+    let docs = firestore.list_masked::<Document>(&mask).await.unwrap();
+    assert!(docs.iter().all(|d| d.fields.len() == 1));
+    assert_eq!(firestore.list_ids().await.unwrap(), vec![id]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn can_apply_field_transforms() {
+    logging_init();
+
+    let firestore = connect().await;
+    let firestore = FirestoreClient::new(Arc::new(firestore), None, "_test_transform".to_owned());
+    let id = generate_uuid(stringify!(can_apply_field_transforms));
+    let test_item = TestItem {
+        id,
+        number: 5,
+        test_case: "can_apply_field_transforms".to_owned(),
+    };
+    firestore
+        .upsert(&id, test_item.clone(), None)
+        .await
+        .unwrap();
+
+    // Outside a transaction
+    firestore
+        .transform(
+            &id,
+            vec![FieldTransform::increment(
+                "number",
+                DocumentField::IntegerValue("3".to_owned()),
+            )],
+            None,
+        )
+        .await
+        .unwrap();
+
+    // Inside a transaction
+    let t = firestore
+        .begin_transaction(TransactionType::ReadWrite)
+        .await
+        .unwrap();
+    firestore
+        .transform(
+            &id,
+            vec![
+                FieldTransform::maximum("number", DocumentField::IntegerValue("4".to_owned())),
+                FieldTransform::server_timestamp("updated"),
+            ],
+            Some(&t),
+        )
+        .await
+        .unwrap();
+    t.commit().await.unwrap();
+
+    let doc = firestore.get::<Document>(&id, None).await.unwrap().unwrap();
+    assert_eq!(doc.extract_integer::<u32>("number"), Ok(8));
+    assert!(doc.extract_timestamp("updated").is_ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_check_preconditions() {
+    logging_init();
+
+    let firestore = connect().await;
+    let firestore =
+        FirestoreClient::new(Arc::new(firestore), None, "_test_precondition".to_owned());
+    let id = generate_uuid(stringify!(writes_check_preconditions));
+    let mut test_item = TestItem {
+        id,
+        number: 0,
+        test_case: "writes_check_preconditions".to_owned(),
+    };
+    let _ = firestore.delete::<TestItem>(&id, None).await;
+    match firestore
+        .upsert_if(&id, test_item.clone(), Precondition::Exists(true), None)
+        .await
+    {
+        Err(pccg_rs_storage::Error::Conflict(_)) => (),
+        other => panic!("Expected conflict, got {:?}", other),
+    }
+    firestore
+        .upsert_if(&id, test_item.clone(), Precondition::Exists(false), None)
+        .await
+        .unwrap();
+
+    let doc = firestore.get::<Document>(&id, None).await.unwrap().unwrap();
+    let read_update_time = Precondition::UpdateTime(doc.update_time);
+    test_item.number = 1;
+    firestore
+        .upsert_if(&id, test_item.clone(), read_update_time.clone(), None)
+        .await
+        .unwrap();
+    match firestore.delete_if(&id, read_update_time, None).await {
+        Err(pccg_rs_storage::Error::Conflict(_)) => (),
+        other => panic!("Expected conflict, got {:?}", other),
+    }
+    let ret = firestore.get::<TestItem>(&id, None).await.unwrap();
+    assert_eq!(ret, Some(test_item));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn can_batch_write_more_than_one_commit() {
+    logging_init();
+
+    let firestore = connect().await;
+    let firestore = FirestoreClient::new(Arc::new(firestore), None, "_test_batch".to_owned());
+    let ids: Vec<Uuid> = (0..510)
+        .map(|i| generate_uuid(&format!("can_batch_write_more_than_one_commit{}", i)))
+        .collect();
+    let results = firestore
+        .batch_write(ids.iter().map(|id| BatchWrite::delete(*id)).collect())
+        .await
+        .unwrap();
+    assert!(results.iter().all(|r| r.is_ok()));
+
+    let items = ids.iter().enumerate().map(|(i, id)| TestItem {
+        id: *id,
+        number: i as u32,
+        test_case: "can_batch_write_more_than_one_commit".to_owned(),
+    });
+    let results = firestore
+        .batch_write(
+            items
+                .map(|item| BatchWrite::insert(item.id, item))
+                .collect(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(results.len(), ids.len());
+    assert!(results.iter().all(|r| r.is_ok()));
+
+    let results = firestore
+        .batch_write(vec![
+            BatchWrite::insert(ids[0], TestItem::default()),
+            BatchWrite::delete(ids[509]),
+        ])
+        .await
+        .unwrap();
+    match &results[0] {
+        Err(pccg_rs_storage::Error::Conflict(_)) => (),
+        other => panic!("Expected conflict, got {:?}", other),
+    }
+    assert!(results[1].is_ok());
+
+    let ret = firestore.batch_get::<TestItem>(&ids, None).await.unwrap();
+    assert_eq!(ret[&ids[0]].as_ref().map(|item| item.number), Some(0));
+    assert_eq!(ret[&ids[508]].as_ref().map(|item| item.number), Some(508));
+    assert_eq!(ret[&ids[509]], None);
+}
+
 #[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
 struct TestItem {
     pub id: Uuid,
@@ -262,3 +554,54 @@ fn can_convert_between_document_and_test_item() {
 
     assert_eq!(test_item, test_item_from_doc);
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transactions_read_their_own_writes_and_roll_back() {
+    logging_init();
+
+    let firestore = connect().await;
+    let firestore = FirestoreClient::new(Arc::new(firestore), None, "_test_own_writes".to_owned());
+    let id = generate_uuid(stringify!(transactions_read_their_own_writes_and_roll_back));
+    let test_item = TestItem {
+        id,
+        number: 1,
+        test_case: "transactions_read_their_own_writes_and_roll_back".to_owned(),
+    };
+    firestore
+        .upsert(&id, test_item.clone(), None)
+        .await
+        .unwrap();
+
+    let t = firestore
+        .begin_transaction(TransactionType::ReadWrite)
+        .await
+        .unwrap();
+    firestore
+        .upsert(
+            &id,
+            TestItem {
+                number: 2,
+                ..test_item.clone()
+            },
+            Some(&t),
+        )
+        .await
+        .unwrap();
+    firestore
+        .transform(
+            &id,
+            vec![FieldTransform::increment(
+                "number",
+                DocumentField::IntegerValue("3".to_owned()),
+            )],
+            Some(&t),
+        )
+        .await
+        .unwrap();
+    let ret = firestore.get::<TestItem>(&id, Some(&t)).await.unwrap();
+    assert_eq!(ret.map(|item| item.number), Some(5));
+
+    t.rollback().await.unwrap();
+    let ret = firestore.get::<TestItem>(&id, None).await.unwrap();
+    assert_eq!(ret, Some(test_item));
+}